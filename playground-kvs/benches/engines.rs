@@ -0,0 +1,294 @@
+//! Compares the engines of kvs with each other and with sled, an embedded store of its own.
+//! Run them with `cargo bench -p kvs`, the reports end up in `target/criterion`.
+//!
+//! The stores keep their default options, so writes to the log of a `KvStore`
+//! and of sled are synced in the background and not one by one.
+//! A `SledStore` syncs every write, which shows what that costs.
+
+use criterion::measurement::WallTime;
+use criterion::{
+    criterion_group, criterion_main, BatchSize, BenchmarkGroup, BenchmarkId, Criterion, Throughput,
+};
+use kvs::{KvStore, KvStoreOptions, KvsEngine, MemStore, SledStore};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::path::Path;
+use std::thread;
+use tempfile::TempDir;
+
+/// How many keys the stores have when reads are measured.
+const KEYS: u64 = 1000;
+/// How large the values are in bytes.
+const VALUE_SIZES: [usize; 3] = [16, 1024, 16 * 1024];
+/// How many reads every reader does when readers run concurrently.
+const READS_PER_THREAD: u64 = 1000;
+
+/// What is compared, for the engines of kvs and for sled.
+trait Engine: Clone + Send + 'static {
+    fn set(&self, key: &[u8], value: &[u8]);
+    fn get(&self, key: &[u8]) -> Option<Vec<u8>>;
+    fn remove(&self, key: &[u8]);
+}
+
+impl Engine for MemStore {
+    fn set(&self, key: &[u8], value: &[u8]) {
+        KvsEngine::set(self, key, value).unwrap();
+    }
+
+    fn get(&self, key: &[u8]) -> Option<Vec<u8>> {
+        KvsEngine::get(self, key).unwrap()
+    }
+
+    fn remove(&self, key: &[u8]) {
+        KvsEngine::remove(self, key).unwrap();
+    }
+}
+
+impl Engine for KvStore {
+    fn set(&self, key: &[u8], value: &[u8]) {
+        KvsEngine::set(self, key, value).unwrap();
+    }
+
+    fn get(&self, key: &[u8]) -> Option<Vec<u8>> {
+        KvsEngine::get(self, key).unwrap()
+    }
+
+    fn remove(&self, key: &[u8]) {
+        KvsEngine::remove(self, key).unwrap();
+    }
+}
+
+impl Engine for SledStore {
+    fn set(&self, key: &[u8], value: &[u8]) {
+        KvsEngine::set(self, key, value).unwrap();
+    }
+
+    fn get(&self, key: &[u8]) -> Option<Vec<u8>> {
+        KvsEngine::get(self, key).unwrap()
+    }
+
+    fn remove(&self, key: &[u8]) {
+        KvsEngine::remove(self, key).unwrap();
+    }
+}
+
+impl Engine for sled::Db {
+    fn set(&self, key: &[u8], value: &[u8]) {
+        self.insert(key, value).unwrap();
+    }
+
+    fn get(&self, key: &[u8]) -> Option<Vec<u8>> {
+        sled::Tree::get(self, key)
+            .unwrap()
+            .map(|value| value.to_vec())
+    }
+
+    fn remove(&self, key: &[u8]) {
+        sled::Tree::remove(self, key).unwrap().unwrap();
+    }
+}
+
+fn open_memory(_: &Path) -> MemStore {
+    MemStore::new()
+}
+
+fn open_kvs(dir: &Path) -> KvStore {
+    KvStore::open(dir).unwrap()
+}
+
+fn open_sled_store(dir: &Path) -> SledStore {
+    SledStore::open(dir).unwrap()
+}
+
+fn open_sled(dir: &Path) -> sled::Db {
+    sled::open(dir).unwrap()
+}
+
+fn key(i: u64) -> Vec<u8> {
+    format!("key{:08}", i).into_bytes()
+}
+
+/// Sets the keys `0..KEYS` to values of `size` bytes.
+fn fill(engine: &impl Engine, size: usize) {
+    let value = vec![b'v'; size];
+    for i in 0..KEYS {
+        engine.set(&key(i), &value);
+    }
+}
+
+fn set(c: &mut Criterion) {
+    let mut group = c.benchmark_group("set");
+    for &size in &VALUE_SIZES {
+        group.throughput(Throughput::Bytes(size as u64));
+        bench_set(&mut group, "memory", open_memory, size);
+        bench_set(&mut group, "kvs", open_kvs, size);
+        bench_set(&mut group, "sled_store", open_sled_store, size);
+        bench_set(&mut group, "sled", open_sled, size);
+    }
+    group.finish();
+}
+
+/// Sets ever new keys in order and keys picked at random, which are mostly overwritten.
+fn bench_set<E: Engine>(
+    group: &mut BenchmarkGroup<WallTime>,
+    name: &str,
+    open: fn(&Path) -> E,
+    size: usize,
+) {
+    let dir = TempDir::new().unwrap();
+    let engine = open(dir.path());
+    let value = vec![b'v'; size];
+    let mut i = 0;
+    group.bench_function(
+        BenchmarkId::new(format!("{}/sequential", name), size),
+        |b| {
+            b.iter(|| {
+                i += 1;
+                engine.set(&key(i), &value)
+            })
+        },
+    );
+    let mut rng = StdRng::seed_from_u64(0);
+    group.bench_function(BenchmarkId::new(format!("{}/random", name), size), |b| {
+        b.iter(|| engine.set(&key(rng.gen_range(0..KEYS)), &value))
+    });
+}
+
+fn get(c: &mut Criterion) {
+    let mut group = c.benchmark_group("get");
+    for &size in &VALUE_SIZES {
+        group.throughput(Throughput::Bytes(size as u64));
+        bench_get(&mut group, "memory", open_memory, size);
+        bench_get(&mut group, "kvs", open_kvs, size);
+        bench_get(&mut group, "sled_store", open_sled_store, size);
+        bench_get(&mut group, "sled", open_sled, size);
+    }
+    group.finish();
+}
+
+/// Gets the keys of a filled store in order and at random.
+fn bench_get<E: Engine>(
+    group: &mut BenchmarkGroup<WallTime>,
+    name: &str,
+    open: fn(&Path) -> E,
+    size: usize,
+) {
+    let dir = TempDir::new().unwrap();
+    let engine = open(dir.path());
+    fill(&engine, size);
+    let mut i = 0;
+    group.bench_function(
+        BenchmarkId::new(format!("{}/sequential", name), size),
+        |b| {
+            b.iter(|| {
+                i = (i + 1) % KEYS;
+                engine.get(&key(i)).unwrap()
+            })
+        },
+    );
+    let mut rng = StdRng::seed_from_u64(0);
+    group.bench_function(BenchmarkId::new(format!("{}/random", name), size), |b| {
+        b.iter(|| engine.get(&key(rng.gen_range(0..KEYS))).unwrap())
+    });
+}
+
+fn remove(c: &mut Criterion) {
+    let mut group = c.benchmark_group("remove");
+    bench_remove(&mut group, "memory", open_memory);
+    bench_remove(&mut group, "kvs", open_kvs);
+    bench_remove(&mut group, "sled_store", open_sled_store);
+    bench_remove(&mut group, "sled", open_sled);
+    group.finish();
+}
+
+/// Removes keys which were set just before, only the removal is measured.
+fn bench_remove<E: Engine>(group: &mut BenchmarkGroup<WallTime>, name: &str, open: fn(&Path) -> E) {
+    let dir = TempDir::new().unwrap();
+    let engine = open(dir.path());
+    let mut i = 0;
+    group.bench_function(name, |b| {
+        b.iter_batched(
+            || {
+                i += 1;
+                engine.set(&key(i), b"value");
+                key(i)
+            },
+            |key| engine.remove(&key),
+            BatchSize::SmallInput,
+        )
+    });
+}
+
+fn concurrent_get(c: &mut Criterion) {
+    let mut group = c.benchmark_group("concurrent_get");
+    for threads in [1, 2, 4, 8] {
+        group.throughput(Throughput::Elements(threads * READS_PER_THREAD));
+        bench_concurrent_get(&mut group, "memory", open_memory, threads);
+        bench_concurrent_get(&mut group, "kvs", open_kvs, threads);
+        bench_concurrent_get(&mut group, "sled_store", open_sled_store, threads);
+        bench_concurrent_get(&mut group, "sled", open_sled, threads);
+    }
+    group.finish();
+}
+
+/// Lets `threads` readers get keys at random from a filled store at the same time.
+fn bench_concurrent_get<E: Engine>(
+    group: &mut BenchmarkGroup<WallTime>,
+    name: &str,
+    open: fn(&Path) -> E,
+    threads: u64,
+) {
+    let dir = TempDir::new().unwrap();
+    let engine = open(dir.path());
+    fill(&engine, 1024);
+    group.bench_function(BenchmarkId::new(name, threads), |b| {
+        b.iter(|| {
+            thread::scope(|scope| {
+                for seed in 0..threads {
+                    let engine = engine.clone();
+                    scope.spawn(move || {
+                        let mut rng = StdRng::seed_from_u64(seed);
+                        for _ in 0..READS_PER_THREAD {
+                            engine.get(&key(rng.gen_range(0..KEYS))).unwrap();
+                        }
+                    });
+                }
+            })
+        })
+    });
+}
+
+/// Compacts a `KvStore` whose keys were each overwritten a few times,
+/// so that most of its log is stale. The other engines don't compact like this.
+fn compaction(c: &mut Criterion) {
+    let mut group = c.benchmark_group("compaction");
+    group.sample_size(20);
+    for overwrites in [1, 4, 16] {
+        group.throughput(Throughput::Bytes(KEYS * 1024 * (overwrites + 1)));
+        group.bench_function(BenchmarkId::new("kvs", overwrites), |b| {
+            b.iter_batched(
+                || {
+                    let dir = TempDir::new().unwrap();
+                    let options = KvStoreOptions {
+                        compaction_threshold: u64::MAX,
+                        ..KvStoreOptions::default()
+                    };
+                    let store = KvStore::open_with_options(dir.path(), options).unwrap();
+                    for _ in 0..=overwrites {
+                        fill(&store, 1024);
+                    }
+                    (dir, store)
+                },
+                |(dir, store)| {
+                    store.compact().unwrap();
+                    (dir, store)
+                },
+                BatchSize::PerIteration,
+            )
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, set, get, remove, concurrent_get, compaction);
+criterion_main!(benches);
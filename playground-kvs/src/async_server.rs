@@ -0,0 +1,246 @@
+use crate::protocol::{read_frame_async, write_frame_async, Replication, Request, Response};
+use crate::replication::Status;
+use crate::server::WATCH_CHECK_INTERVAL;
+use crate::{Expected, KeyRange, KvsEngine, KvsError, Result, Watch, WriteBatch};
+use std::future::Future;
+use std::io;
+use std::thread;
+use std::time::Duration;
+use tokio::io::{AsyncWrite, BufReader, BufWriter};
+use tokio::net::tcp::ReadHalf;
+use tokio::net::{TcpListener, TcpStream, ToSocketAddrs};
+use tokio::sync::mpsc::{self, error::TrySendError};
+use tokio::task;
+
+/// How many changes a client of a watch can fall behind before it is dropped.
+const WATCH_BUFFER_LEN: usize = 1024;
+
+/// Makes the calls of a [`KvsEngine`] awaitable.
+/// They run on the blocking threads of the tokio runtime,
+/// so a slow disk doesn't hold up the tasks of other connections.
+#[derive(Clone)]
+pub struct AsyncEngine<E: KvsEngine> {
+    engine: E,
+}
+
+impl<E: KvsEngine> AsyncEngine<E> {
+    /// Wraps `engine`, its calls can only be awaited within a tokio runtime.
+    pub fn new(engine: E) -> Self {
+        Self { engine }
+    }
+
+    /// Like [`KvsEngine::set`].
+    pub fn set(&self, key: Vec<u8>, value: Vec<u8>) -> impl Future<Output = Result<u64>> {
+        self.run(move |engine| engine.set(key, value))
+    }
+
+    /// Like [`KvsEngine::set_with_ttl`].
+    pub fn set_with_ttl(
+        &self,
+        key: Vec<u8>,
+        value: Vec<u8>,
+        ttl: Duration,
+    ) -> impl Future<Output = Result<u64>> {
+        self.run(move |engine| engine.set_with_ttl(key, value, ttl))
+    }
+
+    /// Like [`KvsEngine::get`].
+    pub fn get(&self, key: Vec<u8>) -> impl Future<Output = Result<Option<Vec<u8>>>> {
+        self.run(move |engine| engine.get(key))
+    }
+
+    /// Like [`KvsEngine::get_versioned`].
+    pub fn get_versioned(
+        &self,
+        key: Vec<u8>,
+    ) -> impl Future<Output = Result<Option<(Vec<u8>, u64)>>> {
+        self.run(move |engine| engine.get_versioned(key))
+    }
+
+    /// Like [`KvsEngine::write_if`].
+    pub fn write_if(
+        &self,
+        key: Vec<u8>,
+        expected: Expected,
+        new: Option<Vec<u8>>,
+        ttl: Option<Duration>,
+    ) -> impl Future<Output = Result<u64>> {
+        self.run(move |engine| engine.write_if(key, expected, new.as_deref(), ttl))
+    }
+
+    /// Like [`KvsEngine::remove`].
+    pub fn remove(&self, key: Vec<u8>) -> impl Future<Output = Result<()>> {
+        self.run(move |engine| engine.remove(key))
+    }
+
+    /// Like [`KvsEngine::apply`].
+    pub fn apply(&self, batch: WriteBatch) -> impl Future<Output = Result<()>> {
+        self.run(move |engine| engine.apply(&batch))
+    }
+
+    /// Like [`KvsEngine::scan`] but with all entries at once, or at most `limit` of them.
+    pub fn scan(
+        &self,
+        range: KeyRange,
+        limit: Option<usize>,
+    ) -> impl Future<Output = Result<Vec<(Vec<u8>, Vec<u8>)>>> {
+        self.run(move |engine| {
+            engine
+                .scan(range)
+                .take(limit.unwrap_or(usize::MAX))
+                .collect()
+        })
+    }
+
+    /// Like [`KvsEngine::watch`].
+    pub fn watch(&self, prefix: Vec<u8>) -> impl Future<Output = Result<Watch>> {
+        self.run(move |engine| Ok(engine.watch(prefix)))
+    }
+
+    /// The engine is cloned right away, so the future doesn't borrow `self`
+    /// and can be sent to other threads even if the engine isn't `Sync`.
+    fn run<T, F>(&self, f: F) -> impl Future<Output = Result<T>>
+    where
+        T: Send + 'static,
+        F: FnOnce(E) -> Result<T> + Send + 'static,
+    {
+        let engine = self.engine.clone();
+        async move {
+            task::spawn_blocking(move || f(engine))
+                .await
+                .map_err(io::Error::other)?
+        }
+    }
+}
+
+/// Serves a [`KvsEngine`] to [`KvsClient`](crate::KvsClient)s over TCP like [`KvsServer`](crate::KvsServer),
+/// but every connection is a task of the tokio runtime instead of a job of a thread pool.
+pub struct AsyncKvsServer<E: KvsEngine> {
+    engine: AsyncEngine<E>,
+}
+
+impl<E: KvsEngine> AsyncKvsServer<E> {
+    /// Returns a server which will serve `engine`.
+    pub fn new(engine: E) -> Self {
+        Self {
+            engine: AsyncEngine::new(engine),
+        }
+    }
+
+    /// Listens on `addr` and serves every client which connects.
+    /// A failing connection is reported on stderr and doesn't stop the server.
+    /// Has to be run within a tokio runtime.
+    pub async fn run(&self, addr: impl ToSocketAddrs) -> Result<()> {
+        let listener = TcpListener::bind(addr).await?;
+        loop {
+            let (stream, _) = listener.accept().await?;
+            let engine = self.engine.clone();
+            tokio::spawn(async move {
+                if let Err(e) = serve(engine, stream).await {
+                    eprintln!("connection failed: {}", e);
+                }
+            });
+        }
+    }
+}
+
+async fn serve<E: KvsEngine>(engine: AsyncEngine<E>, mut stream: TcpStream) -> Result<()> {
+    let (reader, writer) = stream.split();
+    let mut reader = BufReader::new(reader);
+    let mut writer = BufWriter::new(writer);
+    while let Some(request) = read_frame_async(&mut reader).await? {
+        match request {
+            Request::Replicate { .. } => {
+                let refused =
+                    Replication::Refused("replication needs the threaded server".to_owned());
+                return write_frame_async(&mut writer, &refused).await;
+            }
+            // The connection streams the changes from now on.
+            Request::Watch { prefix } => {
+                let watch = engine.watch(prefix).await?;
+                write_frame_async(&mut writer, &Response::Ok).await?;
+                return send_events(watch, reader.get_ref(), &mut writer).await;
+            }
+            _ => {}
+        }
+        let response = handle(engine.clone(), request).await;
+        write_frame_async(&mut writer, &response).await?;
+    }
+    Ok(())
+}
+
+async fn handle<E: KvsEngine>(engine: AsyncEngine<E>, request: Request) -> Response {
+    let result = match request {
+        Request::Get { key } => engine.get(key).await.map(Response::Value),
+        Request::Set { key, value, ttl } => match ttl {
+            Some(ttl) => engine.set_with_ttl(key, value, ttl).await,
+            None => engine.set(key, value).await,
+        }
+        .map(Response::Version),
+        Request::Remove { key } => engine.remove(key).await.map(|()| Response::Ok),
+        Request::GetVersioned { key } => engine.get_versioned(key).await.map(Response::Versioned),
+        Request::WriteIf {
+            key,
+            expected,
+            new,
+            ttl,
+        } => engine
+            .write_if(key, expected, new, ttl)
+            .await
+            .map(Response::Version),
+        Request::Batch { batch } => engine.apply(batch).await.map(|()| Response::Ok),
+        Request::Scan { range, limit } => engine
+            .scan(range, limit.map(|limit| limit as usize))
+            .await
+            .map(Response::Entries),
+        Request::Status => Ok(Response::Status(Status::Standalone)),
+        Request::Watch { .. } | Request::Replicate { .. } => {
+            unreachable!("watches and followers are served in serve")
+        }
+    };
+    Response::from_result(result)
+}
+
+/// Sends the changes of the watch to the client until it disconnects.
+/// Waiting for them blocks, so a thread of its own forwards them to the task,
+/// which keeps the threads of the runtime free for the other connections.
+/// A client which falls more than [`WATCH_BUFFER_LEN`] changes behind is dropped.
+async fn send_events(
+    watch: Watch,
+    reader: &ReadHalf<'_>,
+    writer: &mut (impl AsyncWrite + Unpin),
+) -> Result<()> {
+    let (sender, mut receiver) = mpsc::channel(WATCH_BUFFER_LEN);
+    // `None` when there was no change for a while, so the task looks whether the client is gone.
+    // The thread ends once the task ended or the buffer is full.
+    thread::spawn(move || loop {
+        let sent = match watch.next_timeout(WATCH_CHECK_INTERVAL) {
+            Some(event) => sender.try_send(Some(event)),
+            // The task has changes to send then, it needs no reminder.
+            None => match sender.try_send(None) {
+                Err(TrySendError::Full(_)) => Ok(()),
+                sent => sent,
+            },
+        };
+        if sent.is_err() {
+            return;
+        }
+    });
+    loop {
+        match receiver.recv().await {
+            Some(Some(event)) => write_frame_async(&mut *writer, &Response::Event(event)).await?,
+            // The client sends nothing anymore, so reading only finds out whether it is gone.
+            Some(None) => match reader.try_read(&mut [0]) {
+                Ok(0) => return Ok(()),
+                Ok(_) => {}
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                Err(e) => return Err(e.into()),
+            },
+            None => {
+                return Err(KvsError::Server(
+                    "the client fell too far behind the changes it watches".to_owned(),
+                ))
+            }
+        }
+    }
+}
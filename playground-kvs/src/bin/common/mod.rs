@@ -0,0 +1,74 @@
+//! What the command line tools which write to a store have in common.
+
+use clap::{Arg, ArgMatches};
+use kvs::{Expected, Result};
+use std::fs;
+use std::io::{self, Read};
+use std::time::Duration;
+
+/// The value to set, given as argument or read from the file given with `--file`.
+pub fn read_value(m: &ArgMatches) -> Result<Vec<u8>> {
+    match m.value_of_os("file") {
+        Some(path) if path == "-" => {
+            let mut value = Vec::new();
+            io::stdin().read_to_end(&mut value)?;
+            Ok(value)
+        }
+        Some(path) => Ok(fs::read(path)?),
+        None => Ok(m.value_of("value").unwrap().into()),
+    }
+}
+
+/// `--if-version` and `--if-value`, which make a write conditional.
+pub fn condition_args() -> [Arg<'static, 'static>; 2] {
+    [
+        Arg::with_name("if-version")
+            .long("if-version")
+            .takes_value(true)
+            .validator(|version| {
+                version
+                    .parse::<u64>()
+                    .map(|_| ())
+                    .map_err(|e| e.to_string())
+            })
+            .help("Only writes if the key has this version, 0 if it must not be there"),
+        Arg::with_name("if-value")
+            .long("if-value")
+            .takes_value(true)
+            .help("Only writes if the key has this value"),
+    ]
+}
+
+pub fn versioned_arg(help: &'static str) -> Arg<'static, 'static> {
+    Arg::with_name("versioned").long("versioned").help(help)
+}
+
+/// The condition of a write given with `--if-absent`, `--if-version` or `--if-value`.
+pub fn expected(m: &ArgMatches) -> Option<Expected> {
+    if m.is_present("if-absent") {
+        Some(Expected::Version(0))
+    } else if let Some(version) = m.value_of("if-version") {
+        Some(Expected::Version(version.parse().unwrap()))
+    } else {
+        m.value_of("if-value")
+            .map(|value| Expected::Value(Some(value.into())))
+    }
+}
+
+/// Parses a duration like `30s`, plain numbers are seconds.
+pub fn parse_ttl(ttl: &str) -> std::result::Result<Duration, String> {
+    let unit_start = ttl.find(|c: char| !c.is_ascii_digit()).unwrap_or(ttl.len());
+    let (number, unit) = ttl.split_at(unit_start);
+    let number: u64 = number
+        .parse()
+        .map_err(|_| format!("{} doesn't start with a number", ttl))?;
+    let millis = match unit {
+        "ms" => 1,
+        "" | "s" => 1000,
+        "m" => 60 * 1000,
+        "h" => 60 * 60 * 1000,
+        "d" => 24 * 60 * 60 * 1000,
+        _ => return Err(format!("unknown unit {}, use ms, s, m, h or d", unit)),
+    };
+    Ok(Duration::from_millis(number.saturating_mul(millis)))
+}
@@ -0,0 +1,245 @@
+mod common;
+
+use clap::{
+    app_from_crate, crate_authors, crate_description, crate_name, crate_version, AppSettings, Arg,
+    ArgGroup, ArgMatches, SubCommand,
+};
+use common::{condition_args, expected, parse_ttl, read_value, versioned_arg};
+use kvs::{Event, KvsClient, KvsError, Result, Status, WriteBatch};
+use std::io::{self, Read, Write};
+use std::process::exit;
+
+fn main() {
+    let app = app_from_crate!()
+        .setting(AppSettings::SubcommandRequiredElseHelp)
+        .arg(
+            Arg::with_name("addr")
+                .long("addr")
+                .takes_value(true)
+                .default_value("127.0.0.1:4000")
+                .global(true)
+                .help("IP:PORT of the server"),
+        )
+        .subcommand(
+            SubCommand::with_name("set")
+                .arg(Arg::with_name("key").index(1).required(true))
+                .arg(Arg::with_name("value").index(2).required_unless("file"))
+                .arg(
+                    Arg::with_name("file")
+                        .long("file")
+                        .takes_value(true)
+                        .conflicts_with("value")
+                        .help("Reads the value from this file instead, or from stdin if it is -"),
+                )
+                .arg(
+                    Arg::with_name("if-absent")
+                        .long("if-absent")
+                        .help("Only sets the key if it is not there yet"),
+                )
+                .args(&condition_args())
+                .group(ArgGroup::with_name("condition").args(&[
+                    "if-absent",
+                    "if-version",
+                    "if-value",
+                ]))
+                .arg(
+                    Arg::with_name("ttl")
+                        .long("ttl")
+                        .takes_value(true)
+                        .validator(|ttl| parse_ttl(&ttl).map(|_| ()))
+                        .help("The key is gone after this long, like 500ms, 30s, 5m, 2h or 1d"),
+                )
+                .arg(versioned_arg("Prints the new version of the key")),
+        )
+        .subcommand(
+            SubCommand::with_name("get")
+                .arg(Arg::with_name("key").index(1).required(true))
+                .arg(versioned_arg(
+                    "Prints the version of the key and a space before the value",
+                )),
+        )
+        .subcommand(
+            SubCommand::with_name("rm")
+                .arg(Arg::with_name("key").index(1).required(true))
+                .args(&condition_args())
+                .group(ArgGroup::with_name("condition").args(&["if-version", "if-value"])),
+        )
+        .subcommand(
+            SubCommand::with_name("batch").about(
+                "Applies all or none of the `set <key> <value>` and `rm <key>` lines on stdin",
+            ),
+        )
+        .subcommand(
+            SubCommand::with_name("scan")
+                .about("Prints the entries ordered by key")
+                .arg(
+                    Arg::with_name("prefix")
+                        .long("prefix")
+                        .takes_value(true)
+                        .help("Only keys which start with this"),
+                )
+                .arg(
+                    Arg::with_name("start")
+                        .long("start")
+                        .takes_value(true)
+                        .help("Only keys from this one on"),
+                )
+                .arg(
+                    Arg::with_name("end")
+                        .long("end")
+                        .takes_value(true)
+                        .help("Only keys before this one"),
+                )
+                .arg(
+                    Arg::with_name("limit")
+                        .long("limit")
+                        .takes_value(true)
+                        .validator(|limit| {
+                            limit
+                                .parse::<usize>()
+                                .map(|_| ())
+                                .map_err(|e| e.to_string())
+                        })
+                        .help("Prints at most this many entries"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("watch")
+                .about(
+                    "Prints `set <key> <value>` and `rm <key>` lines for the changes as they come",
+                )
+                .arg(
+                    Arg::with_name("prefix")
+                        .index(1)
+                        .default_value("")
+                        .help("Only keys which start with this"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("status")
+                .about("Prints how the server takes part in replication"),
+        );
+    let matches = app.get_matches();
+
+    if let Err(e) = run(&matches) {
+        // "Key not found" is an answer, not a malfunction, so it goes to stdout.
+        if let KvsError::KeyNotFound = e {
+            println!("{}", e);
+        } else {
+            eprintln!("{}", e);
+        }
+        exit(e.exit_code());
+    }
+}
+
+fn run(matches: &ArgMatches) -> Result<()> {
+    let mut client = KvsClient::connect(matches.value_of("addr").unwrap())?;
+
+    if let Some(m) = matches.subcommand_matches("set") {
+        let key = m.value_of("key").unwrap();
+        let value = read_value(m)?;
+
+        let ttl = m.value_of("ttl").map(|ttl| parse_ttl(ttl).unwrap());
+        let version = match (expected(m), ttl) {
+            (Some(expected), ttl) => client.write_if(key, expected, Some(&value), ttl)?,
+            (None, Some(ttl)) => client.set_with_ttl(key, value, ttl)?,
+            (None, None) => client.set(key, value)?,
+        };
+        if m.is_present("versioned") {
+            println!("{}", version);
+        }
+    } else if let Some(m) = matches.subcommand_matches("get") {
+        let key = m.value_of("key").unwrap();
+
+        match client.get_versioned(key)? {
+            Some((value, version)) => {
+                let mut stdout = io::stdout();
+                if m.is_present("versioned") {
+                    write!(stdout, "{} ", version)?;
+                }
+                stdout.write_all(&value)?;
+                stdout.write_all(b"\n")?;
+            }
+            None => println!("Key not found"),
+        }
+    } else if let Some(m) = matches.subcommand_matches("rm") {
+        let key = m.value_of("key").unwrap();
+
+        match expected(m) {
+            Some(expected) => {
+                client.write_if(key, expected, None, None)?;
+            }
+            None => client.remove(key)?,
+        }
+    } else if matches.subcommand_matches("batch").is_some() {
+        let mut input = String::new();
+        io::stdin().read_to_string(&mut input)?;
+        let batch: WriteBatch = input.parse()?;
+
+        client.apply(&batch)?;
+    } else if let Some(m) = matches.subcommand_matches("scan") {
+        let range = kvs::scan_range(
+            m.value_of("prefix").map(str::as_bytes),
+            m.value_of("start").map(str::as_bytes),
+            m.value_of("end").map(str::as_bytes),
+        );
+        let limit = m.value_of("limit").map(|limit| limit.parse().unwrap());
+
+        for (key, value) in client.scan(range, limit)? {
+            // Bytes which aren't UTF-8 show up as replacement characters.
+            println!(
+                "{} {}",
+                String::from_utf8_lossy(&key),
+                String::from_utf8_lossy(&value)
+            );
+        }
+    } else if let Some(m) = matches.subcommand_matches("watch") {
+        let prefix = m.value_of("prefix").unwrap();
+
+        for event in client.watch(prefix)? {
+            match event? {
+                Event::Set { key, value, .. } => println!(
+                    "set {} {}",
+                    String::from_utf8_lossy(&key),
+                    String::from_utf8_lossy(&value)
+                ),
+                Event::Remove { key } => println!("rm {}", String::from_utf8_lossy(&key)),
+            }
+        }
+    } else if matches.subcommand_matches("status").is_some() {
+        print_status(&client.status()?);
+    }
+    Ok(())
+}
+
+/// Prints one `name: value` line per detail of the status.
+fn print_status(status: &Status) {
+    match status {
+        Status::Standalone => println!("role: standalone"),
+        Status::Primary { offset, followers } => {
+            println!("role: primary");
+            println!("offset: {}", offset);
+            println!("followers: {}", followers);
+        }
+        Status::Follower {
+            primary,
+            connected,
+            offset,
+            lag,
+            last_contact,
+        } => {
+            println!("role: follower");
+            println!("primary: {}", primary);
+            println!("connected: {}", connected);
+            match offset {
+                Some(offset) => println!("offset: {}", offset),
+                None => println!("offset: none"),
+            }
+            println!("lag: {} bytes", lag);
+            match last_contact {
+                Some(last_contact) => println!("last contact: {}ms ago", last_contact.as_millis()),
+                None => println!("last contact: never"),
+            }
+        }
+    }
+}
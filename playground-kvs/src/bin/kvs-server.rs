@@ -0,0 +1,168 @@
+use clap::{
+    app_from_crate, crate_authors, crate_description, crate_name, crate_version, Arg, ArgMatches,
+};
+use kvs::thread_pool::{NaiveThreadPool, SharedQueueThreadPool, ThreadPool};
+use kvs::{KvStore, KvsEngine, KvsError, KvsServer, MemStore, Protocol, Result, SledStore};
+use std::env;
+use std::path::PathBuf;
+use std::process::exit;
+use std::thread;
+
+fn main() {
+    let app = app_from_crate!()
+        .arg(
+            Arg::with_name("addr")
+                .long("addr")
+                .takes_value(true)
+                .default_value("127.0.0.1:4000")
+                .help("IP:PORT to listen on"),
+        )
+        .arg(
+            Arg::with_name("dir")
+                .long("dir")
+                .takes_value(true)
+                .help("Directory of the store, defaults to the current directory"),
+        )
+        .arg(
+            Arg::with_name("engine")
+                .long("engine")
+                .takes_value(true)
+                .possible_values(&["kvs", "sled", "memory"])
+                .help("Storage engine, defaults to the one which used the directory before or kvs"),
+        )
+        .arg(
+            Arg::with_name("pool")
+                .long("pool")
+                .takes_value(true)
+                .possible_values(&["shared-queue", "naive"])
+                .default_value("shared-queue")
+                .help("Thread pool which serves the requests"),
+        )
+        .arg(
+            Arg::with_name("threads")
+                .long("threads")
+                .takes_value(true)
+                .validator(|threads| match threads.parse::<u32>() {
+                    Ok(threads) if threads > 0 => Ok(()),
+                    _ => Err("must be a positive number".to_owned()),
+                })
+                .help("Number of requests served at once, defaults to the number of CPUs"),
+        )
+        .arg(
+            Arg::with_name("follow")
+                .long("follow")
+                .takes_value(true)
+                .value_name("IP:PORT")
+                .help("Serves a read-only copy of the kvs-server at IP:PORT, which is kept up to date"),
+        )
+        .arg(
+            Arg::with_name("protocol")
+                .long("protocol")
+                .takes_value(true)
+                .possible_values(&["kvs", "resp", "http"])
+                .help("Protocol of the clients, kvs-client by default, resp for Redis clients or http"),
+        );
+    #[cfg(feature = "async")]
+    let app = app.arg(
+        Arg::with_name("async")
+            .long("async")
+            .conflicts_with_all(&["follow", "protocol"])
+            .help("Serves the connections as tasks of an async runtime instead of a thread pool"),
+    );
+    let matches = app.get_matches();
+
+    if let Err(e) = run(&matches) {
+        eprintln!("{}", e);
+        exit(e.exit_code());
+    }
+}
+
+fn run(matches: &ArgMatches) -> Result<()> {
+    let addr = matches.value_of("addr").unwrap();
+    let dir = match matches.value_of("dir") {
+        Some(dir) => PathBuf::from(dir),
+        None => env::current_dir()?,
+    };
+
+    let engine = kvs::select_engine(&dir, matches.value_of("engine"), "kvs")?;
+    if matches.is_present("follow") && engine != "kvs" {
+        return Err(KvsError::InvalidInput(format!(
+            "only the kvs engine can follow a primary, not {}",
+            engine
+        )));
+    }
+    eprintln!(
+        "kvs-server {} listening on {} with engine {}",
+        crate_version!(),
+        addr,
+        engine
+    );
+    match engine.as_str() {
+        "kvs" => serve(KvStore::open(dir)?, matches),
+        "sled" => serve(SledStore::open(dir)?, matches),
+        "memory" => serve(MemStore::new(), matches),
+        // Only possible if the directory was used by an engine this binary doesn't know.
+        recorded => Err(KvsError::WrongEngine {
+            requested: "kvs".to_owned(),
+            recorded: recorded.to_owned(),
+        }),
+    }
+}
+
+fn serve<E: Replicate>(engine: E, matches: &ArgMatches) -> Result<()> {
+    let addr = matches.value_of("addr").unwrap();
+    let protocol = match matches.value_of("protocol") {
+        Some(protocol) => protocol.parse()?,
+        None => Protocol::Kvs,
+    };
+    let threads = match matches.value_of("threads") {
+        Some(threads) => threads.parse().unwrap(),
+        None => thread::available_parallelism().map_or(4, |n| n.get() as u32),
+    };
+    #[cfg(feature = "async")]
+    {
+        if matches.is_present("async") {
+            let runtime = tokio::runtime::Builder::new_multi_thread()
+                .worker_threads(threads as usize)
+                .enable_io()
+                .build()?;
+            return runtime.block_on(kvs::AsyncKvsServer::new(engine).run(addr));
+        }
+    }
+    match matches.value_of("pool").unwrap() {
+        "naive" => engine
+            .server(NaiveThreadPool::new(threads)?, matches)?
+            .with_protocol(protocol)
+            .run(addr),
+        _ => engine
+            .server(SharedQueueThreadPool::new(threads)?, matches)?
+            .with_protocol(protocol)
+            .run(addr),
+    }
+}
+
+/// How an engine takes part in replication on the threaded server.
+trait Replicate: KvsEngine {
+    fn server<P: ThreadPool>(self, pool: P, matches: &ArgMatches) -> Result<KvsServer<Self, P>>;
+}
+
+impl Replicate for KvStore {
+    fn server<P: ThreadPool>(self, pool: P, matches: &ArgMatches) -> Result<KvsServer<Self, P>> {
+        match matches.value_of("follow") {
+            Some(primary) => KvsServer::follower(self, pool, primary),
+            None => Ok(KvsServer::primary(self, pool)),
+        }
+    }
+}
+
+impl Replicate for SledStore {
+    fn server<P: ThreadPool>(self, pool: P, _: &ArgMatches) -> Result<KvsServer<Self, P>> {
+        Ok(KvsServer::new(self, pool))
+    }
+}
+
+impl Replicate for MemStore {
+    fn server<P: ThreadPool>(self, pool: P, _: &ArgMatches) -> Result<KvsServer<Self, P>> {
+        Ok(KvsServer::new(self, pool))
+    }
+}
@@ -1,37 +1,483 @@
+mod common;
+
 use clap::{
     app_from_crate, crate_authors, crate_description, crate_name, crate_version, AppSettings, Arg,
-    SubCommand,
+    ArgGroup, ArgMatches, SubCommand,
+};
+use common::{condition_args, expected, parse_ttl, read_value, versioned_arg};
+use kvs::{
+    CheckReport, ImportMode, KvStore, KvsEngine, KvsError, MemStore, Result, SledStore, StoreStats,
+    WriteBatch,
 };
+use std::env;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::exit;
+use std::time::{SystemTime, UNIX_EPOCH};
 
 fn main() {
     let app = app_from_crate!()
         .setting(AppSettings::SubcommandRequiredElseHelp)
+        .arg(
+            Arg::with_name("dir")
+                .long("dir")
+                .takes_value(true)
+                .global(true)
+                .help("Directory of the store, defaults to the current directory"),
+        )
+        .arg(
+            Arg::with_name("engine")
+                .long("engine")
+                .takes_value(true)
+                .possible_values(&["kvs", "sled", "memory"])
+                .global(true)
+                .help(
+                    "Storage engine, defaults to the one which used the directory before or kvs. \
+                     memory keeps nothing once the command is done",
+                ),
+        )
+        .arg(
+            Arg::with_name("ns")
+                .long("ns")
+                .takes_value(true)
+                .global(true)
+                .help("Namespace of the keys, the kvs engine has one of its own for every name"),
+        )
         .subcommand(
             SubCommand::with_name("set")
                 .arg(Arg::with_name("key").index(1).required(true))
-                .arg(Arg::with_name("value").index(2).required(true)),
+                .arg(Arg::with_name("value").index(2).required_unless("file"))
+                .arg(
+                    Arg::with_name("file")
+                        .long("file")
+                        .takes_value(true)
+                        .conflicts_with("value")
+                        .help("Reads the value from this file instead, or from stdin if it is -"),
+                )
+                .arg(
+                    Arg::with_name("if-absent")
+                        .long("if-absent")
+                        .help("Only sets the key if it is not there yet"),
+                )
+                .args(&condition_args())
+                .group(ArgGroup::with_name("condition").args(&[
+                    "if-absent",
+                    "if-version",
+                    "if-value",
+                ]))
+                .arg(
+                    Arg::with_name("ttl")
+                        .long("ttl")
+                        .takes_value(true)
+                        .validator(|ttl| parse_ttl(&ttl).map(|_| ()))
+                        .help("The key is gone after this long, like 500ms, 30s, 5m, 2h or 1d"),
+                )
+                .arg(versioned_arg("Prints the new version of the key")),
+        )
+        .subcommand(
+            SubCommand::with_name("get")
+                .arg(Arg::with_name("key").index(1).required(true))
+                .arg(versioned_arg(
+                    "Prints the version of the key and a space before the value",
+                )),
+        )
+        .subcommand(
+            SubCommand::with_name("rm")
+                .arg(Arg::with_name("key").index(1).required(true))
+                .args(&condition_args())
+                .group(ArgGroup::with_name("condition").args(&["if-version", "if-value"])),
+        )
+        .subcommand(
+            SubCommand::with_name("batch").about(
+                "Applies all or none of the `set <key> <value>` and `rm <key>` lines on stdin",
+            ),
+        )
+        .subcommand(
+            SubCommand::with_name("scan")
+                .about("Prints the entries ordered by key")
+                .arg(
+                    Arg::with_name("prefix")
+                        .long("prefix")
+                        .takes_value(true)
+                        .help("Only keys which start with this"),
+                )
+                .arg(
+                    Arg::with_name("start")
+                        .long("start")
+                        .takes_value(true)
+                        .help("Only keys from this one on"),
+                )
+                .arg(
+                    Arg::with_name("end")
+                        .long("end")
+                        .takes_value(true)
+                        .help("Only keys before this one"),
+                )
+                .arg(
+                    Arg::with_name("limit")
+                        .long("limit")
+                        .takes_value(true)
+                        .validator(|limit| {
+                            limit
+                                .parse::<usize>()
+                                .map(|_| ())
+                                .map_err(|e| e.to_string())
+                        })
+                        .help("Prints at most this many entries"),
+                ),
         )
-        .subcommand(SubCommand::with_name("get").arg(Arg::with_name("key").index(1).required(true)))
-        .subcommand(SubCommand::with_name("rm").arg(Arg::with_name("key").index(1).required(true)));
+        .subcommand(
+            SubCommand::with_name("export")
+                .about("Prints all entries ordered by key")
+                .arg(format_arg()),
+        )
+        .subcommand(
+            SubCommand::with_name("import")
+                .about("Sets the entries read from a file or stdin")
+                .arg(
+                    Arg::with_name("file")
+                        .index(1)
+                        .help("File to read the entries from, stdin if it is - or not given"),
+                )
+                .arg(format_arg())
+                .arg(
+                    Arg::with_name("replace")
+                        .long("replace")
+                        .help("Leaves only the imported entries instead of merging, once the whole input is read"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("compact").about("Rewrites the log with only the live entries"),
+        )
+        .subcommand(
+            SubCommand::with_name("snapshot")
+                .about("Saves the entries under a name to restore them later")
+                .setting(AppSettings::SubcommandRequiredElseHelp)
+                .subcommand(
+                    SubCommand::with_name("create")
+                        .about("Saves the entries as they are now")
+                        .arg(Arg::with_name("name").index(1).required(true)),
+                )
+                .subcommand(
+                    SubCommand::with_name("list").about("Prints the names of the saved snapshots"),
+                )
+                .subcommand(
+                    SubCommand::with_name("restore")
+                        .about("Replaces all entries with those of a saved snapshot")
+                        .arg(Arg::with_name("name").index(1).required(true)),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("ns")
+                .about("Looks after the namespaces of the store")
+                .setting(AppSettings::SubcommandRequiredElseHelp)
+                .subcommand(
+                    SubCommand::with_name("list")
+                        .about("Prints the names of the namespaces which have keys"),
+                )
+                .subcommand(
+                    SubCommand::with_name("stats")
+                        .about("Prints how many keys a namespace has and how many bytes they take")
+                        .arg(Arg::with_name("name").index(1).required(true)),
+                )
+                .subcommand(
+                    SubCommand::with_name("drop")
+                        .about("Removes all keys of a namespace")
+                        .arg(Arg::with_name("name").index(1).required(true)),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("stats")
+                .about("Prints how big the store is and how much of its log is stale")
+                .arg(
+                    Arg::with_name("format")
+                        .long("format")
+                        .takes_value(true)
+                        .possible_values(&["text", "json"])
+                        .default_value("text")
+                        .help("One `name: value` line per detail or a JSON object"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("check")
+                .about("Reads the whole log and prints what is damaged, without changing it"),
+        )
+        .subcommand(SubCommand::with_name("repair").about(
+            "Rewrites the log with the entries of all intact records, dropping the damaged ones",
+        ));
     let matches = app.get_matches();
 
-    let mut store = kvs::KvStore::new();
+    if let Err(e) = run(&matches) {
+        // "Key not found" is an answer, not a malfunction, so it goes to stdout.
+        if let KvsError::KeyNotFound = e {
+            println!("{}", e);
+        } else {
+            eprintln!("{}", e);
+        }
+        exit(e.exit_code());
+    }
+}
+
+fn run(matches: &ArgMatches) -> Result<()> {
+    let dir = match matches.value_of("dir") {
+        Some(dir) => PathBuf::from(dir),
+        None => env::current_dir()?,
+    };
+
+    // Checking only looks at the directory, so it doesn't claim it for an engine.
+    if matches.subcommand_matches("check").is_some() {
+        let requested = matches.value_of("engine");
+        let engine = match (requested, kvs::recorded_engine(&dir)?) {
+            (Some(requested), Some(recorded)) if requested != recorded => {
+                return Err(KvsError::WrongEngine {
+                    requested: requested.to_owned(),
+                    recorded,
+                });
+            }
+            (_, Some(recorded)) => recorded,
+            (requested, None) => requested.unwrap_or("kvs").to_owned(),
+        };
+        if engine != "kvs" {
+            return Err(KvsError::InvalidInput(
+                "only the kvs engine can check".to_owned(),
+            ));
+        }
+        return check(&dir);
+    }
+
+    match kvs::select_engine(&dir, matches.value_of("engine"), "kvs")?.as_str() {
+        "kvs" => {
+            // The store might not open, so this works on the log itself.
+            if matches.subcommand_matches("repair").is_some() {
+                let report = KvStore::repair(&dir)?;
+                print_problems(&report);
+                println!("{} keys salvaged", report.keys);
+                return Ok(());
+            }
+            let store = KvStore::open(dir)?;
+            let store = match matches.value_of("ns") {
+                Some(name) => store.namespace(name),
+                None => store,
+            };
+            // These work on the whole store, whichever namespace is given.
+            if matches.subcommand_matches("compact").is_some() {
+                store.compact()
+            } else if let Some(m) = matches.subcommand_matches("snapshot") {
+                run_snapshot(&store, m)
+            } else if let Some(m) = matches.subcommand_matches("ns") {
+                run_ns(&store, m)
+            } else if let Some(m) = matches.subcommand_matches("stats") {
+                let stats = store.stats()?;
+                if m.value_of("format") == Some("json") {
+                    println!("{}", serde_json::to_string_pretty(&stats)?);
+                } else {
+                    print_stats(&stats);
+                }
+                Ok(())
+            } else {
+                run_engine(store, matches)
+            }
+        }
+        // sled looks after its files itself, memory has none.
+        engine @ ("sled" | "memory") => match matches.subcommand_name() {
+            Some(name @ ("compact" | "snapshot" | "repair")) => Err(KvsError::InvalidInput(
+                format!("only the kvs engine can {}", name),
+            )),
+            Some("stats") => Err(KvsError::InvalidInput(
+                "only the kvs engine has stats".to_owned(),
+            )),
+            _ => {
+                check_no_namespace(matches)?;
+                match engine {
+                    "sled" => run_engine(SledStore::open(dir)?, matches),
+                    // What is written is gone once the command is done, only good for trying things out.
+                    _ => run_engine(MemStore::new(), matches),
+                }
+            }
+        },
+        // Only possible if the directory was used by an engine this binary doesn't know.
+        recorded => Err(KvsError::WrongEngine {
+            requested: "kvs".to_owned(),
+            recorded: recorded.to_owned(),
+        }),
+    }
+}
+
+fn run_snapshot(store: &KvStore, matches: &ArgMatches) -> Result<()> {
+    if let Some(m) = matches.subcommand_matches("create") {
+        store.create_snapshot(m.value_of("name").unwrap())
+    } else if let Some(m) = matches.subcommand_matches("restore") {
+        store.restore_snapshot(m.value_of("name").unwrap())
+    } else {
+        for name in store.snapshot_names()? {
+            println!("{}", name);
+        }
+        Ok(())
+    }
+}
+
+fn run_ns(store: &KvStore, matches: &ArgMatches) -> Result<()> {
+    if let Some(m) = matches.subcommand_matches("stats") {
+        let stats = store.namespace_stats(m.value_of("name").unwrap());
+        println!("{} keys, {} bytes", stats.keys, stats.bytes);
+    } else if let Some(m) = matches.subcommand_matches("drop") {
+        let keys = store.drop_namespace(m.value_of("name").unwrap())?;
+        println!("{} keys removed", keys);
+    } else {
+        for name in store.namespaces() {
+            println!("{}", name);
+        }
+    }
+    Ok(())
+}
+
+/// Prints one `name: value` line per detail of the stats.
+fn print_stats(stats: &StoreStats) {
+    println!("keys: {}", stats.keys);
+    println!("live: {} bytes", stats.live_bytes());
+    println!("stale: {} bytes", stats.stale_bytes());
+    for gen in &stats.generations {
+        println!(
+            "generation {}: {} bytes live, {} bytes stale",
+            gen.gen, gen.live_bytes, gen.stale_bytes
+        );
+    }
+    match stats.last_compaction {
+        Some(at) => {
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |now| now.as_millis() as u64);
+            println!("last compaction: {}s ago", now.saturating_sub(at) / 1000);
+        }
+        None => println!("last compaction: never"),
+    }
+    println!("index: about {} bytes", stats.index_bytes);
+}
+
+/// Fails for the engines without namespaces if one is asked for.
+fn check_no_namespace(matches: &ArgMatches) -> Result<()> {
+    if matches.value_of("ns").is_some() || matches.subcommand_matches("ns").is_some() {
+        return Err(KvsError::InvalidInput(
+            "only the kvs engine has namespaces".to_owned(),
+        ));
+    }
+    Ok(())
+}
+
+fn check(dir: &Path) -> Result<()> {
+    let report = KvStore::check(dir)?;
+    print_problems(&report);
+    println!(
+        "{} generations, {} records, {} keys, {} problems",
+        report.generations,
+        report.records,
+        report.keys,
+        report.problems.len()
+    );
+    if report.is_ok() {
+        Ok(())
+    } else {
+        Err(KvsError::CorruptLog(format!(
+            "{} problems found, `kvs repair` keeps what is intact",
+            report.problems.len()
+        )))
+    }
+}
 
+fn print_problems(report: &CheckReport) {
+    for problem in &report.problems {
+        println!("{}", problem);
+    }
+}
+
+fn run_engine(engine: impl KvsEngine, matches: &ArgMatches) -> Result<()> {
     if let Some(m) = matches.subcommand_matches("set") {
-        let key = m.value_of("key").unwrap().into();
-        let value = m.value_of("value").unwrap().into();
+        let key = m.value_of("key").unwrap();
+        let value = read_value(m)?;
 
-        store.set(key, value);
-        panic!("{}", "unimplemented");
+        let ttl = m.value_of("ttl").map(|ttl| parse_ttl(ttl).unwrap());
+        let version = match (expected(m), ttl) {
+            (Some(expected), ttl) => engine.write_if(key, expected, Some(&value), ttl)?,
+            (None, Some(ttl)) => engine.set_with_ttl(key, value, ttl)?,
+            (None, None) => engine.set(key, value)?,
+        };
+        if m.is_present("versioned") {
+            println!("{}", version);
+        }
     } else if let Some(m) = matches.subcommand_matches("get") {
-        let key = m.value_of("key").unwrap().into();
+        let key = m.value_of("key").unwrap();
 
-        store.get(key);
-        panic!("{}", "unimplemented");
+        match engine.get_versioned(key)? {
+            Some((value, version)) => {
+                let mut stdout = io::stdout();
+                if m.is_present("versioned") {
+                    write!(stdout, "{} ", version)?;
+                }
+                stdout.write_all(&value)?;
+                stdout.write_all(b"\n")?;
+            }
+            None => println!("Key not found"),
+        }
     } else if let Some(m) = matches.subcommand_matches("rm") {
-        let key = m.value_of("key").unwrap().into();
+        let key = m.value_of("key").unwrap();
+
+        match expected(m) {
+            Some(expected) => {
+                engine.write_if(key, expected, None, None)?;
+            }
+            None => engine.remove(key)?,
+        }
+    } else if matches.subcommand_matches("batch").is_some() {
+        let mut input = String::new();
+        io::stdin().read_to_string(&mut input)?;
+        let batch: WriteBatch = input.parse()?;
 
-        store.remove(key);
-        panic!("{}", "unimplemented");
+        engine.apply(&batch)?;
+    } else if let Some(m) = matches.subcommand_matches("scan") {
+        let range = kvs::scan_range(
+            m.value_of("prefix").map(str::as_bytes),
+            m.value_of("start").map(str::as_bytes),
+            m.value_of("end").map(str::as_bytes),
+        );
+        let limit = m
+            .value_of("limit")
+            .map_or(usize::MAX, |limit| limit.parse().unwrap());
+
+        for entry in engine.scan(range).take(limit) {
+            let (key, value) = entry?;
+            // Bytes which aren't UTF-8 show up as replacement characters.
+            println!(
+                "{} {}",
+                String::from_utf8_lossy(&key),
+                String::from_utf8_lossy(&value)
+            );
+        }
+    } else if let Some(m) = matches.subcommand_matches("export") {
+        let format = m.value_of("format").unwrap().parse()?;
+        kvs::export(&engine, format, BufWriter::new(io::stdout()))?;
+    } else if let Some(m) = matches.subcommand_matches("import") {
+        let format = m.value_of("format").unwrap().parse()?;
+        let mode = if m.is_present("replace") {
+            ImportMode::Replace
+        } else {
+            ImportMode::Merge
+        };
+        match m.value_of_os("file") {
+            Some(path) if path != "-" => {
+                kvs::import(&engine, format, BufReader::new(File::open(path)?), mode)?
+            }
+            _ => kvs::import(&engine, format, io::stdin().lock(), mode)?,
+        };
     }
+    Ok(())
+}
+
+fn format_arg() -> Arg<'static, 'static> {
+    Arg::with_name("format")
+        .long("format")
+        .takes_value(true)
+        .possible_values(&["jsonl", "csv"])
+        .default_value("jsonl")
+        .help("JSON Lines with one object per entry or CSV with a header")
 }
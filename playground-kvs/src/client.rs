@@ -0,0 +1,228 @@
+use crate::protocol::{read_frame, write_frame, Request, Response};
+use crate::{prefix_range, Event, Expected, KvsError, Result, Status, WriteBatch};
+use std::io::{BufReader, BufWriter};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::ops::RangeBounds;
+use std::time::Duration;
+
+/// Talks to a [`KvsServer`](crate::KvsServer) over one TCP connection.
+/// Errors of the server come back as [`KvsError::KeyNotFound`], [`KvsError::Conflict`]
+/// or [`KvsError::Server`].
+pub struct KvsClient {
+    reader: BufReader<TcpStream>,
+    writer: BufWriter<TcpStream>,
+}
+
+impl KvsClient {
+    /// Connects to the server listening on `addr`.
+    pub fn connect(addr: impl ToSocketAddrs) -> Result<Self> {
+        let stream = TcpStream::connect(addr)?;
+        Ok(Self {
+            reader: BufReader::new(stream.try_clone()?),
+            writer: BufWriter::new(stream),
+        })
+    }
+
+    /// Returns the value of the key, if there is one.
+    pub fn get(&mut self, key: impl AsRef<[u8]>) -> Result<Option<Vec<u8>>> {
+        let key = key.as_ref().to_vec();
+        match self.request(&Request::Get { key })? {
+            Response::Value(value) => Ok(value),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    /// Like [`KvsClient::get`] for values which were set as strings.
+    /// Fails with [`KvsError::NotUtf8`] if the value is no string.
+    pub fn get_string(&mut self, key: impl AsRef<[u8]>) -> Result<Option<String>> {
+        match self.get(key)? {
+            Some(value) => Ok(Some(String::from_utf8(value)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Returns the value of the key with its version, if there is one.
+    pub fn get_versioned(&mut self, key: impl AsRef<[u8]>) -> Result<Option<(Vec<u8>, u64)>> {
+        let key = key.as_ref().to_vec();
+        match self.request(&Request::GetVersioned { key })? {
+            Response::Versioned(entry) => Ok(entry),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    /// Sets the value of the key, returns its new version.
+    pub fn set(&mut self, key: impl AsRef<[u8]>, value: impl AsRef<[u8]>) -> Result<u64> {
+        self.set_maybe_ttl(key, value, None)
+    }
+
+    /// Like [`KvsClient::set`], but the key is gone after `ttl`.
+    pub fn set_with_ttl(
+        &mut self,
+        key: impl AsRef<[u8]>,
+        value: impl AsRef<[u8]>,
+        ttl: Duration,
+    ) -> Result<u64> {
+        self.set_maybe_ttl(key, value, Some(ttl))
+    }
+
+    fn set_maybe_ttl(
+        &mut self,
+        key: impl AsRef<[u8]>,
+        value: impl AsRef<[u8]>,
+        ttl: Option<Duration>,
+    ) -> Result<u64> {
+        let request = Request::Set {
+            key: key.as_ref().to_vec(),
+            value: value.as_ref().to_vec(),
+            ttl,
+        };
+        match self.request(&request)? {
+            Response::Version(version) => Ok(version),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    /// Like [`KvsEngine::write_if`](crate::KvsEngine::write_if).
+    pub fn write_if(
+        &mut self,
+        key: impl AsRef<[u8]>,
+        expected: Expected,
+        new: Option<&[u8]>,
+        ttl: Option<Duration>,
+    ) -> Result<u64> {
+        let request = Request::WriteIf {
+            key: key.as_ref().to_vec(),
+            expected,
+            new: new.map(<[u8]>::to_vec),
+            ttl,
+        };
+        match self.request(&request)? {
+            Response::Version(version) => Ok(version),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    /// Like [`KvsEngine::compare_and_swap`](crate::KvsEngine::compare_and_swap).
+    pub fn compare_and_swap(
+        &mut self,
+        key: impl AsRef<[u8]>,
+        expected: Option<&[u8]>,
+        new: Option<&[u8]>,
+    ) -> Result<u64> {
+        let expected = Expected::Value(expected.map(<[u8]>::to_vec));
+        self.write_if(key, expected, new, None)
+    }
+
+    /// Like [`KvsEngine::set_if_absent`](crate::KvsEngine::set_if_absent).
+    pub fn set_if_absent(&mut self, key: impl AsRef<[u8]>, value: impl AsRef<[u8]>) -> Result<u64> {
+        self.write_if(key, Expected::Version(0), Some(value.as_ref()), None)
+    }
+
+    /// Like [`KvsEngine::set_if_version`](crate::KvsEngine::set_if_version).
+    pub fn set_if_version(
+        &mut self,
+        key: impl AsRef<[u8]>,
+        value: impl AsRef<[u8]>,
+        version: u64,
+    ) -> Result<u64> {
+        self.write_if(key, Expected::Version(version), Some(value.as_ref()), None)
+    }
+
+    /// Removes the key, fails with [`KvsError::KeyNotFound`] if there is no such key.
+    pub fn remove(&mut self, key: impl AsRef<[u8]>) -> Result<()> {
+        let key = key.as_ref().to_vec();
+        match self.request(&Request::Remove { key })? {
+            Response::Ok => Ok(()),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    /// Applies all changes of `batch` or, if one of them fails, none of them.
+    pub fn apply(&mut self, batch: &WriteBatch) -> Result<()> {
+        let batch = batch.clone();
+        match self.request(&Request::Batch { batch })? {
+            Response::Ok => Ok(()),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    /// Returns the entries with keys in `range` ordered by key, at most `limit` of them if given.
+    pub fn scan(
+        &mut self,
+        range: impl RangeBounds<Vec<u8>>,
+        limit: Option<usize>,
+    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let request = Request::Scan {
+            range: (range.start_bound().cloned(), range.end_bound().cloned()),
+            limit: limit.map(|limit| limit as u64),
+        };
+        match self.request(&request)? {
+            Response::Entries(entries) => Ok(entries),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    /// Returns the entries with keys which start with `prefix` ordered by key,
+    /// at most `limit` of them if given.
+    pub fn scan_prefix(
+        &mut self,
+        prefix: impl AsRef<[u8]>,
+        limit: Option<usize>,
+    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        self.scan(prefix_range(prefix), limit)
+    }
+
+    /// Returns how the server takes part in replication.
+    pub fn status(&mut self) -> Result<Status> {
+        match self.request(&Request::Status)? {
+            Response::Status(status) => Ok(status),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    /// Watches the changes of the keys which start with `prefix` from now on.
+    /// The connection is only used for the changes from then on.
+    pub fn watch(mut self, prefix: impl AsRef<[u8]>) -> Result<RemoteWatch> {
+        let prefix = prefix.as_ref().to_vec();
+        match self.request(&Request::Watch { prefix })? {
+            Response::Ok => Ok(RemoteWatch {
+                reader: self.reader,
+            }),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    fn request(&mut self, request: &Request) -> Result<Response> {
+        write_frame(&mut self.writer, request)?;
+        match read_frame(&mut self.reader)? {
+            Some(Response::KeyNotFound) => Err(KvsError::KeyNotFound),
+            Some(Response::Conflict(version)) => Err(KvsError::Conflict(version)),
+            Some(Response::Err(message)) => Err(KvsError::Server(message)),
+            Some(response) => Ok(response),
+            None => Err(KvsError::Server("connection closed".to_owned())),
+        }
+    }
+}
+
+/// The changes of the keys which a [`KvsClient::watch`] is for, as the server sends them.
+/// Iterating waits for the next change and ends when the server closes the connection.
+pub struct RemoteWatch {
+    reader: BufReader<TcpStream>,
+}
+
+impl Iterator for RemoteWatch {
+    type Item = Result<Event>;
+
+    fn next(&mut self) -> Option<Result<Event>> {
+        match read_frame(&mut self.reader) {
+            Ok(Some(Response::Event(event))) => Some(Ok(event)),
+            Ok(Some(response)) => Some(Err(unexpected(response))),
+            Ok(None) => None,
+            Err(e) => Some(Err(e)),
+        }
+    }
+}
+
+fn unexpected(response: Response) -> KvsError {
+    KvsError::Server(format!("unexpected response {:?}", response))
+}
@@ -0,0 +1,118 @@
+use crate::{KvsError, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::str::FromStr;
+
+/// Sets and removals which are applied all together or not at all
+/// with [`KvsEngine::apply`](crate::KvsEngine::apply).
+/// Readers see either none or all of them.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct WriteBatch {
+    ops: Vec<BatchOp>,
+}
+
+/// One change of a [`WriteBatch`].
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub(crate) enum BatchOp {
+    Set {
+        #[serde(with = "serde_bytes")]
+        key: Vec<u8>,
+        #[serde(with = "serde_bytes")]
+        value: Vec<u8>,
+    },
+    Remove {
+        #[serde(with = "serde_bytes")]
+        key: Vec<u8>,
+    },
+}
+
+impl WriteBatch {
+    /// Returns an empty batch, applying it changes nothing.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds setting the key to the value.
+    pub fn set(&mut self, key: impl AsRef<[u8]>, value: impl AsRef<[u8]>) -> &mut Self {
+        self.ops.push(BatchOp::Set {
+            key: key.as_ref().to_vec(),
+            value: value.as_ref().to_vec(),
+        });
+        self
+    }
+
+    /// Adds removing the key. Applying the batch fails with [`KvsError::KeyNotFound`]
+    /// if the key isn't there by then, neither in the store nor set earlier in the batch.
+    pub fn remove(&mut self, key: impl AsRef<[u8]>) -> &mut Self {
+        self.ops.push(BatchOp::Remove {
+            key: key.as_ref().to_vec(),
+        });
+        self
+    }
+
+    /// How many changes were added.
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+
+    /// Whether no changes were added.
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+
+    pub(crate) fn ops(&self) -> &[BatchOp] {
+        &self.ops
+    }
+
+    /// Fails with [`KvsError::KeyNotFound`] if a removal wouldn't find its key
+    /// when the changes are applied in order to a store which contains the keys `contains` says.
+    pub(crate) fn check_removals(&self, contains: impl Fn(&[u8]) -> bool) -> Result<()> {
+        let mut changed: HashMap<&[u8], bool> = HashMap::new();
+        for op in &self.ops {
+            match op {
+                BatchOp::Set { key, .. } => {
+                    changed.insert(key, true);
+                }
+                BatchOp::Remove { key } => {
+                    let present = changed.get(key.as_slice()).copied();
+                    if !present.unwrap_or_else(|| contains(key)) {
+                        return Err(KvsError::KeyNotFound);
+                    }
+                    changed.insert(key, false);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Parses one change per line, `set <key> <value>` or `rm <key>`.
+/// The value is the rest of the line, so it can contain spaces.
+/// Empty lines and lines starting with `#` are skipped.
+impl FromStr for WriteBatch {
+    type Err = KvsError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut batch = WriteBatch::new();
+        for (number, line) in s.lines().enumerate() {
+            let line = line.trim_start();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (command, args) = line.split_once(' ').unwrap_or((line, ""));
+            match (command, args.split_once(' ')) {
+                ("set", Some((key, value))) if !key.is_empty() => batch.set(key, value),
+                ("rm", _) if !args.trim().is_empty() && !args.trim().contains(' ') => {
+                    batch.remove(args.trim())
+                }
+                _ => {
+                    return Err(KvsError::InvalidInput(format!(
+                        "line {} is neither `set <key> <value>` nor `rm <key>`",
+                        number + 1
+                    )))
+                }
+            };
+        }
+        Ok(batch)
+    }
+}
@@ -0,0 +1,1222 @@
+use super::batch::BatchOp;
+use super::{
+    expiry, now_millis, prefix_range, Event, Expected, KeyCounts, KvsEngine, Scan, Watch, Watchers,
+    WriteBatch,
+};
+use crate::{KvsError, Result};
+use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
+use std::cmp::Reverse;
+use std::collections::btree_map::Entry;
+use std::collections::{BTreeMap, BinaryHeap};
+use std::convert::{TryFrom, TryInto};
+use std::ffi::OsStr;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::ops::RangeBounds;
+use std::path::{Path, PathBuf};
+use std::slice;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread;
+use std::time::Duration;
+
+mod namespace;
+mod repair;
+mod snapshot;
+mod stats;
+mod tail;
+
+pub use self::namespace::NamespaceStats;
+pub use self::repair::{CheckReport, Problem};
+pub use self::snapshot::Snapshot;
+pub use self::stats::{GenerationStats, StoreStats};
+pub use self::tail::LogOffset;
+
+/// Every log starts with these bytes followed by the format version as little endian u32,
+/// the highest version given to a key when the log was created as little endian u64
+/// and the CRC32 of all that as little endian u32.
+const LOG_MAGIC: &[u8; 4] = b"KVSL";
+const LOG_VERSION: u32 = 7;
+const LOG_HEADER_LEN: u64 = 20;
+/// Every record starts with the length of its command, the CRC32 of the length
+/// and the CRC32 of the command, all as little endian u32.
+/// A damaged length is told apart from a record which was cut off by its own checksum.
+const RECORD_HEADER_LEN: u64 = 12;
+
+/// A key-value store which appends every change to a log in a directory.
+/// Only the keys, their versions and the positions of their values in the log are kept in memory,
+/// values are read from the log when they are requested.
+/// Opening the same directory again replays the log and you get back what you set before.
+///
+/// The log is split into generations, one file `<generation>.log` each.
+/// Overwritten and removed entries stay in the log until they make up more than
+/// [`KvStoreOptions::compaction_threshold`] bytes, then the live entries are copied
+/// into a new generation and the older generations are deleted.
+/// [`KvStore::stats`] tells how much of the log is stale.
+///
+/// Expired keys are hidden right away but stay in the log until the next compaction.
+/// Their records count as stale once they expire, so they get compacted away like overwritten ones.
+///
+/// [`KvStore::snapshot`] returns a view which long reads can use to see no later writes.
+/// Snapshots can also be saved under a name in the store directory and restored later.
+///
+/// [`KvsEngine::watch`] gets the changes of keys as they are written.
+/// Restoring a snapshot shows up as a removal of every key which isn't in it
+/// and a set of every key which is.
+///
+/// Every command is written as a record with its length and checksum.
+/// If the process dies while writing, the torn record at the end of the log is cut off when it is opened.
+/// A damaged record anywhere else fails the open with [`KvsError::CorruptLog`].
+/// When the log is synced to disk is up to [`KvStoreOptions::sync`].
+///
+/// The changes of a [`WriteBatch`] are written as a marker followed by their commands.
+/// If the process dies before all of them are written, the batch is cut off the log when it is opened.
+///
+/// [`KvStore::namespace`] gives keyspaces of their own within the same log.
+/// The keys in the log start with their namespace.
+///
+/// Clones share the same index and writer, so one store can be used by many threads.
+/// Every clone has its own file handles to read from, so reads only wait for each other
+/// while the index is switched after a compaction. Writes wait for each other.
+#[derive(Clone)]
+pub struct KvStore {
+    /// What the keys of this store start with in the log.
+    namespace: Arc<[u8]>,
+    index: Arc<RwLock<Index>>,
+    reader: KvStoreReader,
+    writer: Arc<Mutex<KvStoreWriter>>,
+}
+
+/// Settings of a [`KvStore`] which are not stored along with the data.
+#[derive(Clone, Debug)]
+pub struct KvStoreOptions {
+    /// The store gets compacted automatically when more than this many bytes of the log are stale.
+    pub compaction_threshold: u64,
+    /// When writes are synced to disk, so that they survive a crash of the system.
+    pub sync: SyncPolicy,
+}
+
+impl Default for KvStoreOptions {
+    fn default() -> Self {
+        Self {
+            compaction_threshold: 1024 * 1024,
+            sync: SyncPolicy::Interval(Duration::from_millis(100)),
+        }
+    }
+}
+
+/// When a [`KvStore`] syncs its log to disk. Writes which weren't synced yet survive the
+/// process dying but can get lost if the whole system goes down.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SyncPolicy {
+    /// Every write is synced before it returns.
+    Always,
+    /// A background thread syncs the writes of the last interval, if there were any.
+    /// The last writes are also synced when the store is dropped.
+    Interval(Duration),
+    /// The log is only synced by compactions, it is up to the system when the rest gets to disk.
+    Never,
+}
+
+/// What gets written to the log, one after another.
+/// The bytes are serialized like strings, so logs with string keys and values read the same.
+#[derive(Serialize, Deserialize, Debug)]
+enum Command {
+    Set {
+        #[serde(with = "serde_bytes")]
+        key: Vec<u8>,
+        #[serde(with = "serde_bytes")]
+        value: Vec<u8>,
+        version: u64,
+        /// Milliseconds since the Unix epoch.
+        expires_at: Option<u64>,
+    },
+    Remove {
+        #[serde(with = "serde_bytes")]
+        key: Vec<u8>,
+    },
+    /// The next `len` commands only count if all of them made it into the log.
+    Batch { len: u64 },
+}
+
+impl Command {
+    /// The version which the command gives to its key, 0 if it doesn't set one.
+    fn version(&self) -> u64 {
+        match self {
+            Command::Set { version, .. } => *version,
+            _ => 0,
+        }
+    }
+}
+
+/// Where the record of a command lies in the log.
+#[derive(Clone, Copy, Debug)]
+struct CommandPos {
+    gen: u64,
+    pos: u64,
+    len: u64,
+}
+
+/// What is kept in memory of a key which is there.
+#[derive(Clone, Copy, Debug)]
+struct IndexEntry {
+    /// The record of the set command with the value.
+    pos: CommandPos,
+    version: u64,
+    expires_at: Option<u64>,
+}
+
+impl IndexEntry {
+    fn is_live(&self, now: u64) -> bool {
+        self.expires_at.is_none_or(|expires_at| now < expires_at)
+    }
+}
+
+type Index = BTreeMap<Vec<u8>, IndexEntry>;
+
+/// When the record of a set with an expiry time becomes stale.
+#[derive(PartialEq, Eq, PartialOrd, Ord)]
+struct Expiring {
+    /// Milliseconds since the Unix epoch.
+    expires_at: u64,
+    gen: u64,
+    pos: u64,
+    key: Vec<u8>,
+}
+
+/// Reads values from the log with file handles of its own.
+struct KvStoreReader {
+    path: Arc<PathBuf>,
+    /// Generations before this one were compacted away, their handles can be closed.
+    safe_point: Arc<AtomicU64>,
+    readers: RefCell<BTreeMap<u64, BufReader<File>>>,
+}
+
+/// Everything which changes the log, only one thread at a time gets to use it.
+struct KvStoreWriter {
+    path: Arc<PathBuf>,
+    options: KvStoreOptions,
+    index: Arc<RwLock<Index>>,
+    reader: KvStoreReader,
+    writer: BufWriter<File>,
+    writer_gen: u64,
+    writer_pos: u64,
+    /// Bytes in the log which are not needed anymore.
+    uncompacted: u64,
+    /// The keys with an expiry time, soonest first,
+    /// whose records become stale once it is over.
+    expiring: BinaryHeap<Reverse<Expiring>>,
+    /// The highest version given to a key so far. New logs start with it,
+    /// so it isn't lost when the records with it are compacted away.
+    last_version: u64,
+    /// Whether there were writes since the log was last synced.
+    unsynced: bool,
+    /// Why the last sync in the background failed, reported by the next write.
+    sync_error: Option<io::Error>,
+    watchers: Watchers,
+}
+
+impl KvStore {
+    /// Opens the store in the directory `path`, creating the directory and the log if needed.
+    /// The log gets replayed to find out which keys are there.
+    pub fn open(path: impl Into<PathBuf>) -> Result<Self> {
+        Self::open_with_options(path, KvStoreOptions::default())
+    }
+
+    /// Like [`KvStore::open`] but with other than the default options.
+    pub fn open_with_options(path: impl Into<PathBuf>, options: KvStoreOptions) -> Result<Self> {
+        let path = Arc::new(path.into());
+        fs::create_dir_all(&*path)?;
+
+        let mut index = BTreeMap::new();
+        let mut uncompacted = 0;
+        let mut last_version = 0;
+
+        let gen_list = sorted_gen_list(&path)?;
+        for &gen in &gen_list {
+            let mut reader = BufReader::new(File::open(log_path(&path, gen))?);
+            let (stale, len) = load(gen, &mut reader, &mut index, &mut last_version)?;
+            uncompacted += stale;
+            let file_len = reader.get_ref().metadata()?.len();
+            if len < file_len && Some(&gen) != gen_list.last() {
+                // Only the last generation is written to, the others were complete.
+                return Err(KvsError::CorruptLog(format!(
+                    "log {} ends in an incomplete record at byte {} although later logs follow",
+                    gen, len
+                )));
+            }
+            if len < file_len {
+                // The process died while writing a record or a batch, so it never happened.
+                let file = OpenOptions::new().write(true).open(log_path(&path, gen))?;
+                file.set_len(len)?;
+                file.sync_all()?;
+            }
+        }
+
+        // Keep on writing to the last generation.
+        let writer_gen = gen_list.last().copied().unwrap_or(1);
+        let (writer, writer_pos) = open_log(&path, writer_gen, last_version)?;
+
+        let sync = options.sync;
+        let index = Arc::new(RwLock::new(index));
+        let reader = KvStoreReader {
+            path: Arc::clone(&path),
+            safe_point: Arc::new(AtomicU64::new(0)),
+            readers: RefCell::new(BTreeMap::new()),
+        };
+        let mut writer = KvStoreWriter {
+            path,
+            options,
+            index: Arc::clone(&index),
+            reader: reader.clone(),
+            writer,
+            writer_gen,
+            writer_pos,
+            uncompacted,
+            expiring: BinaryHeap::new(),
+            last_version,
+            unsynced: false,
+            sync_error: None,
+            watchers: Watchers::default(),
+        };
+        writer.track_expiring();
+        writer.drop_expired();
+        let writer = Arc::new(Mutex::new(writer));
+
+        if let SyncPolicy::Interval(interval) = sync {
+            // The thread ends once the store is dropped.
+            let writer = Arc::downgrade(&writer);
+            thread::spawn(move || loop {
+                thread::sleep(interval);
+                let writer = match writer.upgrade() {
+                    Some(writer) => writer,
+                    None => break,
+                };
+                let mut writer = match writer.lock() {
+                    Ok(writer) => writer,
+                    Err(_) => break,
+                };
+                if let Err(e) = writer.sync() {
+                    writer.sync_error = Some(e);
+                }
+            });
+        }
+
+        Ok(Self {
+            namespace: namespace::default_namespace(),
+            index,
+            reader,
+            writer,
+        })
+    }
+
+    /// Copies all live entries into a new generation and deletes the older generations.
+    /// The index is switched to the new generation only after it was completely written and synced,
+    /// so a failing compaction leaves the store as it was.
+    pub fn compact(&self) -> Result<()> {
+        self.writer.lock().unwrap().compact()
+    }
+}
+
+impl KvsEngine for KvStore {
+    /// Inserts a new key-value entry or overwrites an existing one with an equal key.
+    /// The entry is written to the log before this call returns.
+    fn set(&self, key: impl AsRef<[u8]>, value: impl AsRef<[u8]>) -> Result<u64> {
+        self.writer
+            .lock()
+            .unwrap()
+            .set(&self.log_key(key.as_ref()), value.as_ref(), None)
+    }
+
+    /// The expiry time is written to the log along with the entry.
+    fn set_with_ttl(
+        &self,
+        key: impl AsRef<[u8]>,
+        value: impl AsRef<[u8]>,
+        ttl: Duration,
+    ) -> Result<u64> {
+        self.writer.lock().unwrap().set(
+            &self.log_key(key.as_ref()),
+            value.as_ref(),
+            expiry(Some(ttl)),
+        )
+    }
+
+    /// The changes are written to the log before this call returns.
+    fn apply(&self, batch: &WriteBatch) -> Result<()> {
+        self.writer.lock().unwrap().apply(&self.namespace, batch)
+    }
+
+    /// The entries are written to a new generation of the log, which takes the place of the
+    /// others only once it is complete. The entries of the other namespaces are copied to it.
+    fn replace_all(&self, entries: Scan) -> Result<()> {
+        self.writer
+            .lock()
+            .unwrap()
+            .replace_namespace(&self.namespace, entries)
+    }
+
+    /// If an equal key was set before and not removed yet,
+    /// then its value is read from the log and returned.
+    fn get_versioned(&self, key: impl AsRef<[u8]>) -> Result<Option<(Vec<u8>, u64)>> {
+        // Holding the lock while reading keeps a compaction from deleting the generation meanwhile.
+        let index = self.index.read().unwrap();
+        match live_entry(&index, &self.log_key(key.as_ref())) {
+            Some(entry) => Ok(Some((self.reader.read_value(entry.pos)?, entry.version))),
+            None => Ok(None),
+        }
+    }
+
+    /// Removes the entry with an equal key.
+    /// The removal is written to the log, so the key stays removed after reopening the store.
+    /// Fails with [`KvsError::KeyNotFound`] if there is no such key.
+    fn remove(&self, key: impl AsRef<[u8]>) -> Result<()> {
+        self.writer
+            .lock()
+            .unwrap()
+            .remove(&self.log_key(key.as_ref()))
+    }
+
+    /// Writes to the log like [`KvStore::set`] or [`KvStore::remove`] if the key is as expected.
+    fn write_if(
+        &self,
+        key: impl AsRef<[u8]>,
+        expected: Expected,
+        new: Option<&[u8]>,
+        ttl: Option<Duration>,
+    ) -> Result<u64> {
+        self.writer.lock().unwrap().write_if(
+            &self.log_key(key.as_ref()),
+            &expected,
+            new,
+            expiry(ttl),
+        )
+    }
+
+    /// Reads the values of the keys in batches, holding the lock while reading like [`KvStore::get`].
+    fn scan(&self, range: impl RangeBounds<Vec<u8>>) -> Scan {
+        let store = self.clone();
+        let range = (range.start_bound().cloned(), range.end_bound().cloned());
+        Scan::new(range, move |range, limit| {
+            let index = store.index.read().unwrap();
+            let now = now_millis();
+            let prefix_len = store.namespace.len();
+            index
+                .range::<Vec<u8>, _>(namespace::log_range(&store.namespace, range))
+                .filter(|(_, entry)| entry.is_live(now))
+                .take(limit)
+                .map(|(key, entry)| {
+                    let value = store.reader.read_value(entry.pos)?;
+                    Ok((key[prefix_len..].to_vec(), value))
+                })
+                .collect()
+        })
+    }
+
+    fn scan_keys(&self, range: impl RangeBounds<Vec<u8>>) -> Scan<Vec<u8>> {
+        let store = self.clone();
+        let range = (range.start_bound().cloned(), range.end_bound().cloned());
+        Scan::new(range, move |range, limit| {
+            let index = store.index.read().unwrap();
+            let now = now_millis();
+            let prefix_len = store.namespace.len();
+            Ok(index
+                .range::<Vec<u8>, _>(namespace::log_range(&store.namespace, range))
+                .filter(|(_, entry)| entry.is_live(now))
+                .take(limit)
+                .map(|(key, _)| key[prefix_len..].to_vec())
+                .collect())
+        })
+    }
+
+    fn scan_consistent(&self, range: impl RangeBounds<Vec<u8>>) -> Result<Scan> {
+        Ok(self.snapshot()?.scan(range))
+    }
+
+    /// Counts the keys of the namespace of the store in the index.
+    fn key_counts(&self) -> Result<KeyCounts> {
+        let index = self.index.read().unwrap();
+        let now = now_millis();
+        Ok(index
+            .range::<Vec<u8>, _>(prefix_range(&self.namespace))
+            .filter(|(_, entry)| entry.is_live(now))
+            .fold(KeyCounts::default(), |counts, (_, entry)| {
+                counts.add(entry.expires_at)
+            }))
+    }
+
+    /// The changes are seen in the order they are written to the log,
+    /// also those which a follower gets from its primary.
+    fn watch(&self, prefix: impl AsRef<[u8]>) -> Watch {
+        self.writer
+            .lock()
+            .unwrap()
+            .watchers
+            .watch_namespace(&self.namespace, prefix.as_ref())
+    }
+}
+
+impl KvStoreReader {
+    fn read_value(&self, pos: CommandPos) -> Result<Vec<u8>> {
+        self.read_and(pos, |mut reader| match read_record(&mut reader, pos.len)? {
+            (Record::Command(Command::Set { value, .. }), _) => Ok(value),
+            _ => Err(KvsError::CorruptLog(format!(
+                "index of {:?} points to a record without value",
+                self.path
+            ))),
+        })
+    }
+
+    /// Copies the record at `pos` to `writer` as it is, returns how many bytes that were.
+    fn copy_command(&self, pos: CommandPos, writer: &mut impl Write) -> Result<u64> {
+        self.read_and(pos, |mut reader| Ok(io::copy(&mut reader, writer)?))
+    }
+
+    /// Calls `f` with a reader of exactly the record at `pos`.
+    fn read_and<R>(
+        &self,
+        pos: CommandPos,
+        f: impl FnOnce(io::Take<&mut BufReader<File>>) -> Result<R>,
+    ) -> Result<R> {
+        self.close_stale_handles();
+        let mut readers = self.readers.borrow_mut();
+        let reader = match readers.entry(pos.gen) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => {
+                entry.insert(BufReader::new(File::open(log_path(&self.path, pos.gen))?))
+            }
+        };
+        reader.seek(SeekFrom::Start(pos.pos))?;
+        f(reader.take(pos.len))
+    }
+
+    fn close_stale_handles(&self) {
+        let safe_point = self.safe_point.load(Ordering::SeqCst);
+        let mut readers = self.readers.borrow_mut();
+        while let Some(entry) = readers.first_entry() {
+            if *entry.key() >= safe_point {
+                break;
+            }
+            entry.remove();
+        }
+    }
+}
+
+impl Clone for KvStoreReader {
+    /// The clone opens file handles of its own.
+    fn clone(&self) -> Self {
+        Self {
+            path: Arc::clone(&self.path),
+            safe_point: Arc::clone(&self.safe_point),
+            readers: RefCell::new(BTreeMap::new()),
+        }
+    }
+}
+
+impl KvStoreWriter {
+    fn set(&mut self, key: &[u8], value: &[u8], expires_at: Option<u64>) -> Result<u64> {
+        let version = self.last_version + 1;
+        let command = Command::Set {
+            key: key.to_vec(),
+            value: value.to_vec(),
+            version,
+            expires_at,
+        };
+        let pos = self.append(&command)?;
+        self.last_version = version;
+        self.track_expiry(&command, pos);
+        let events = self.events(slice::from_ref(&command));
+        self.uncompacted += replay(&mut self.index.write().unwrap(), command, pos);
+        self.watchers.send(events);
+        self.maybe_compact()?;
+        Ok(version)
+    }
+
+    fn remove(&mut self, key: &[u8]) -> Result<()> {
+        if live_entry(&self.index.read().unwrap(), key).is_none() {
+            return Err(KvsError::KeyNotFound);
+        }
+        let command = Command::Remove { key: key.to_vec() };
+        let pos = self.append(&command)?;
+        let events = self.events(slice::from_ref(&command));
+        self.uncompacted += replay(&mut self.index.write().unwrap(), command, pos);
+        self.watchers.send(events);
+        self.maybe_compact()
+    }
+
+    fn write_if(
+        &mut self,
+        key: &[u8],
+        expected: &Expected,
+        new: Option<&[u8]>,
+        expires_at: Option<u64>,
+    ) -> Result<u64> {
+        // Only this writer changes the index, so the entry stays the same until it is written.
+        let entry = live_entry(&self.index.read().unwrap(), key).copied();
+        let value = match (entry, expected) {
+            (Some(entry), Expected::Value(_)) => self.reader.read_value(entry.pos)?,
+            _ => Vec::new(),
+        };
+        if !expected.matches(entry.map(|entry| (value.as_slice(), entry.version))) {
+            return Err(KvsError::Conflict(entry.map_or(0, |entry| entry.version)));
+        }
+        match new {
+            Some(value) => self.set(key, value, expires_at),
+            None if entry.is_some() => self.remove(key).map(|()| 0),
+            // There is nothing to remove.
+            None => Ok(0),
+        }
+    }
+
+    /// Applies the batch to the keys of the namespace whose keys in the log start with `prefix`.
+    fn apply(&mut self, prefix: &[u8], batch: &WriteBatch) -> Result<()> {
+        let log_key = |key: &[u8]| [prefix, key].concat();
+        // Only this writer changes the index, so the check stays true until the batch is applied.
+        let index = self.index.read().unwrap();
+        batch.check_removals(|key| live_entry(&index, &log_key(key)).is_some())?;
+        if batch.is_empty() {
+            return Ok(());
+        }
+        let mut commands = vec![Command::Batch {
+            len: batch.len() as u64,
+        }];
+        let mut version = self.last_version;
+        for op in batch.ops() {
+            commands.push(match op {
+                BatchOp::Set { key, value } => {
+                    version += 1;
+                    Command::Set {
+                        key: log_key(key),
+                        value: value.clone(),
+                        version,
+                        expires_at: None,
+                    }
+                }
+                BatchOp::Remove { key } => Command::Remove { key: log_key(key) },
+            });
+        }
+        drop(index);
+        let positions = self.append_all(&commands)?;
+        self.last_version = version;
+        let events = self.events(&commands);
+
+        let mut index = self.index.write().unwrap();
+        for (command, pos) in commands.into_iter().zip(positions) {
+            self.uncompacted += replay(&mut index, command, pos);
+        }
+        drop(index);
+        self.watchers.send(events);
+        self.maybe_compact()
+    }
+
+    fn compact(&mut self) -> Result<()> {
+        self.rewrite(Self::write_compaction)?;
+        // The compaction went through, the time of it is only for the stats
+        // and must not fail the write which started it.
+        if let Err(e) = stats::save_compaction_time(&self.path) {
+            eprintln!("couldn't save the time of the compaction: {}", e);
+        }
+        Ok(())
+    }
+
+    /// Replaces the log with the entries of the snapshot log at `path`.
+    /// They get new versions, so a key never has a version again which it had before.
+    fn restore(&mut self, path: &Path) -> Result<()> {
+        self.replace(|writer, gen| {
+            let mut reader = BufReader::new(File::open(path)?);
+            let mut snapshot_index = BTreeMap::new();
+            let (_, len) = load(
+                gen,
+                &mut reader,
+                &mut snapshot_index,
+                &mut writer.last_version,
+            )?;
+            if len < reader.get_ref().metadata()?.len() {
+                return Err(KvsError::CorruptLog(format!(
+                    "snapshot {:?} is cut off",
+                    path
+                )));
+            }
+
+            let (mut log, pos) = open_log(&writer.path, gen, writer.last_version)?;
+            let mut bytes = Vec::new();
+            let mut index = BTreeMap::new();
+            for (key, old) in snapshot_index {
+                reader.seek(SeekFrom::Start(old.pos.pos))?;
+                let value = match read_record(&mut reader, old.pos.len)? {
+                    (Record::Command(Command::Set { value, .. }), _) => value,
+                    _ => {
+                        return Err(KvsError::CorruptLog(format!(
+                            "snapshot {:?} has no value at byte {}",
+                            path, old.pos.pos
+                        )))
+                    }
+                };
+                writer.last_version += 1;
+                let start = bytes.len() as u64;
+                encode_record(
+                    &Command::Set {
+                        key: key.clone(),
+                        value,
+                        version: writer.last_version,
+                        expires_at: old.expires_at,
+                    },
+                    &mut bytes,
+                )?;
+                let entry = IndexEntry {
+                    pos: CommandPos {
+                        gen,
+                        pos: pos + start,
+                        len: bytes.len() as u64 - start,
+                    },
+                    version: writer.last_version,
+                    expires_at: old.expires_at,
+                };
+                index.insert(key, entry);
+            }
+            log.write_all(&bytes)?;
+            log.flush()?;
+            writer.remove_others(gen, &index)?;
+            Ok(index)
+        })
+    }
+
+    /// Replaces the entries of the namespace whose keys in the log start with `prefix`
+    /// with `entries`, which get new versions.
+    fn replace_namespace(&mut self, prefix: &[u8], entries: Scan) -> Result<()> {
+        self.replace(|writer, gen| {
+            let (mut log, mut pos) = open_log(&writer.path, gen, writer.last_version)?;
+            let mut index = BTreeMap::new();
+            // Only this writer changes the index, so it stays the same while copying.
+            let now = now_millis();
+            for (key, &old) in writer.index.read().unwrap().iter() {
+                if key.starts_with(prefix) || !old.is_live(now) {
+                    continue;
+                }
+                let len = writer.reader.copy_command(old.pos, &mut log)?;
+                let entry = IndexEntry {
+                    pos: CommandPos { gen, pos, len },
+                    ..old
+                };
+                index.insert(key.clone(), entry);
+                pos += len;
+            }
+
+            let mut bytes = Vec::new();
+            for entry in entries {
+                let (key, value) = entry?;
+                let key = [prefix, &key].concat();
+                writer.last_version += 1;
+                bytes.clear();
+                encode_record(
+                    &Command::Set {
+                        key: key.clone(),
+                        value,
+                        version: writer.last_version,
+                        expires_at: None,
+                    },
+                    &mut bytes,
+                )?;
+                log.write_all(&bytes)?;
+                let len = bytes.len() as u64;
+                let entry = IndexEntry {
+                    pos: CommandPos { gen, pos, len },
+                    version: writer.last_version,
+                    expires_at: None,
+                };
+                index.insert(key, entry);
+                pos += len;
+            }
+            log.flush()?;
+            writer.remove_others(gen, &index)?;
+            Ok(index)
+        })
+    }
+
+    /// Removes all entries.
+    fn clear(&mut self) -> Result<()> {
+        self.replace(|writer, gen| {
+            let index = BTreeMap::new();
+            writer.remove_others(gen, &index)?;
+            Ok(index)
+        })
+    }
+
+    /// Like [`KvStoreWriter::rewrite`] for a new generation with other entries than before,
+    /// the watches get to see the difference.
+    fn replace(&mut self, write: impl FnOnce(&mut Self, u64) -> Result<Index>) -> Result<()> {
+        let now = now_millis();
+        let old_keys: Vec<_> = self
+            .index
+            .read()
+            .unwrap()
+            .iter()
+            .filter(|(key, entry)| entry.is_live(now) && self.watchers.is_watched(key))
+            .map(|(key, _)| key.clone())
+            .collect();
+        self.rewrite(write)?;
+
+        let index = self.index.read().unwrap();
+        let mut events = Vec::new();
+        for key in old_keys {
+            if !index.contains_key(&key) {
+                events.push(Event::Remove { key });
+            }
+        }
+        for (key, entry) in index.iter() {
+            if entry.is_live(now) && self.watchers.is_watched(key) {
+                events.push(Event::Set {
+                    key: key.clone(),
+                    value: self.reader.read_value(entry.pos)?,
+                    version: entry.version,
+                });
+            }
+        }
+        drop(index);
+        self.watchers.send(events);
+        Ok(())
+    }
+
+    /// The events of the commands for the keys which are watched.
+    fn events<'a>(&self, commands: impl IntoIterator<Item = &'a Command>) -> Vec<Event> {
+        commands
+            .into_iter()
+            .filter_map(|command| match command {
+                Command::Set {
+                    key,
+                    value,
+                    version,
+                    ..
+                } if self.watchers.is_watched(key) => Some(Event::Set {
+                    key: key.clone(),
+                    value: value.clone(),
+                    version: *version,
+                }),
+                Command::Remove { key } if self.watchers.is_watched(key) => {
+                    Some(Event::Remove { key: key.clone() })
+                }
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Appends removals of the keys which are not in `index` to the log of generation `gen`
+    /// and syncs it. Until the older generations are deleted they are replayed before it,
+    /// so it has to remove what they have and it doesn't.
+    fn remove_others(&self, gen: u64, index: &Index) -> Result<()> {
+        let mut bytes = Vec::new();
+        for key in self.index.read().unwrap().keys() {
+            if !index.contains_key(key) {
+                encode_record(&Command::Remove { key: key.clone() }, &mut bytes)?;
+            }
+        }
+        let (mut log, _) = open_log(&self.path, gen, self.last_version)?;
+        log.write_all(&bytes)?;
+        log.flush()?;
+        log.get_ref().sync_all()?;
+        Ok(())
+    }
+
+    /// Writes a new generation with `write`, which returns the index of it,
+    /// switches to that index and deletes the older generations.
+    /// The index is switched only after `write` succeeded, so a failure leaves the store as it was.
+    fn rewrite(&mut self, write: impl FnOnce(&mut Self, u64) -> Result<Index>) -> Result<()> {
+        let new_gen = self.writer_gen + 1;
+        let new_index = match write(self, new_gen) {
+            Ok(new_index) => new_index,
+            Err(e) => {
+                let _ = fs::remove_file(log_path(&self.path, new_gen));
+                return Err(e);
+            }
+        };
+
+        // Later writes go to the generation after the new one.
+        let writer_gen = new_gen + 1;
+        let (writer, writer_pos) = open_log(&self.path, writer_gen, self.last_version)?;
+
+        *self.index.write().unwrap() = new_index;
+        // The old writer has nothing left to sync that is still needed.
+        self.unsynced = false;
+        self.writer = writer;
+        self.writer_gen = writer_gen;
+        self.writer_pos = writer_pos;
+        self.uncompacted = 0;
+        self.track_expiring();
+
+        // Readers only look at the new index from now on and close their stale handles.
+        self.reader.safe_point.store(new_gen, Ordering::SeqCst);
+        // Oldest first, so a crash in between never leaves an entry without its later removal.
+        for gen in sorted_gen_list(&self.path)? {
+            if gen >= new_gen {
+                break;
+            }
+            fs::remove_file(log_path(&self.path, gen))?;
+        }
+        Ok(())
+    }
+
+    fn write_compaction(&mut self, gen: u64) -> Result<Index> {
+        let (mut writer, mut pos) = open_log(&self.path, gen, self.last_version)?;
+        let mut new_index = BTreeMap::new();
+        // Only this writer changes the index, so it stays the same while copying.
+        let now = now_millis();
+        for (key, &old) in self.index.read().unwrap().iter() {
+            if !old.is_live(now) {
+                continue;
+            }
+            let len = self.reader.copy_command(old.pos, &mut writer)?;
+            let entry = IndexEntry {
+                pos: CommandPos { gen, pos, len },
+                version: old.version,
+                expires_at: old.expires_at,
+            };
+            new_index.insert(key.clone(), entry);
+            pos += len;
+        }
+        writer.flush()?;
+        writer.get_ref().sync_all()?;
+        Ok(new_index)
+    }
+
+    /// Starts over keeping track of the entries in the index which expire.
+    fn track_expiring(&mut self) {
+        let index = self.index.read().unwrap();
+        self.expiring = index
+            .iter()
+            .filter_map(|(key, entry)| {
+                Some(Reverse(Expiring {
+                    expires_at: entry.expires_at?,
+                    gen: entry.pos.gen,
+                    pos: entry.pos.pos,
+                    key: key.clone(),
+                }))
+            })
+            .collect();
+    }
+
+    /// Keeps track of when the record of `command` at `pos` expires, if it is a set which does.
+    fn track_expiry(&mut self, command: &Command, pos: CommandPos) {
+        if let Command::Set {
+            key,
+            expires_at: Some(expires_at),
+            ..
+        } = command
+        {
+            self.expiring.push(Reverse(Expiring {
+                expires_at: *expires_at,
+                gen: pos.gen,
+                pos: pos.pos,
+                key: key.clone(),
+            }));
+        }
+    }
+
+    /// Drops the entries which expired from the index and counts their records as stale.
+    /// Entries which were overwritten or removed meanwhile were counted then.
+    fn drop_expired(&mut self) {
+        let now = now_millis();
+        let mut index = self.index.write().unwrap();
+        while let Some(Reverse(expiring)) = self.expiring.peek() {
+            if expiring.expires_at > now {
+                break;
+            }
+            let Reverse(expiring) = self.expiring.pop().unwrap();
+            if let Entry::Occupied(entry) = index.entry(expiring.key) {
+                let pos = entry.get().pos;
+                if (pos.gen, pos.pos) == (expiring.gen, expiring.pos) {
+                    self.uncompacted += pos.len;
+                    entry.remove();
+                }
+            }
+        }
+    }
+
+    fn maybe_compact(&mut self) -> Result<()> {
+        self.drop_expired();
+        if self.uncompacted > self.options.compaction_threshold {
+            self.compact()?;
+        }
+        Ok(())
+    }
+
+    fn append(&mut self, command: &Command) -> Result<CommandPos> {
+        Ok(self.append_all(slice::from_ref(command))?[0])
+    }
+
+    /// Writes the commands to the log with one write, returns where the record of each of them lies.
+    /// Syncs the log afterwards if the options say so.
+    fn append_all(&mut self, commands: &[Command]) -> Result<Vec<CommandPos>> {
+        let mut bytes = Vec::new();
+        let mut positions = Vec::with_capacity(commands.len());
+        for command in commands {
+            let start = bytes.len() as u64;
+            encode_record(command, &mut bytes)?;
+            positions.push(CommandPos {
+                gen: self.writer_gen,
+                pos: self.writer_pos + start,
+                len: bytes.len() as u64 - start,
+            });
+        }
+        self.write_records(&bytes)?;
+        Ok(positions)
+    }
+
+    /// Appends `records` to the log with one write and syncs it if the options say so.
+    fn write_records(&mut self, records: &[u8]) -> Result<()> {
+        if let Some(e) = self.sync_error.take() {
+            return Err(e.into());
+        }
+        self.writer.write_all(records)?;
+        self.writer.flush()?;
+        self.writer_pos += records.len() as u64;
+        self.unsynced = true;
+        if self.options.sync == SyncPolicy::Always {
+            self.sync()?;
+        }
+        Ok(())
+    }
+
+    /// Syncs the writes since the last sync to disk, if there were any.
+    fn sync(&mut self) -> io::Result<()> {
+        if self.unsynced {
+            self.writer.get_ref().sync_data()?;
+            self.unsynced = false;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for KvStoreWriter {
+    fn drop(&mut self) {
+        if self.options.sync != SyncPolicy::Never {
+            // There is nobody left to report an error to.
+            let _ = self.sync();
+        }
+    }
+}
+
+fn log_path(dir: &Path, gen: u64) -> PathBuf {
+    dir.join(format!("{}.log", gen))
+}
+
+/// Returns the generations of all logs in `dir`, oldest first.
+fn sorted_gen_list(dir: &Path) -> Result<Vec<u64>> {
+    let mut gen_list = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_file() && path.extension() == Some(OsStr::new("log")) {
+            if let Some(gen) = path
+                .file_stem()
+                .and_then(OsStr::to_str)
+                .and_then(|stem| stem.parse().ok())
+            {
+                gen_list.push(gen);
+            }
+        }
+    }
+    gen_list.sort_unstable();
+    Ok(gen_list)
+}
+
+/// Opens the log of generation `gen` for appending, writing the header with `last_version` if it is new.
+/// Returns the writer and the position it writes to.
+fn open_log(dir: &Path, gen: u64, last_version: u64) -> Result<(BufWriter<File>, u64)> {
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(log_path(dir, gen))?;
+    let mut pos = file.metadata()?.len();
+    if pos == 0 {
+        write_header(&mut file, last_version)?;
+        pos = LOG_HEADER_LEN;
+    }
+    Ok((BufWriter::new(file), pos))
+}
+
+/// Appends the record of `command` to `bytes`.
+fn encode_record(command: &Command, bytes: &mut Vec<u8>) -> Result<()> {
+    let payload = bincode::serialize(command)?;
+    let len = u32::try_from(payload.len()).map_err(|_| {
+        KvsError::InvalidInput(format!("a record of {} bytes is too long", payload.len()))
+    })?;
+    bytes.extend_from_slice(&len.to_le_bytes());
+    bytes.extend_from_slice(&crc32fast::hash(&len.to_le_bytes()).to_le_bytes());
+    bytes.extend_from_slice(&crc32fast::hash(&payload).to_le_bytes());
+    bytes.extend_from_slice(&payload);
+    Ok(())
+}
+
+fn write_header(writer: &mut impl Write, last_version: u64) -> io::Result<()> {
+    let mut header = LOG_MAGIC.to_vec();
+    header.extend_from_slice(&LOG_VERSION.to_le_bytes());
+    header.extend_from_slice(&last_version.to_le_bytes());
+    header.extend_from_slice(&crc32fast::hash(&header).to_le_bytes());
+    writer.write_all(&header)
+}
+
+/// Checks the header and replays the log of generation `gen` into `index`,
+/// raising `last_version` to the highest version in it.
+/// Returns how many bytes of it are stale and how long the log should be:
+/// a torn record at the end or a batch at the end which is missing commands doesn't count.
+fn load(
+    gen: u64,
+    reader: &mut BufReader<File>,
+    index: &mut Index,
+    last_version: &mut u64,
+) -> Result<(u64, u64)> {
+    let end = reader.get_ref().metadata()?.len();
+    *last_version = (*last_version).max(read_header(gen, reader)?);
+
+    let mut uncompacted = 0;
+    let mut pos = LOG_HEADER_LEN;
+    // The commands of the batch being read, replayed once the last one is there.
+    let mut batch = Vec::new();
+    let mut batch_missing = 0;
+    let mut batch_start = pos;
+    while pos < end {
+        let (command, len) = match read_record(&mut *reader, end - pos)? {
+            (Record::Command(command), len) => (command, len),
+            // The process died while writing the last record.
+            (Record::Torn, _) => break,
+            (Record::Damaged(_), len) if pos + len == end => break,
+            (Record::Damaged(reason), _) => {
+                return Err(KvsError::CorruptLog(format!(
+                    "{} in log {} at byte {}",
+                    reason, gen, pos
+                )))
+            }
+        };
+        let new_pos = pos + len;
+        let command_pos = CommandPos { gen, pos, len };
+        *last_version = (*last_version).max(command.version());
+        if let Command::Batch { len } = command {
+            if batch_missing > 0 {
+                return Err(KvsError::CorruptLog(format!(
+                    "batch in log {} at byte {} starts within another batch",
+                    gen, pos
+                )));
+            }
+            batch_missing = len + 1;
+            batch_start = pos;
+        }
+        if batch_missing > 0 {
+            batch.push((command, command_pos));
+            batch_missing -= 1;
+            if batch_missing == 0 {
+                for (command, command_pos) in batch.drain(..) {
+                    uncompacted += replay(index, command, command_pos);
+                }
+            }
+        } else {
+            uncompacted += replay(index, command, command_pos);
+        }
+        pos = new_pos;
+    }
+    let len = if batch_missing > 0 { batch_start } else { pos };
+    Ok((uncompacted, len))
+}
+
+/// Checks the header of the log of generation `gen` and leaves `reader` right after it.
+/// Returns the highest version given to a key when the log was created.
+fn read_header(gen: u64, reader: &mut BufReader<File>) -> Result<u64> {
+    reader.rewind()?;
+    let mut header = [0; LOG_HEADER_LEN as usize];
+    reader
+        .read_exact(&mut header)
+        .map_err(|_| KvsError::CorruptLog(format!("log {} is too short for its header", gen)))?;
+    if &header[..4] != LOG_MAGIC {
+        return Err(KvsError::CorruptLog(format!(
+            "log {} doesn't start with the magic bytes",
+            gen
+        )));
+    }
+    let version = u32::from_le_bytes([header[4], header[5], header[6], header[7]]);
+    if version != LOG_VERSION {
+        return Err(KvsError::UnsupportedVersion(version));
+    }
+    let checksum = u32::from_le_bytes(header[16..].try_into().unwrap());
+    if crc32fast::hash(&header[..16]) != checksum {
+        return Err(KvsError::CorruptLog(format!(
+            "header checksum mismatch in log {}",
+            gen
+        )));
+    }
+    Ok(u64::from_le_bytes(header[8..16].try_into().unwrap()))
+}
+
+/// What is found where a record should start.
+enum Record {
+    Command(Command),
+    /// A record whose checksum doesn't match or whose command can't be read, and why.
+    Damaged(String),
+    /// A record which would reach beyond the end of the log.
+    Torn,
+}
+
+/// Reads the record at the position of `reader`, with `remaining` bytes left in the log.
+/// Returns it along with its length, which is `remaining` for a torn record
+/// and only that of the header if the length is damaged.
+fn read_record(reader: &mut impl Read, remaining: u64) -> Result<(Record, u64)> {
+    if remaining < RECORD_HEADER_LEN {
+        return Ok((Record::Torn, remaining));
+    }
+    let mut header = [0; RECORD_HEADER_LEN as usize];
+    reader.read_exact(&mut header)?;
+    let len = u32::from_le_bytes(header[..4].try_into().unwrap());
+    let len_checksum = u32::from_le_bytes(header[4..8].try_into().unwrap());
+    let checksum = u32::from_le_bytes(header[8..].try_into().unwrap());
+    if crc32fast::hash(&header[..4]) != len_checksum {
+        // Without a length there is no telling where the record ends.
+        return Ok((
+            Record::Damaged("length checksum mismatch".to_owned()),
+            RECORD_HEADER_LEN,
+        ));
+    }
+    let record_len = RECORD_HEADER_LEN + u64::from(len);
+    if record_len > remaining {
+        return Ok((Record::Torn, remaining));
+    }
+    let mut payload = vec![0; len as usize];
+    reader.read_exact(&mut payload)?;
+    let record = if crc32fast::hash(&payload) != checksum {
+        Record::Damaged("checksum mismatch".to_owned())
+    } else {
+        match bincode::deserialize(&payload) {
+            Ok(command) => Record::Command(command),
+            Err(e) => Record::Damaged(format!("unreadable command: {}", e)),
+        }
+    };
+    Ok((record, record_len))
+}
+
+/// Updates `index` with the command at `pos`, returns how many bytes of the log became stale by it.
+fn replay(index: &mut Index, command: Command, pos: CommandPos) -> u64 {
+    match command {
+        Command::Set {
+            key,
+            version,
+            expires_at,
+            ..
+        } => index
+            .insert(
+                key,
+                IndexEntry {
+                    pos,
+                    version,
+                    expires_at,
+                },
+            )
+            .map_or(0, |old| old.pos.len),
+        // The removal itself is only needed as long as the removed entry is in the log.
+        // After an interrupted compaction there can be removals of keys which aren't there.
+        Command::Remove { key } => index.remove(&key).map_or(0, |old| old.pos.len) + pos.len,
+        // Compaction only copies the commands of the batch, which are complete by then.
+        Command::Batch { .. } => pos.len,
+    }
+}
+
+/// The entry of the key if it is there and not expired.
+fn live_entry<'a>(index: &'a Index, key: &[u8]) -> Option<&'a IndexEntry> {
+    index.get(key).filter(|entry| entry.is_live(now_millis()))
+}
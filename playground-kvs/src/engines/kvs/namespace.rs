@@ -0,0 +1,149 @@
+use super::{Index, KvStore};
+use crate::engines::{now_millis, prefix_range, KeyRange, WriteBatch};
+use crate::Result;
+use std::convert::TryInto;
+use std::ops::Bound;
+use std::sync::Arc;
+
+/// First byte of the keys of the default namespace in the log.
+const DEFAULT: u8 = 0;
+/// First byte of the keys of named namespaces in the log,
+/// followed by the length of the name as big endian u32 and the name.
+const NAMED: u8 = 1;
+
+/// How many keys a namespace has and how much of the log they take.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct NamespaceStats {
+    /// How many keys are there, without the expired ones.
+    pub keys: usize,
+    /// How many bytes of the log the records of these keys take.
+    pub bytes: u64,
+}
+
+impl KvStore {
+    /// Returns the store with the keyspace `name` instead of the one of this store.
+    /// Namespaces don't see each other's keys, but they share the log, its compaction,
+    /// its snapshots and the replication to followers.
+    /// The empty name is the default namespace, the one of [`KvStore::open`].
+    pub fn namespace(&self, name: &str) -> KvStore {
+        KvStore {
+            namespace: namespace_prefix(name).into(),
+            ..self.clone()
+        }
+    }
+
+    /// Returns the names of the namespaces other than the default one which have keys, in order.
+    pub fn namespaces(&self) -> Vec<String> {
+        let index = self.index.read().unwrap();
+        let now = now_millis();
+        let mut names: Vec<String> = Vec::new();
+        for (key, entry) in index.range(vec![NAMED]..) {
+            if !entry.is_live(now) {
+                continue;
+            }
+            if let Some((name, _)) = split_key(key) {
+                if names.last().map(String::as_str) != Some(name.as_str()) {
+                    names.push(name);
+                }
+            }
+        }
+        names
+    }
+
+    /// Removes all keys of the namespace `name` at once, like a [`WriteBatch`] does.
+    /// Returns how many there were.
+    pub fn drop_namespace(&self, name: &str) -> Result<usize> {
+        let prefix = namespace_prefix(name);
+        let mut writer = self.writer.lock().unwrap();
+        // Only this writer changes the index, so the keys stay the same until they are removed.
+        let keys = live_keys(&self.index.read().unwrap(), &prefix);
+        let mut batch = WriteBatch::new();
+        for key in &keys {
+            batch.remove(&key[prefix.len()..]);
+        }
+        writer.apply(&prefix, &batch)?;
+        Ok(keys.len())
+    }
+
+    /// Returns how many keys the namespace `name` has and how much of the log they take.
+    pub fn namespace_stats(&self, name: &str) -> NamespaceStats {
+        let prefix = namespace_prefix(name);
+        let index = self.index.read().unwrap();
+        let now = now_millis();
+        index
+            .range::<Vec<u8>, _>(prefix_range(&prefix))
+            .filter(|(_, entry)| entry.is_live(now))
+            .fold(NamespaceStats::default(), |stats, (_, entry)| {
+                NamespaceStats {
+                    keys: stats.keys + 1,
+                    bytes: stats.bytes + entry.pos.len,
+                }
+            })
+    }
+
+    /// The key in the log for `key` of the namespace of this store.
+    pub(super) fn log_key(&self, key: &[u8]) -> Vec<u8> {
+        [&self.namespace[..], key].concat()
+    }
+}
+
+/// What the keys in the log of the namespace `name` start with.
+pub(super) fn namespace_prefix(name: &str) -> Vec<u8> {
+    if name.is_empty() {
+        return vec![DEFAULT];
+    }
+    let mut prefix = vec![NAMED];
+    prefix.extend_from_slice(&(name.len() as u32).to_be_bytes());
+    prefix.extend_from_slice(name.as_bytes());
+    prefix
+}
+
+/// The range in the log of the keys in `range` of the namespace with `prefix`.
+pub(super) fn log_range(prefix: &[u8], range: &KeyRange) -> KeyRange {
+    let with_prefix = |key: &Vec<u8>| [prefix, key].concat();
+    let (all_start, all_end) = prefix_range(prefix);
+    let start = match &range.0 {
+        Bound::Included(key) => Bound::Included(with_prefix(key)),
+        Bound::Excluded(key) => Bound::Excluded(with_prefix(key)),
+        Bound::Unbounded => all_start,
+    };
+    let end = match &range.1 {
+        Bound::Included(key) => Bound::Included(with_prefix(key)),
+        Bound::Excluded(key) => Bound::Excluded(with_prefix(key)),
+        Bound::Unbounded => all_end,
+    };
+    (start, end)
+}
+
+/// The live keys in the log which start with `prefix`.
+fn live_keys(index: &Index, prefix: &[u8]) -> Vec<Vec<u8>> {
+    let now = now_millis();
+    index
+        .range::<Vec<u8>, _>(prefix_range(prefix))
+        .filter(|(_, entry)| entry.is_live(now))
+        .map(|(key, _)| key.clone())
+        .collect()
+}
+
+/// The name of the namespace and the key in it of a key in the log,
+/// `None` for the default namespace and keys which are no valid keys of a named namespace.
+fn split_key(key: &[u8]) -> Option<(String, &[u8])> {
+    let rest = key.strip_prefix(&[NAMED])?;
+    let len = u32::from_be_bytes(rest.get(..4)?.try_into().unwrap()) as usize;
+    let name = rest.get(4..4 + len)?;
+    Some((String::from_utf8_lossy(name).into_owned(), &rest[4 + len..]))
+}
+
+/// How a key in the log is shown to people, with its namespace unless it is the default one.
+pub(super) fn describe_key(key: &[u8]) -> String {
+    match (key.strip_prefix(&[DEFAULT]), split_key(key)) {
+        (Some(key), _) => String::from_utf8_lossy(key).into_owned(),
+        (None, Some((name, key))) => format!("{}:{}", name, String::from_utf8_lossy(key)),
+        (None, None) => String::from_utf8_lossy(key).into_owned(),
+    }
+}
+
+/// The namespace which [`KvStore::open`] gives.
+pub(super) fn default_namespace() -> Arc<[u8]> {
+    namespace_prefix("").into()
+}
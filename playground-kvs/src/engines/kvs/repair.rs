@@ -0,0 +1,289 @@
+use super::namespace::describe_key;
+use super::{
+    log_path, open_log, read_header, read_record, replay, sorted_gen_list, Command, CommandPos,
+    Index, KvStore, KvStoreReader, Record, LOG_HEADER_LEN, RECORD_HEADER_LEN,
+};
+use crate::engines::now_millis;
+use crate::{KvsError, Result};
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{BufReader, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::sync::atomic::AtomicU64;
+use std::sync::Arc;
+
+/// What [`KvStore::check`] or [`KvStore::repair`] found in the logs of a store.
+#[derive(Clone, Debug, Default)]
+pub struct CheckReport {
+    /// How many generations there are.
+    pub generations: usize,
+    /// How many records are intact.
+    pub records: u64,
+    /// How many keys are there when only intact records and complete batches count.
+    pub keys: usize,
+    /// Everything which is wrong, in the order of the logs.
+    pub problems: Vec<Problem>,
+}
+
+impl CheckReport {
+    /// Whether nothing is wrong.
+    pub fn is_ok(&self) -> bool {
+        self.problems.is_empty()
+    }
+
+    fn problem(&mut self, gen: u64, pos: u64, description: String) {
+        self.problems.push(Problem {
+            gen,
+            pos,
+            description,
+        });
+    }
+}
+
+/// Something wrong at a place in the log of a generation.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Problem {
+    /// The generation of the log.
+    pub gen: u64,
+    /// The byte in the log where it starts.
+    pub pos: u64,
+    /// What is wrong.
+    pub description: String,
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "log {} at byte {}: {}",
+            self.gen, self.pos, self.description
+        )
+    }
+}
+
+/// The index of the intact records of all logs and what was wrong with the others.
+struct Salvage {
+    gen_list: Vec<u64>,
+    index: Index,
+    /// The highest version in the headers and intact records.
+    last_version: u64,
+    report: CheckReport,
+}
+
+impl KvStore {
+    /// Reads every log in the directory `path` without changing anything and reports
+    /// damaged records, batches which are missing commands and versions which don't add up.
+    /// Unlike [`KvStore::open`] this goes on after the first problem.
+    pub fn check(path: impl AsRef<Path>) -> Result<CheckReport> {
+        Ok(salvage(path.as_ref())?.report)
+    }
+
+    /// Copies the live entries of all intact records into a new generation
+    /// and deletes the older generations, so the store can be opened again.
+    /// Records after a damaged header are intact all the same, the new generation gets a new header.
+    /// Damaged records and all changes of a batch which is missing commands are lost.
+    /// Returns what was wrong before, like [`KvStore::check`].
+    pub fn repair(path: impl AsRef<Path>) -> Result<CheckReport> {
+        let path = path.as_ref();
+        let Salvage {
+            gen_list,
+            index,
+            last_version,
+            report,
+        } = salvage(path)?;
+        let gen = match gen_list.last() {
+            Some(last) => last + 1,
+            None => return Ok(report),
+        };
+        if let Err(e) = write_salvage(path, gen, &index, last_version) {
+            let _ = fs::remove_file(log_path(path, gen));
+            return Err(e);
+        }
+        for gen in gen_list {
+            fs::remove_file(log_path(path, gen))?;
+        }
+        Ok(report)
+    }
+}
+
+fn salvage(dir: &Path) -> Result<Salvage> {
+    let gen_list = sorted_gen_list(dir)?;
+    let mut index = BTreeMap::new();
+    let mut last_version = 0;
+    let mut report = CheckReport {
+        generations: gen_list.len(),
+        ..CheckReport::default()
+    };
+    for &gen in &gen_list {
+        let mut reader = BufReader::new(File::open(log_path(dir, gen))?);
+        salvage_log(gen, &mut reader, &mut index, &mut last_version, &mut report)?;
+    }
+    let now = now_millis();
+    report.keys = index.values().filter(|entry| entry.is_live(now)).count();
+    Ok(Salvage {
+        gen_list,
+        index,
+        last_version,
+        report,
+    })
+}
+
+/// Replays the intact records of the log of generation `gen` into `index`,
+/// skipping over damaged ones to the next intact record.
+/// Raises `last_version` to the highest version in it.
+fn salvage_log(
+    gen: u64,
+    reader: &mut BufReader<File>,
+    index: &mut Index,
+    last_version: &mut u64,
+    report: &mut CheckReport,
+) -> Result<()> {
+    let end = reader.get_ref().metadata()?.len();
+    match read_header(gen, reader) {
+        Ok(header_version) => *last_version = (*last_version).max(header_version),
+        // The records of a log of another version can't be read.
+        Err(e @ KvsError::UnsupportedVersion(_)) if header_intact(reader)? => {
+            report.problem(gen, 0, e.to_string());
+            return Ok(());
+        }
+        // The records have checksums of their own, so they are still good after a damaged header.
+        Err(e) => report.problem(gen, 0, e.to_string()),
+    }
+
+    let mut pos = LOG_HEADER_LEN;
+    // The commands of the batch being read, replayed once the last one is there.
+    let mut batch = Vec::new();
+    let mut batch_missing = 0;
+    let mut batch_start = pos;
+    while pos < end {
+        reader.seek(SeekFrom::Start(pos))?;
+        let (command, len) = match read_record(reader, end - pos)? {
+            (Record::Command(command), len) => (command, len),
+            (record, _) => {
+                let next = next_intact_record(reader, pos + 1, end)?;
+                let description = match (record, next) {
+                    (Record::Damaged(reason), Some(next)) => {
+                        format!("{}, {} bytes skipped", reason, next - pos)
+                    }
+                    (_, Some(next)) => format!("damaged record, {} bytes skipped", next - pos),
+                    (_, None) => format!("torn write of {} bytes at the end", end - pos),
+                };
+                report.problem(gen, pos, description);
+                if batch_missing > 0 {
+                    report.problem(
+                        gen,
+                        batch_start,
+                        "batch lost commands to the damage after it and is dropped".to_owned(),
+                    );
+                    batch.clear();
+                    batch_missing = 0;
+                }
+                pos = next.unwrap_or(end);
+                continue;
+            }
+        };
+        report.records += 1;
+        *last_version = (*last_version).max(command.version());
+        let command_pos = CommandPos { gen, pos, len };
+        if let Command::Batch { len } = command {
+            if batch_missing > 0 {
+                report.problem(
+                    gen,
+                    batch_start,
+                    "batch is cut short by another batch and is dropped".to_owned(),
+                );
+                batch.clear();
+            }
+            batch_missing = len + 1;
+            batch_start = pos;
+        }
+        if batch_missing > 0 {
+            batch.push((command, command_pos));
+            batch_missing -= 1;
+            if batch_missing == 0 {
+                for (command, command_pos) in batch.drain(..) {
+                    replay_checked(index, command, command_pos, report);
+                }
+            }
+        } else {
+            replay_checked(index, command, command_pos, report);
+        }
+        pos += len;
+    }
+    if batch_missing > 0 {
+        report.problem(
+            gen,
+            batch_start,
+            format!(
+                "batch at the end is missing {} commands and is dropped",
+                batch_missing
+            ),
+        );
+    }
+    Ok(())
+}
+
+/// Like [`replay`], but reports sets whose versions don't follow from the records before.
+fn replay_checked(index: &mut Index, command: Command, pos: CommandPos, report: &mut CheckReport) {
+    if let Command::Set { key, version, .. } = &command {
+        let old = index.get(key);
+        let key = describe_key(key);
+        match old {
+            _ if *version == 0 => {
+                report.problem(pos.gen, pos.pos, format!("{} is set with version 0", key))
+            }
+            // A compaction copies entries with their versions, so the same one can come again.
+            Some(old) if *version < old.version => report.problem(
+                pos.gen,
+                pos.pos,
+                format!(
+                    "version of {} goes back from {} to {}",
+                    key, old.version, version
+                ),
+            ),
+            _ => {}
+        }
+    }
+    replay(index, command, pos);
+}
+
+/// Whether the header of the log matches its checksum, whatever it says.
+fn header_intact(reader: &mut BufReader<File>) -> Result<bool> {
+    let mut header = [0; LOG_HEADER_LEN as usize];
+    reader.rewind()?;
+    if reader.read_exact(&mut header).is_err() {
+        return Ok(false);
+    }
+    Ok(header[16..] == crc32fast::hash(&header[..16]).to_le_bytes())
+}
+
+/// Returns the first position from `pos` on where an intact record starts.
+fn next_intact_record(reader: &mut BufReader<File>, mut pos: u64, end: u64) -> Result<Option<u64>> {
+    while pos + RECORD_HEADER_LEN <= end {
+        reader.seek(SeekFrom::Start(pos))?;
+        if let (Record::Command(_), _) = read_record(reader, end - pos)? {
+            return Ok(Some(pos));
+        }
+        pos += 1;
+    }
+    Ok(None)
+}
+
+/// Copies the records of the live entries in `index` into a new log of generation `gen` and syncs it.
+fn write_salvage(dir: &Path, gen: u64, index: &Index, last_version: u64) -> Result<()> {
+    let reader = KvStoreReader {
+        path: Arc::new(dir.to_owned()),
+        safe_point: Arc::new(AtomicU64::new(0)),
+        readers: RefCell::new(BTreeMap::new()),
+    };
+    let (mut writer, _) = open_log(dir, gen, last_version)?;
+    let now = now_millis();
+    for entry in index.values().filter(|entry| entry.is_live(now)) {
+        reader.copy_command(entry.pos, &mut writer)?;
+    }
+    writer.flush()?;
+    writer.get_ref().sync_all()?;
+    Ok(())
+}
@@ -0,0 +1,190 @@
+use super::{log_path, namespace, write_header, Index, KvStore, KvStoreReader};
+use crate::engines::{now_millis, prefix_range, Scan};
+use crate::{KvsError, Result};
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet};
+use std::ffi::OsStr;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Write};
+use std::ops::RangeBounds;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::AtomicU64;
+use std::sync::{Arc, Mutex};
+
+/// Name of the directory in a store directory with the snapshots saved by name.
+const SNAPSHOT_DIR_NAME: &str = "snapshots";
+
+/// A read-only view of a [`KvStore`] as it was when [`KvStore::snapshot`] was called.
+/// Later writes and compactions don't change what it sees,
+/// and keys which expire later are still there.
+///
+/// It sees the keys of the namespace of the store it was taken of.
+/// It keeps the logs it reads from open, so a compaction only frees their space once it is dropped.
+/// Clones share the same file handles.
+#[derive(Clone)]
+pub struct Snapshot {
+    /// What the keys of its namespace start with in the log.
+    namespace: Arc<[u8]>,
+    index: Arc<Index>,
+    /// Milliseconds since the Unix epoch when the snapshot was taken, expiry is judged by it.
+    taken_at: u64,
+    reader: Arc<Mutex<KvStoreReader>>,
+}
+
+impl KvStore {
+    /// Returns a view of the store as it is now, which stays the same while writes go on.
+    pub fn snapshot(&self) -> Result<Snapshot> {
+        // Holding the lock keeps a compaction from deleting a generation before it is opened.
+        let index = self.index.read().unwrap();
+        let mut readers = BTreeMap::new();
+        let gens: BTreeSet<_> = index.values().map(|entry| entry.pos.gen).collect();
+        for gen in gens {
+            let file = File::open(log_path(&self.reader.path, gen))?;
+            readers.insert(gen, BufReader::new(file));
+        }
+        let reader = KvStoreReader {
+            path: Arc::clone(&self.reader.path),
+            // The handles are never closed for being stale.
+            safe_point: Arc::new(AtomicU64::new(0)),
+            readers: RefCell::new(readers),
+        };
+        Ok(Snapshot {
+            namespace: Arc::clone(&self.namespace),
+            index: Arc::new(index.clone()),
+            taken_at: now_millis(),
+            reader: Arc::new(Mutex::new(reader)),
+        })
+    }
+
+    /// Saves a snapshot of the store as it is now under `name`, in the directory `snapshots`
+    /// of the store, with the keys of all namespaces. The name may contain letters, digits, `-`, `_` and `.`.
+    pub fn create_snapshot(&self, name: &str) -> Result<()> {
+        let path = snapshot_path(&self.reader.path, name)?;
+        if path.exists() {
+            return Err(KvsError::InvalidInput(format!(
+                "there is a snapshot named {} already",
+                name
+            )));
+        }
+        fs::create_dir_all(path.parent().unwrap())?;
+        // Only complete snapshots get their name.
+        let temp_path = path.with_extension("tmp");
+        if let Err(e) = self.snapshot()?.save(&temp_path) {
+            let _ = fs::remove_file(&temp_path);
+            return Err(e);
+        }
+        fs::rename(temp_path, path)?;
+        Ok(())
+    }
+
+    /// Returns the names of the saved snapshots in order.
+    pub fn snapshot_names(&self) -> Result<Vec<String>> {
+        let dir = self.reader.path.join(SNAPSHOT_DIR_NAME);
+        let entries = match fs::read_dir(dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e.into()),
+        };
+        let mut names = Vec::new();
+        for entry in entries {
+            let path = entry?.path();
+            if path.extension() == Some(OsStr::new("log")) {
+                if let Some(name) = path.file_stem().and_then(OsStr::to_str) {
+                    names.push(name.to_owned());
+                }
+            }
+        }
+        names.sort_unstable();
+        Ok(names)
+    }
+
+    /// Replaces all entries of the store with those of the snapshot saved under `name`,
+    /// in all namespaces. The snapshot stays saved.
+    /// The restored keys get new versions, higher than all versions before.
+    pub fn restore_snapshot(&self, name: &str) -> Result<()> {
+        let path = snapshot_path(&self.reader.path, name)?;
+        if !path.is_file() {
+            return Err(KvsError::InvalidInput(format!(
+                "there is no snapshot named {}",
+                name
+            )));
+        }
+        self.writer.lock().unwrap().restore(&path)
+    }
+}
+
+impl Snapshot {
+    /// Returns the value of the key when the snapshot was taken.
+    pub fn get(&self, key: impl AsRef<[u8]>) -> Result<Option<Vec<u8>>> {
+        Ok(self.get_versioned(key)?.map(|(value, _)| value))
+    }
+
+    /// Like [`Snapshot::get`], also returns the version of the key.
+    pub fn get_versioned(&self, key: impl AsRef<[u8]>) -> Result<Option<(Vec<u8>, u64)>> {
+        let key = [&self.namespace[..], key.as_ref()].concat();
+        match self.index.get(&key) {
+            Some(entry) if entry.is_live(self.taken_at) => {
+                let value = self.reader.lock().unwrap().read_value(entry.pos)?;
+                Ok(Some((value, entry.version)))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// Returns the entries with keys in `range` when the snapshot was taken, ordered by key.
+    pub fn scan(&self, range: impl RangeBounds<Vec<u8>>) -> Scan {
+        let snapshot = self.clone();
+        let range = (range.start_bound().cloned(), range.end_bound().cloned());
+        Scan::new(range, move |range, limit| {
+            let reader = snapshot.reader.lock().unwrap();
+            let prefix_len = snapshot.namespace.len();
+            snapshot
+                .index
+                .range::<Vec<u8>, _>(namespace::log_range(&snapshot.namespace, range))
+                .filter(|(_, entry)| entry.is_live(snapshot.taken_at))
+                .take(limit)
+                .map(|(key, entry)| {
+                    let value = reader.read_value(entry.pos)?;
+                    Ok((key[prefix_len..].to_vec(), value))
+                })
+                .collect()
+        })
+    }
+
+    /// Returns the entries with keys which start with `prefix`, ordered by key.
+    pub fn scan_prefix(&self, prefix: impl AsRef<[u8]>) -> Scan {
+        self.scan(prefix_range(prefix))
+    }
+
+    /// Writes the live entries to a new log at `path` and syncs it.
+    fn save(&self, path: &Path) -> Result<()> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        let last_version = self.index.values().map(|entry| entry.version).max();
+        write_header(&mut writer, last_version.unwrap_or(0))?;
+        let reader = self.reader.lock().unwrap();
+        for entry in self.index.values() {
+            if entry.is_live(self.taken_at) {
+                reader.copy_command(entry.pos, &mut writer)?;
+            }
+        }
+        writer.flush()?;
+        writer.get_ref().sync_all()?;
+        Ok(())
+    }
+}
+
+/// Where the snapshot `name` of the store in `dir` is saved.
+fn snapshot_path(dir: &Path, name: &str) -> Result<PathBuf> {
+    let valid = !name.is_empty()
+        && !name.starts_with('.')
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'));
+    if !valid {
+        return Err(KvsError::InvalidInput(format!(
+            "{:?} is no snapshot name, use letters, digits, -, _ and .",
+            name
+        )));
+    }
+    Ok(dir.join(SNAPSHOT_DIR_NAME).join(format!("{}.log", name)))
+}
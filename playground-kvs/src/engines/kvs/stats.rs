@@ -0,0 +1,111 @@
+use super::{log_path, sorted_gen_list, IndexEntry, KvStore, LOG_HEADER_LEN};
+use crate::engines::now_millis;
+use crate::{KvsError, Result};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::mem;
+use std::path::Path;
+
+/// Name of the file in the store directory with the time of the last compaction.
+const COMPACTED_AT_FILE_NAME: &str = "compacted-at";
+
+/// How big a [`KvStore`] is and how much of its log is stale, as [`KvStore::stats`] returns it.
+#[derive(Serialize, Clone, Debug, PartialEq, Eq)]
+pub struct StoreStats {
+    /// How many keys are there in all namespaces, without the expired ones.
+    pub keys: usize,
+    /// The generations of the log, oldest first.
+    pub generations: Vec<GenerationStats>,
+    /// Milliseconds since the Unix epoch when the store was last compacted, if ever.
+    pub last_compaction: Option<u64>,
+    /// About how many bytes of memory the index takes: the keys and what is kept of them,
+    /// without what the map itself needs besides.
+    pub index_bytes: u64,
+}
+
+/// How much of the log of a generation is needed and how much is stale.
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct GenerationStats {
+    /// The generation of the log.
+    pub gen: u64,
+    /// Bytes of the records of the keys which are there.
+    pub live_bytes: u64,
+    /// Bytes of the records which aren't needed anymore, which a compaction frees.
+    pub stale_bytes: u64,
+}
+
+impl StoreStats {
+    /// Bytes of the records of the keys which are there, in all generations.
+    pub fn live_bytes(&self) -> u64 {
+        self.generations.iter().map(|gen| gen.live_bytes).sum()
+    }
+
+    /// Bytes of the records which aren't needed anymore, in all generations.
+    pub fn stale_bytes(&self) -> u64 {
+        self.generations.iter().map(|gen| gen.stale_bytes).sum()
+    }
+}
+
+impl KvStore {
+    /// Returns how big the store is and how much of its log is stale.
+    pub fn stats(&self) -> Result<StoreStats> {
+        // Holding the writer keeps the logs from changing meanwhile.
+        let writer = self.writer.lock().unwrap();
+        let index = self.index.read().unwrap();
+        let now = now_millis();
+        let mut keys = 0;
+        let mut live_bytes = BTreeMap::new();
+        for entry in index.values().filter(|entry| entry.is_live(now)) {
+            keys += 1;
+            *live_bytes.entry(entry.pos.gen).or_insert(0) += entry.pos.len;
+        }
+
+        let mut generations = Vec::new();
+        for gen in sorted_gen_list(&writer.path)? {
+            let len = fs::metadata(log_path(&writer.path, gen))?.len();
+            let live_bytes = live_bytes.get(&gen).copied().unwrap_or(0);
+            generations.push(GenerationStats {
+                gen,
+                live_bytes,
+                stale_bytes: len.saturating_sub(LOG_HEADER_LEN + live_bytes),
+            });
+        }
+
+        let index_bytes = index
+            .keys()
+            .map(|key| key.capacity() + mem::size_of::<Vec<u8>>() + mem::size_of::<IndexEntry>())
+            .sum::<usize>() as u64;
+        Ok(StoreStats {
+            keys,
+            generations,
+            last_compaction: load_compaction_time(&writer.path)?,
+            index_bytes,
+        })
+    }
+}
+
+/// When the store in `dir` was last compacted, if ever.
+fn load_compaction_time(dir: &Path) -> Result<Option<u64>> {
+    let saved = match fs::read_to_string(dir.join(COMPACTED_AT_FILE_NAME)) {
+        Ok(saved) => saved,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    saved.trim().parse().map(Some).map_err(|_| {
+        KvsError::CorruptLog(format!(
+            "{} is no time of the last compaction",
+            saved.trim()
+        ))
+    })
+}
+
+/// Saves that the store in `dir` was compacted just now.
+pub(super) fn save_compaction_time(dir: &Path) -> Result<()> {
+    let path = dir.join(COMPACTED_AT_FILE_NAME);
+    let temp_path = path.with_extension("tmp");
+    fs::write(&temp_path, now_millis().to_string())?;
+    fs::rename(temp_path, path)?;
+    Ok(())
+}
@@ -0,0 +1,259 @@
+use super::{
+    log_path, read_record, replay, sorted_gen_list, Command, CommandPos, KvStore, Record,
+    LOG_HEADER_LEN,
+};
+use crate::{KvsError, Result};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, BufReader, Read, Seek, SeekFrom};
+use std::path::PathBuf;
+use std::sync::Arc;
+
+/// Name of the file in the store directory of a follower
+/// with the offset in the log of the primary up to which it is applied.
+const PRIMARY_OFFSET_FILE_NAME: &str = "primary-offset";
+
+/// A position in the log of a [`KvStore`]: the generation and the byte in its log.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct LogOffset {
+    /// The generation of the log.
+    pub gen: u64,
+    /// The byte in the log.
+    pub pos: u64,
+}
+
+impl fmt::Display for LogOffset {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.gen, self.pos)
+    }
+}
+
+/// Reads the records which are appended to the log of a store, generation after generation.
+/// A generation written by a compaction only has copies of entries from before,
+/// so reading on through it gets to the same entries as reading the removed generations.
+pub(crate) struct LogTail {
+    path: Arc<PathBuf>,
+    offset: LogOffset,
+    reader: BufReader<File>,
+}
+
+impl KvStore {
+    /// Returns a tail of the log from `from` on and whether it had to start over at the beginning
+    /// of the log instead, because there is no such offset (anymore).
+    pub(crate) fn tail(&self, from: Option<LogOffset>) -> Result<(LogTail, bool)> {
+        let path = Arc::clone(&self.reader.path);
+        if let Some(from) = from {
+            match File::open(log_path(&path, from.gen)) {
+                Ok(file) if from.pos >= LOG_HEADER_LEN && from.pos <= file.metadata()?.len() => {
+                    let tail = LogTail {
+                        path,
+                        offset: from,
+                        reader: BufReader::new(file),
+                    };
+                    return Ok((tail, false));
+                }
+                Ok(_) => {}
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+        // A compaction can delete the oldest generation before it is opened.
+        loop {
+            let gen = sorted_gen_list(&path)?.first().copied().unwrap_or(1);
+            match File::open(log_path(&path, gen)) {
+                Ok(file) => {
+                    let tail = LogTail {
+                        path,
+                        offset: LogOffset {
+                            gen,
+                            pos: LOG_HEADER_LEN,
+                        },
+                        reader: BufReader::new(file),
+                    };
+                    return Ok((tail, true));
+                }
+                Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+
+    /// Appends records read from the [`LogTail`] of another store to the log and replays them.
+    pub(crate) fn append_records(&self, records: &[u8]) -> Result<()> {
+        let mut commands = Vec::new();
+        let mut reader = records;
+        let mut start = 0;
+        while start < records.len() as u64 {
+            match read_record(&mut reader, records.len() as u64 - start)? {
+                (Record::Command(command), len) => {
+                    commands.push((command, start, len));
+                    start += len;
+                }
+                _ => {
+                    return Err(KvsError::CorruptLog(
+                        "replicated records are damaged".to_owned(),
+                    ))
+                }
+            }
+        }
+
+        let mut writer = self.writer.lock().unwrap();
+        let (gen, pos) = (writer.writer_gen, writer.writer_pos);
+        writer.write_records(records)?;
+        let events = writer.events(commands.iter().map(|(command, _, _)| command));
+        let mut index = self.index.write().unwrap();
+        for (command, start, len) in commands {
+            let command_pos = CommandPos {
+                gen,
+                pos: pos + start,
+                len,
+            };
+            writer.last_version = writer.last_version.max(command.version());
+            writer.track_expiry(&command, command_pos);
+            writer.uncompacted += replay(&mut index, command, command_pos);
+        }
+        drop(index);
+        writer.watchers.send(events);
+        writer.maybe_compact()
+    }
+
+    /// Removes all entries.
+    pub(crate) fn clear(&self) -> Result<()> {
+        self.writer.lock().unwrap().clear()
+    }
+
+    /// Up to where the log of a primary is applied to this store, if at all.
+    pub(crate) fn replicated_offset(&self) -> Result<Option<LogOffset>> {
+        let path = self.reader.path.join(PRIMARY_OFFSET_FILE_NAME);
+        let saved = match fs::read_to_string(path) {
+            Ok(saved) => saved,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        match saved
+            .trim()
+            .split_once(':')
+            .map(|(gen, pos)| (gen.parse(), pos.parse()))
+        {
+            Some((Ok(gen), Ok(pos))) => Ok(Some(LogOffset { gen, pos })),
+            _ => Err(KvsError::CorruptLog(format!(
+                "{} is no offset in the log of the primary",
+                saved.trim()
+            ))),
+        }
+    }
+
+    /// Saves up to where the log of a primary is applied to this store.
+    pub(crate) fn set_replicated_offset(&self, offset: LogOffset) -> Result<()> {
+        let path = self.reader.path.join(PRIMARY_OFFSET_FILE_NAME);
+        let temp_path = path.with_extension("tmp");
+        fs::write(&temp_path, offset.to_string())?;
+        fs::rename(temp_path, path)?;
+        Ok(())
+    }
+
+    /// Where the next write goes in the log.
+    pub(crate) fn end_offset(&self) -> LogOffset {
+        let writer = self.writer.lock().unwrap();
+        LogOffset {
+            gen: writer.writer_gen,
+            pos: writer.writer_pos,
+        }
+    }
+}
+
+impl LogTail {
+    /// Where the records read next start.
+    pub(crate) fn offset(&self) -> LogOffset {
+        self.offset
+    }
+
+    /// Returns the complete records from the offset on, about `max` bytes of them, and moves past them.
+    /// A batch comes as a whole or not at all. Returns no records if there are none yet.
+    pub(crate) fn read(&mut self, max: u64) -> Result<Vec<u8>> {
+        loop {
+            // Once there is a later generation nothing gets appended to this one anymore.
+            let next_gen = sorted_gen_list(&self.path)?
+                .into_iter()
+                .find(|&gen| gen > self.offset.gen);
+            let end = self.reader.get_ref().metadata()?.len();
+            let len = self.complete_len(end, max)?;
+            if len > 0 {
+                let mut records = vec![0; len as usize];
+                self.reader.seek(SeekFrom::Start(self.offset.pos))?;
+                self.reader.read_exact(&mut records)?;
+                self.offset.pos += len;
+                return Ok(records);
+            }
+            match next_gen {
+                Some(gen) if self.offset.pos == end => {
+                    match File::open(log_path(&self.path, gen)) {
+                        Ok(file) => {
+                            self.reader = BufReader::new(file);
+                            self.offset = LogOffset {
+                                gen,
+                                pos: LOG_HEADER_LEN,
+                            };
+                        }
+                        // It was compacted away meanwhile, so was everything before it.
+                        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                            return Err(KvsError::CorruptLog(format!(
+                                "log {} is gone before it was read",
+                                gen
+                            )))
+                        }
+                        Err(e) => return Err(e.into()),
+                    }
+                }
+                _ => return Ok(Vec::new()),
+            }
+        }
+    }
+
+    /// How many bytes of the log are after the offset.
+    pub(crate) fn pending(&self) -> Result<u64> {
+        let mut pending = self.reader.get_ref().metadata()?.len() - self.offset.pos;
+        for gen in sorted_gen_list(&self.path)? {
+            if gen > self.offset.gen {
+                match File::open(log_path(&self.path, gen)) {
+                    Ok(file) => pending += file.metadata()?.len() - LOG_HEADER_LEN,
+                    Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                    Err(e) => return Err(e.into()),
+                }
+            }
+        }
+        Ok(pending)
+    }
+
+    /// How many bytes from the offset on are complete records and batches, stopping after `max`.
+    fn complete_len(&mut self, end: u64, max: u64) -> Result<u64> {
+        self.reader.seek(SeekFrom::Start(self.offset.pos))?;
+        let mut pos = self.offset.pos;
+        let mut complete = pos;
+        let mut batch_missing = 0;
+        while pos < end && complete - self.offset.pos < max {
+            match read_record(&mut self.reader, end - pos)? {
+                (Record::Command(command), len) => {
+                    pos += len;
+                    batch_missing = match command {
+                        Command::Batch { len } => len,
+                        _ => batch_missing.saturating_sub(1),
+                    };
+                    if batch_missing == 0 {
+                        complete = pos;
+                    }
+                }
+                // It is still being written.
+                (Record::Torn, _) => break,
+                (Record::Damaged(reason), _) => {
+                    return Err(KvsError::CorruptLog(format!(
+                        "{} in log {} at byte {}",
+                        reason, self.offset.gen, pos
+                    )))
+                }
+            }
+        }
+        Ok(complete - self.offset.pos)
+    }
+}
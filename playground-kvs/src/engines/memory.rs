@@ -0,0 +1,224 @@
+use super::batch::BatchOp;
+use super::{
+    expiry, now_millis, Event, Expected, KeyCounts, KvsEngine, Scan, Watch, Watchers, WriteBatch,
+};
+use crate::{KvsError, Result};
+use std::collections::BTreeMap;
+use std::ops::RangeBounds;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+/// A key-value store which only lives in memory, everything is gone when the last clone is dropped.
+/// Keys and values will be copied.
+/// Expired keys are hidden and only dropped when they are written to or removed.
+#[derive(Clone)]
+pub struct MemStore {
+    store: Arc<RwLock<BTreeMap<Vec<u8>, Entry>>>,
+    /// The highest version given to a key so far, only changed while holding the lock of `store`.
+    last_version: Arc<AtomicU64>,
+    watchers: Watchers,
+}
+
+#[derive(Clone)]
+struct Entry {
+    value: Vec<u8>,
+    version: u64,
+    /// Milliseconds since the Unix epoch.
+    expires_at: Option<u64>,
+}
+
+impl Entry {
+    fn is_live(&self, now: u64) -> bool {
+        self.expires_at.is_none_or(|expires_at| now < expires_at)
+    }
+}
+
+impl MemStore {
+    /// Returns a new empty store.
+    /// Getting a value by a key will return None.
+    pub fn new() -> Self {
+        Self {
+            store: Arc::new(RwLock::new(BTreeMap::new())),
+            last_version: Arc::new(AtomicU64::new(0)),
+            watchers: Watchers::default(),
+        }
+    }
+}
+
+impl Default for MemStore {
+    fn default() -> Self {
+        MemStore::new()
+    }
+}
+
+impl KvsEngine for MemStore {
+    fn set(&self, key: impl AsRef<[u8]>, value: impl AsRef<[u8]>) -> Result<u64> {
+        let mut store = self.store.write().unwrap();
+        Ok(self.set_entry(&mut store, key.as_ref(), value.as_ref(), None))
+    }
+
+    fn set_with_ttl(
+        &self,
+        key: impl AsRef<[u8]>,
+        value: impl AsRef<[u8]>,
+        ttl: Duration,
+    ) -> Result<u64> {
+        let mut store = self.store.write().unwrap();
+        let expires_at = expiry(Some(ttl));
+        Ok(self.set_entry(&mut store, key.as_ref(), value.as_ref(), expires_at))
+    }
+
+    fn get_versioned(&self, key: impl AsRef<[u8]>) -> Result<Option<(Vec<u8>, u64)>> {
+        let store = self.store.read().unwrap();
+        Ok(live_entry(&store, key.as_ref()).map(|entry| (entry.value.clone(), entry.version)))
+    }
+
+    fn remove(&self, key: impl AsRef<[u8]>) -> Result<()> {
+        let mut store = self.store.write().unwrap();
+        let live = live_entry(&store, key.as_ref()).is_some();
+        match store.remove(key.as_ref()) {
+            Some(_) if live => {
+                self.watchers.send(self.removal(key.as_ref()));
+                Ok(())
+            }
+            _ => Err(KvsError::KeyNotFound),
+        }
+    }
+
+    fn write_if(
+        &self,
+        key: impl AsRef<[u8]>,
+        expected: Expected,
+        new: Option<&[u8]>,
+        ttl: Option<Duration>,
+    ) -> Result<u64> {
+        let key = key.as_ref();
+        let mut store = self.store.write().unwrap();
+        let entry = live_entry(&store, key);
+        let entry_was_live = entry.is_some();
+        if !expected.matches(entry.map(|entry| (entry.value.as_slice(), entry.version))) {
+            return Err(KvsError::Conflict(entry.map_or(0, |entry| entry.version)));
+        }
+        match new {
+            Some(value) => Ok(self.set_entry(&mut store, key, value, expiry(ttl))),
+            None => {
+                store.remove(key);
+                if entry_was_live {
+                    self.watchers.send(self.removal(key));
+                }
+                Ok(0)
+            }
+        }
+    }
+
+    fn apply(&self, batch: &WriteBatch) -> Result<()> {
+        let mut store = self.store.write().unwrap();
+        batch.check_removals(|key| live_entry(&store, key).is_some())?;
+        for op in batch.ops() {
+            match op {
+                BatchOp::Set { key, value } => {
+                    self.set_entry(&mut store, key, value, None);
+                }
+                BatchOp::Remove { key } => {
+                    if store.remove(key).is_some() {
+                        self.watchers.send(self.removal(key));
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn scan(&self, range: impl RangeBounds<Vec<u8>>) -> Scan {
+        let store = self.clone();
+        let range = (range.start_bound().cloned(), range.end_bound().cloned());
+        Scan::new(range, move |range, limit| {
+            let now = now_millis();
+            Ok(store
+                .store
+                .read()
+                .unwrap()
+                .range::<Vec<u8>, _>(range.clone())
+                .filter(|(_, entry)| entry.is_live(now))
+                .take(limit)
+                .map(|(key, entry)| (key.clone(), entry.value.clone()))
+                .collect())
+        })
+    }
+
+    fn scan_keys(&self, range: impl RangeBounds<Vec<u8>>) -> Scan<Vec<u8>> {
+        let store = self.clone();
+        let range = (range.start_bound().cloned(), range.end_bound().cloned());
+        Scan::new(range, move |range, limit| {
+            let now = now_millis();
+            Ok(store
+                .store
+                .read()
+                .unwrap()
+                .range::<Vec<u8>, _>(range.clone())
+                .filter(|(_, entry)| entry.is_live(now))
+                .take(limit)
+                .map(|(key, _)| key.clone())
+                .collect())
+        })
+    }
+
+    fn key_counts(&self) -> Result<KeyCounts> {
+        let now = now_millis();
+        Ok(self
+            .store
+            .read()
+            .unwrap()
+            .values()
+            .filter(|entry| entry.is_live(now))
+            .fold(KeyCounts::default(), |counts, entry| {
+                counts.add(entry.expires_at)
+            }))
+    }
+
+    fn watch(&self, prefix: impl AsRef<[u8]>) -> Watch {
+        self.watchers.watch(prefix.as_ref())
+    }
+}
+
+impl MemStore {
+    /// Sets the value of the key and returns its new version.
+    fn set_entry(
+        &self,
+        store: &mut BTreeMap<Vec<u8>, Entry>,
+        key: &[u8],
+        value: &[u8],
+        expires_at: Option<u64>,
+    ) -> u64 {
+        let version = self.last_version.fetch_add(1, Ordering::SeqCst) + 1;
+        let entry = Entry {
+            value: value.to_vec(),
+            version,
+            expires_at,
+        };
+        store.insert(key.to_vec(), entry);
+        if self.watchers.is_watched(key) {
+            self.watchers.send(vec![Event::Set {
+                key: key.to_vec(),
+                value: value.to_vec(),
+                version,
+            }]);
+        }
+        version
+    }
+
+    /// The event of removing the key, if it is watched.
+    fn removal(&self, key: &[u8]) -> Vec<Event> {
+        if self.watchers.is_watched(key) {
+            vec![Event::Remove { key: key.to_vec() }]
+        } else {
+            Vec::new()
+        }
+    }
+}
+
+/// The entry of the key if it is there and not expired.
+fn live_entry<'a>(store: &'a BTreeMap<Vec<u8>, Entry>, key: &[u8]) -> Option<&'a Entry> {
+    store.get(key).filter(|entry| entry.is_live(now_millis()))
+}
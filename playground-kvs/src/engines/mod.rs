@@ -0,0 +1,270 @@
+use crate::{KvsError, Result};
+use serde::{Deserialize, Serialize};
+use std::convert::TryFrom;
+use std::fs;
+use std::io;
+use std::ops::RangeBounds;
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+mod batch;
+mod kvs;
+mod memory;
+mod scan;
+mod sled;
+mod watch;
+
+pub use self::batch::WriteBatch;
+pub use self::kvs::{
+    CheckReport, GenerationStats, KvStore, KvStoreOptions, LogOffset, NamespaceStats, Problem,
+    Snapshot, StoreStats, SyncPolicy,
+};
+pub use self::memory::MemStore;
+pub use self::scan::{prefix_range, scan_range, KeyRange, Scan, ScanItem};
+pub use self::sled::SledStore;
+use self::watch::Watchers;
+pub use self::watch::{Event, Watch};
+
+/// Name of the file in a store directory which tells which engine wrote to it.
+const ENGINE_FILE_NAME: &str = "engine";
+
+/// What every storage backend of kvs can do.
+/// Keys and values are bytes, anything like `&str`, `String`, `&[u8]` or `Vec<u8>` can be passed.
+/// Keys are compared by their bytes and scans return them in that order.
+///
+/// Every set gives its key a version which is higher than every version before in the store,
+/// starting at 1. Keys which are not there have version 0. A version never comes back,
+/// not even when a removed or expired key is set again, so it tells apart every value a key had.
+///
+/// Keys set with a time to live are gone once it is over, just as if they were removed then.
+/// Other than removed keys they don't show up in a [`Watch`].
+///
+/// Clones of an engine are handles to the same store,
+/// so every thread which wants to use the store gets a clone of its own.
+pub trait KvsEngine: Clone + Send + 'static {
+    /// Inserts a new key-value entry or overwrites an existing one with an equal key.
+    /// Returns the new version of the key.
+    fn set(&self, key: impl AsRef<[u8]>, value: impl AsRef<[u8]>) -> Result<u64>;
+
+    /// Like [`KvsEngine::set`], but the key is gone after `ttl`.
+    fn set_with_ttl(
+        &self,
+        key: impl AsRef<[u8]>,
+        value: impl AsRef<[u8]>,
+        ttl: Duration,
+    ) -> Result<u64>;
+
+    /// Returns the value of the key if it was set before and not removed yet.
+    fn get(&self, key: impl AsRef<[u8]>) -> Result<Option<Vec<u8>>> {
+        Ok(self.get_versioned(key)?.map(|(value, _)| value))
+    }
+
+    /// Like [`KvsEngine::get`], also returns the version of the key.
+    fn get_versioned(&self, key: impl AsRef<[u8]>) -> Result<Option<(Vec<u8>, u64)>>;
+
+    /// Removes the entry with an equal key.
+    /// Fails with [`KvsError::KeyNotFound`] if there is no such key.
+    fn remove(&self, key: impl AsRef<[u8]>) -> Result<()>;
+
+    /// Sets the key to `new`, or removes it if `new` is `None`, but only if the key is as `expected`.
+    /// Otherwise nothing is written and this fails with [`KvsError::Conflict`].
+    /// A set key is gone after `ttl` if given.
+    /// Returns the new version of the key, 0 if it was removed.
+    ///
+    /// Checking and writing happen together, no other write to the store comes in between.
+    fn write_if(
+        &self,
+        key: impl AsRef<[u8]>,
+        expected: Expected,
+        new: Option<&[u8]>,
+        ttl: Option<Duration>,
+    ) -> Result<u64>;
+
+    /// Swaps the value of the key from `expected` to `new`, where `None` means that the key is not there.
+    /// Fails with [`KvsError::Conflict`] if the value isn't `expected`.
+    /// Returns the new version of the key, 0 if it was removed.
+    fn compare_and_swap(
+        &self,
+        key: impl AsRef<[u8]>,
+        expected: Option<&[u8]>,
+        new: Option<&[u8]>,
+    ) -> Result<u64> {
+        let expected = Expected::Value(expected.map(<[u8]>::to_vec));
+        self.write_if(key, expected, new, None)
+    }
+
+    /// Sets the key only if it is not there yet, fails with [`KvsError::Conflict`] otherwise.
+    /// Returns the new version of the key.
+    fn set_if_absent(&self, key: impl AsRef<[u8]>, value: impl AsRef<[u8]>) -> Result<u64> {
+        self.write_if(key, Expected::Version(0), Some(value.as_ref()), None)
+    }
+
+    /// Sets the key only if it has `version`, fails with [`KvsError::Conflict`] otherwise.
+    /// Returns the new version of the key.
+    fn set_if_version(
+        &self,
+        key: impl AsRef<[u8]>,
+        value: impl AsRef<[u8]>,
+        version: u64,
+    ) -> Result<u64> {
+        self.write_if(key, Expected::Version(version), Some(value.as_ref()), None)
+    }
+
+    /// Applies all changes of `batch` in order or, if one of them fails, none of them.
+    /// Readers see either none or all of the changes.
+    fn apply(&self, batch: &WriteBatch) -> Result<()>;
+
+    /// Replaces all entries with those of `entries`, which are ordered by key without duplicates.
+    /// Readers see either the old or the new entries. The new ones get new versions.
+    ///
+    /// [`KvStore`] writes them to a new generation of its log and only replaces the entries
+    /// of its namespace. The other engines apply them as one [`WriteBatch`],
+    /// so they have to fit in memory.
+    fn replace_all(&self, entries: Scan) -> Result<()> {
+        let entries = entries.collect::<Result<Vec<_>>>()?;
+        loop {
+            let mut batch = WriteBatch::new();
+            let mut new_keys = entries.iter().map(|(key, _)| key).peekable();
+            for key in self.scan_keys(..) {
+                let key = key?;
+                while new_keys.next_if(|new_key| **new_key < key).is_some() {}
+                if new_keys.peek() != Some(&&key) {
+                    batch.remove(key);
+                }
+            }
+            for (key, value) in &entries {
+                batch.set(key, value);
+            }
+            match self.apply(&batch) {
+                // A key expired after it was scanned.
+                Err(KvsError::KeyNotFound) => continue,
+                result => return result,
+            }
+        }
+    }
+
+    /// Returns the entries with keys in `range`, ordered by key.
+    fn scan(&self, range: impl RangeBounds<Vec<u8>>) -> Scan;
+
+    /// Returns the entries with keys which start with `prefix`, ordered by key.
+    fn scan_prefix(&self, prefix: impl AsRef<[u8]>) -> Scan {
+        self.scan(prefix_range(prefix))
+    }
+
+    /// Like [`KvsEngine::scan`], but only returns the keys.
+    /// Engines which keep the keys apart from the values don't read the values for it.
+    fn scan_keys(&self, range: impl RangeBounds<Vec<u8>>) -> Scan<Vec<u8>> {
+        let engine = self.clone();
+        let range = (range.start_bound().cloned(), range.end_bound().cloned());
+        Scan::new(range, move |range, limit| {
+            engine
+                .scan(range.clone())
+                .take(limit)
+                .map(|entry| entry.map(|(key, _)| key))
+                .collect()
+        })
+    }
+
+    /// Like [`KvsEngine::scan`], but sees the entries as they are now however long it goes on,
+    /// for engines which can. [`KvStore`] reads them from a [`Snapshot`], the others scan live.
+    fn scan_consistent(&self, range: impl RangeBounds<Vec<u8>>) -> Result<Scan> {
+        Ok(self.scan(range))
+    }
+
+    /// Returns how many keys there are without reading their values.
+    fn key_counts(&self) -> Result<KeyCounts>;
+
+    /// Returns the changes of the keys which start with `prefix` from now on.
+    fn watch(&self, prefix: impl AsRef<[u8]>) -> Watch;
+
+    /// Like [`KvsEngine::get`] for values which were set as strings.
+    /// Fails with [`KvsError::NotUtf8`] if the value is no string.
+    fn get_string(&self, key: impl AsRef<[u8]>) -> Result<Option<String>> {
+        match self.get(key)? {
+            Some(value) => Ok(Some(String::from_utf8(value)?)),
+            None => Ok(None),
+        }
+    }
+}
+
+/// How many keys an engine has, as [`KvsEngine::key_counts`] returns it.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct KeyCounts {
+    /// How many keys are there, without the expired ones.
+    pub keys: usize,
+    /// How many of them have a time to live.
+    pub expiring: usize,
+}
+
+impl KeyCounts {
+    /// Counts a key which is there.
+    fn add(self, expires_at: Option<u64>) -> Self {
+        Self {
+            keys: self.keys + 1,
+            expiring: self.expiring + usize::from(expires_at.is_some()),
+        }
+    }
+}
+
+/// What a conditional write expects of the key before it is written.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub enum Expected {
+    /// The key has this value, `None` if it is not there.
+    Value(Option<Vec<u8>>),
+    /// The key has this version, 0 if it is not there.
+    Version(u64),
+}
+
+impl Expected {
+    /// Whether a key with this entry, `None` if it is not there, is as expected.
+    pub(crate) fn matches(&self, entry: Option<(&[u8], u64)>) -> bool {
+        match self {
+            Expected::Value(expected) => expected.as_deref() == entry.map(|(value, _)| value),
+            Expected::Version(expected) => *expected == entry.map_or(0, |(_, version)| version),
+        }
+    }
+}
+
+/// The current time as milliseconds since the Unix epoch, which is how expiry times are kept.
+pub(crate) fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since_epoch| since_epoch.as_millis() as u64)
+}
+
+/// When a key which is set now with `ttl` expires, if it does.
+pub(crate) fn expiry(ttl: Option<Duration>) -> Option<u64> {
+    ttl.map(|ttl| now_millis().saturating_add(u64::try_from(ttl.as_millis()).unwrap_or(u64::MAX)))
+}
+
+/// Makes sure that a store directory is only ever used by one engine.
+///
+/// Returns the engine to use: `requested` if given, otherwise the engine which used `dir` before,
+/// otherwise `default`. The first engine to use `dir` is recorded in it and any other engine
+/// requested later fails with [`KvsError::WrongEngine`].
+/// The `memory` engine leaves nothing in `dir`, so it is never recorded.
+pub fn select_engine(dir: &Path, requested: Option<&str>, default: &str) -> Result<String> {
+    match (requested, recorded_engine(dir)?) {
+        (Some(requested), Some(recorded)) if requested != recorded => Err(KvsError::WrongEngine {
+            requested: requested.to_owned(),
+            recorded,
+        }),
+        (_, Some(recorded)) => Ok(recorded),
+        (Some("memory"), None) => Ok("memory".to_owned()),
+        (requested, None) => {
+            let engine = requested.unwrap_or(default);
+            fs::create_dir_all(dir)?;
+            fs::write(dir.join(ENGINE_FILE_NAME), engine)?;
+            Ok(engine.to_owned())
+        }
+    }
+}
+
+/// The engine recorded in `dir` by [`select_engine`], if any. Unlike it, this changes nothing.
+pub fn recorded_engine(dir: &Path) -> Result<Option<String>> {
+    match fs::read_to_string(dir.join(ENGINE_FILE_NAME)) {
+        Ok(recorded) => Ok(Some(recorded.trim().to_owned())),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
@@ -0,0 +1,136 @@
+use crate::Result;
+use std::ops::Bound;
+use std::vec;
+
+/// A range of keys as start and end bound.
+pub type KeyRange = (Bound<Vec<u8>>, Bound<Vec<u8>>);
+
+/// How many entries a [`Scan`] fetches from its engine at once.
+const BATCH_LEN: usize = 64;
+
+type Fetch<T> = Box<dyn FnMut(&KeyRange, usize) -> Result<Vec<T>> + Send>;
+
+/// Iterator over the entries of a range of keys, ordered by key,
+/// or over only the keys, see [`KvsEngine::scan_keys`](crate::KvsEngine::scan_keys).
+///
+/// The entries are fetched from the engine in small batches while iterating,
+/// so the scan doesn't hold up writers but may or may not see writes which happen meanwhile.
+pub struct Scan<T = (Vec<u8>, Vec<u8>)> {
+    fetch: Fetch<T>,
+    range: KeyRange,
+    batch: vec::IntoIter<T>,
+    done: bool,
+}
+
+/// What a [`Scan`] iterates over, a key with or without its value.
+pub trait ScanItem {
+    /// The key of the item.
+    fn key(&self) -> &[u8];
+}
+
+impl ScanItem for (Vec<u8>, Vec<u8>) {
+    fn key(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl ScanItem for Vec<u8> {
+    fn key(&self) -> &[u8] {
+        self
+    }
+}
+
+impl<T> Scan<T> {
+    /// `fetch` returns up to the given number of items of a range, ordered by key.
+    pub(crate) fn new(
+        range: KeyRange,
+        fetch: impl FnMut(&KeyRange, usize) -> Result<Vec<T>> + Send + 'static,
+    ) -> Self {
+        Self {
+            done: is_empty(&range),
+            fetch: Box::new(fetch),
+            range,
+            batch: Vec::new().into_iter(),
+        }
+    }
+}
+
+impl<T: ScanItem> Iterator for Scan<T> {
+    type Item = Result<T>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(entry) = self.batch.next() {
+            return Some(Ok(entry));
+        }
+        if self.done {
+            return None;
+        }
+        match (self.fetch)(&self.range, BATCH_LEN) {
+            Ok(batch) => {
+                self.done = batch.len() < BATCH_LEN;
+                if let Some(last) = batch.last() {
+                    self.range.0 = Bound::Excluded(last.key().to_vec());
+                }
+                self.batch = batch.into_iter();
+                self.batch.next().map(Ok)
+            }
+            Err(e) => {
+                self.done = true;
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+/// The range of all keys which start with `prefix`.
+pub fn prefix_range(prefix: impl AsRef<[u8]>) -> KeyRange {
+    let prefix = prefix.as_ref();
+    (Bound::Included(prefix.to_vec()), prefix_end(prefix))
+}
+
+/// The range of keys which start with `prefix` (if given), are not before `start` (if given)
+/// and are before `end` (if given).
+pub fn scan_range(prefix: Option<&[u8]>, start: Option<&[u8]>, end: Option<&[u8]>) -> KeyRange {
+    let (mut range_start, mut range_end) = match prefix {
+        Some(prefix) => prefix_range(prefix),
+        None => (Bound::Unbounded, Bound::Unbounded),
+    };
+    if let Some(start) = start {
+        match &range_start {
+            Bound::Included(prefix) if prefix.as_slice() >= start => {}
+            _ => range_start = Bound::Included(start.to_vec()),
+        }
+    }
+    if let Some(end) = end {
+        match &range_end {
+            Bound::Excluded(prefix_end) if prefix_end.as_slice() <= end => {}
+            _ => range_end = Bound::Excluded(end.to_vec()),
+        }
+    }
+    (range_start, range_end)
+}
+
+/// The smallest key after all keys starting with `prefix`.
+fn prefix_end(prefix: &[u8]) -> Bound<Vec<u8>> {
+    let mut bytes = prefix.to_vec();
+    while let Some(last) = bytes.pop() {
+        if last < u8::MAX {
+            bytes.push(last + 1);
+            return Bound::Excluded(bytes);
+        }
+    }
+    Bound::Unbounded
+}
+
+/// Whether no key can be in the range.
+/// `BTreeMap::range` panics for some of these.
+pub(crate) fn is_empty(range: &KeyRange) -> bool {
+    use Bound::*;
+    match range {
+        (Included(start), Included(end)) => start > end,
+        (Included(start), Excluded(end))
+        | (Excluded(start), Included(end))
+        | (Excluded(start), Excluded(end)) => start >= end,
+        _ => false,
+    }
+}
@@ -0,0 +1,289 @@
+use super::batch::BatchOp;
+use super::{
+    expiry, now_millis, Event, Expected, KeyCounts, KvsEngine, Scan, Watch, Watchers, WriteBatch,
+};
+use crate::{KvsError, Result};
+use serde::{Deserialize, Serialize};
+use sled::{Batch, Db, Tree};
+use std::collections::HashMap;
+use std::io;
+use std::ops::RangeBounds;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Name of the sled tree which keeps the highest version given to a key so far.
+const VERSIONS_TREE_NAME: &str = "versions";
+/// Key of the highest version in that tree.
+const LAST_VERSION_KEY: &[u8] = b"last";
+
+/// How long opening a store waits for the directory to be let go by a store which was dropped.
+const LOCK_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// A key-value store on top of [sled](https://docs.rs/sled), a mature embedded database,
+/// to hold [`KvStore`](crate::KvStore) against.
+///
+/// sled keeps the entries, every write is synced to disk before it returns.
+/// Otherwise sled would only write them out every now and then,
+/// so that they would get lost if the process died.
+/// Versions and expiry times are kept in front of the values,
+/// the highest version so far is kept in a tree of its own.
+/// Expired keys are hidden and only dropped when they are written to or removed.
+/// Writes take turns, reads don't wait for them.
+#[derive(Clone)]
+pub struct SledStore {
+    db: Db,
+    versions: Tree,
+    /// Held while writing, so that checking a key and writing it happen together.
+    writer: Arc<Mutex<()>>,
+    watchers: Watchers,
+}
+
+/// What is kept in sled for a key.
+#[derive(Serialize, Deserialize)]
+struct Entry {
+    version: u64,
+    /// Milliseconds since the Unix epoch.
+    expires_at: Option<u64>,
+    #[serde(with = "serde_bytes")]
+    value: Vec<u8>,
+}
+
+impl Entry {
+    fn is_live(&self, now: u64) -> bool {
+        self.expires_at.is_none_or(|expires_at| now < expires_at)
+    }
+}
+
+impl SledStore {
+    /// Opens the store in the directory `path`, creating it if needed.
+    /// Only one store can be open in a directory at a time.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        let started = Instant::now();
+        let db = loop {
+            let config = sled::Config::new().path(path.as_ref()).flush_every_ms(None);
+            match config.open() {
+                // sled's background threads hold the directory a moment longer
+                // than the last clone of a store which was dropped in this process.
+                Err(sled::Error::Io(e)) if is_locked(&e) && started.elapsed() < LOCK_TIMEOUT => {
+                    thread::sleep(Duration::from_millis(10))
+                }
+                result => break result?,
+            }
+        };
+        Ok(Self {
+            versions: db.open_tree(VERSIONS_TREE_NAME)?,
+            db,
+            writer: Arc::new(Mutex::new(())),
+            watchers: Watchers::default(),
+        })
+    }
+
+    /// The entry of the key if it is there and not expired.
+    fn live_entry(&self, key: &[u8]) -> Result<Option<Entry>> {
+        match self.db.get(key)? {
+            Some(bytes) => {
+                let entry: Entry = bincode::deserialize(&bytes)?;
+                Ok(Some(entry).filter(|entry| entry.is_live(now_millis())))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Sets the value of the key and returns its new version, the caller holds the writer lock.
+    fn set_entry(&self, key: &[u8], value: &[u8], expires_at: Option<u64>) -> Result<u64> {
+        let version = self.next_version()?;
+        let entry = Entry {
+            version,
+            expires_at,
+            value: value.to_vec(),
+        };
+        self.db.insert(key, bincode::serialize(&entry)?)?;
+        self.db.flush()?;
+        if self.watchers.is_watched(key) {
+            self.watchers.send(vec![Event::Set {
+                key: key.to_vec(),
+                value: entry.value,
+                version,
+            }]);
+        }
+        Ok(version)
+    }
+
+    /// Returns a version higher than every one before, the caller holds the writer lock.
+    /// It is saved before the key is written with it, so it doesn't come back after a crash.
+    fn next_version(&self) -> Result<u64> {
+        let last_version: u64 = match self.versions.get(LAST_VERSION_KEY)? {
+            Some(bytes) => bincode::deserialize(&bytes)?,
+            None => 0,
+        };
+        let version = last_version + 1;
+        self.versions
+            .insert(LAST_VERSION_KEY, bincode::serialize(&version)?)?;
+        Ok(version)
+    }
+
+    /// Removes the key, the caller holds the writer lock. Fails if it isn't there.
+    fn remove_entry(&self, key: &[u8]) -> Result<()> {
+        let live = self.live_entry(key)?.is_some();
+        self.db.remove(key)?;
+        self.db.flush()?;
+        if !live {
+            return Err(KvsError::KeyNotFound);
+        }
+        if self.watchers.is_watched(key) {
+            self.watchers
+                .send(vec![Event::Remove { key: key.to_vec() }]);
+        }
+        Ok(())
+    }
+}
+
+impl KvsEngine for SledStore {
+    fn set(&self, key: impl AsRef<[u8]>, value: impl AsRef<[u8]>) -> Result<u64> {
+        let _writer = self.writer.lock().unwrap();
+        self.set_entry(key.as_ref(), value.as_ref(), None)
+    }
+
+    fn set_with_ttl(
+        &self,
+        key: impl AsRef<[u8]>,
+        value: impl AsRef<[u8]>,
+        ttl: Duration,
+    ) -> Result<u64> {
+        let _writer = self.writer.lock().unwrap();
+        self.set_entry(key.as_ref(), value.as_ref(), expiry(Some(ttl)))
+    }
+
+    fn get_versioned(&self, key: impl AsRef<[u8]>) -> Result<Option<(Vec<u8>, u64)>> {
+        Ok(self
+            .live_entry(key.as_ref())?
+            .map(|entry| (entry.value, entry.version)))
+    }
+
+    fn remove(&self, key: impl AsRef<[u8]>) -> Result<()> {
+        let _writer = self.writer.lock().unwrap();
+        self.remove_entry(key.as_ref())
+    }
+
+    fn write_if(
+        &self,
+        key: impl AsRef<[u8]>,
+        expected: Expected,
+        new: Option<&[u8]>,
+        ttl: Option<Duration>,
+    ) -> Result<u64> {
+        let key = key.as_ref();
+        let _writer = self.writer.lock().unwrap();
+        let entry = self.live_entry(key)?;
+        if !expected.matches(
+            entry
+                .as_ref()
+                .map(|entry| (entry.value.as_slice(), entry.version)),
+        ) {
+            return Err(KvsError::Conflict(entry.map_or(0, |entry| entry.version)));
+        }
+        match (new, entry) {
+            (Some(value), _) => self.set_entry(key, value, expiry(ttl)),
+            (None, Some(_)) => self.remove_entry(key).map(|()| 0),
+            // Expected not to be there and it isn't, but it might have expired.
+            (None, None) => {
+                self.db.remove(key)?;
+                self.db.flush()?;
+                Ok(0)
+            }
+        }
+    }
+
+    fn apply(&self, batch: &WriteBatch) -> Result<()> {
+        let _writer = self.writer.lock().unwrap();
+        // Whether the keys are there before the batch, read up front because reading can fail.
+        let mut live = HashMap::new();
+        for op in batch.ops() {
+            let key = match op {
+                BatchOp::Set { key, .. } | BatchOp::Remove { key } => key.as_slice(),
+            };
+            if !live.contains_key(key) {
+                live.insert(key, self.live_entry(key)?.is_some());
+            }
+        }
+        batch.check_removals(|key| live[key])?;
+
+        let mut sled_batch = Batch::default();
+        let mut events = Vec::new();
+        for op in batch.ops() {
+            match op {
+                BatchOp::Set { key, value } => {
+                    let version = self.next_version()?;
+                    let entry = Entry {
+                        version,
+                        expires_at: None,
+                        value: value.clone(),
+                    };
+                    sled_batch.insert(key.as_slice(), bincode::serialize(&entry)?);
+                    if self.watchers.is_watched(key) {
+                        events.push(Event::Set {
+                            key: key.clone(),
+                            value: value.clone(),
+                            version,
+                        });
+                    }
+                }
+                BatchOp::Remove { key } => {
+                    sled_batch.remove(key.as_slice());
+                    if self.watchers.is_watched(key) {
+                        events.push(Event::Remove { key: key.clone() });
+                    }
+                }
+            }
+        }
+        self.db.apply_batch(sled_batch)?;
+        self.db.flush()?;
+        self.watchers.send(events);
+        Ok(())
+    }
+
+    fn scan(&self, range: impl RangeBounds<Vec<u8>>) -> Scan {
+        let db = self.db.clone();
+        let range = (range.start_bound().cloned(), range.end_bound().cloned());
+        Scan::new(range, move |range, limit| {
+            let now = now_millis();
+            let mut entries = Vec::new();
+            for item in db.range(range.clone()) {
+                if entries.len() == limit {
+                    break;
+                }
+                let (key, bytes) = item?;
+                let entry: Entry = bincode::deserialize(&bytes)?;
+                if entry.is_live(now) {
+                    entries.push((key.to_vec(), entry.value));
+                }
+            }
+            Ok(entries)
+        })
+    }
+
+    /// The values are kept along with the expiry times, so they are read all the same.
+    fn key_counts(&self) -> Result<KeyCounts> {
+        let now = now_millis();
+        let mut counts = KeyCounts::default();
+        for item in self.db.iter() {
+            let (_, bytes) = item?;
+            let entry: Entry = bincode::deserialize(&bytes)?;
+            if entry.is_live(now) {
+                counts = counts.add(entry.expires_at);
+            }
+        }
+        Ok(counts)
+    }
+
+    fn watch(&self, prefix: impl AsRef<[u8]>) -> Watch {
+        self.watchers.watch(prefix.as_ref())
+    }
+}
+
+/// Whether sled failed to open a directory because another store has it open.
+fn is_locked(e: &io::Error) -> bool {
+    e.kind() == io::ErrorKind::Other && e.to_string().starts_with("could not acquire lock")
+}
@@ -0,0 +1,127 @@
+use serde::{Deserialize, Serialize};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// A change of a key, as a [`Watch`] gets to see it.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub enum Event {
+    /// The key was set.
+    Set {
+        /// The key.
+        #[serde(with = "serde_bytes")]
+        key: Vec<u8>,
+        /// Its new value.
+        #[serde(with = "serde_bytes")]
+        value: Vec<u8>,
+        /// Its new version.
+        version: u64,
+    },
+    /// The key was removed.
+    Remove {
+        /// The key.
+        #[serde(with = "serde_bytes")]
+        key: Vec<u8>,
+    },
+}
+
+impl Event {
+    /// The key which changed.
+    pub fn key(&self) -> &[u8] {
+        match self {
+            Event::Set { key, .. } | Event::Remove { key } => key,
+        }
+    }
+
+    /// A copy of the event with the first `len` bytes of the key cut off.
+    fn strip_key(&self, len: usize) -> Event {
+        let mut event = self.clone();
+        match &mut event {
+            Event::Set { key, .. } | Event::Remove { key } => {
+                key.drain(..len);
+            }
+        }
+        event
+    }
+}
+
+/// The changes of the keys with a prefix from when [`KvsEngine::watch`](crate::KvsEngine::watch)
+/// was called on, in the order they were written.
+///
+/// Iterating waits for the next change and ends once every clone of the store is dropped.
+/// Changes which aren't taken yet are buffered without bound.
+pub struct Watch {
+    receiver: Receiver<Event>,
+}
+
+impl Watch {
+    /// Waits at most `timeout` for the next change, returns `None` if there was none.
+    pub fn next_timeout(&self, timeout: Duration) -> Option<Event> {
+        match self.receiver.recv_timeout(timeout) {
+            Ok(event) => Some(event),
+            Err(RecvTimeoutError::Timeout) | Err(RecvTimeoutError::Disconnected) => None,
+        }
+    }
+}
+
+impl Iterator for Watch {
+    type Item = Event;
+
+    fn next(&mut self) -> Option<Event> {
+        self.receiver.recv().ok()
+    }
+}
+
+/// The watches of a store, shared by its clones.
+#[derive(Clone, Default)]
+pub(crate) struct Watchers {
+    watchers: Arc<Mutex<Vec<Watcher>>>,
+}
+
+/// Where the changes of the keys with `prefix` go.
+struct Watcher {
+    prefix: Vec<u8>,
+    /// How many bytes at the start of the keys are cut off before they are sent.
+    strip: usize,
+    sender: Sender<Event>,
+}
+
+impl Watchers {
+    pub(crate) fn watch(&self, prefix: &[u8]) -> Watch {
+        self.watch_namespace(&[], prefix)
+    }
+
+    /// Like [`Watchers::watch`] for the keys with `prefix` after `namespace`,
+    /// the watch gets to see them without `namespace`.
+    pub(crate) fn watch_namespace(&self, namespace: &[u8], prefix: &[u8]) -> Watch {
+        let (sender, receiver) = mpsc::channel();
+        self.watchers.lock().unwrap().push(Watcher {
+            prefix: [namespace, prefix].concat(),
+            strip: namespace.len(),
+            sender,
+        });
+        Watch { receiver }
+    }
+
+    /// Whether changes of the key are watched, so events have to be made for them.
+    pub(crate) fn is_watched(&self, key: &[u8]) -> bool {
+        let watchers = self.watchers.lock().unwrap();
+        watchers
+            .iter()
+            .any(|watcher| key.starts_with(&watcher.prefix))
+    }
+
+    /// Sends the events to the watches of their keys and forgets the watches which are dropped.
+    pub(crate) fn send(&self, events: Vec<Event>) {
+        if events.is_empty() {
+            return;
+        }
+        let mut watchers = self.watchers.lock().unwrap();
+        watchers.retain(|watcher| {
+            events
+                .iter()
+                .filter(|event| event.key().starts_with(&watcher.prefix))
+                .all(|event| watcher.sender.send(event.strip_key(watcher.strip)).is_ok())
+        });
+    }
+}
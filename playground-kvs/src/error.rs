@@ -0,0 +1,150 @@
+use std::fmt;
+use std::io;
+use std::string::FromUtf8Error;
+
+/// Everything that can go wrong with a [`KvsEngine`](crate::KvsEngine).
+#[derive(Debug)]
+pub enum KvsError {
+    /// Reading or writing the log failed.
+    Io(io::Error),
+    /// A command could not be serialized or deserialized.
+    Serde(bincode::Error),
+    /// The key to remove was not in the store.
+    KeyNotFound,
+    /// The log contains something it can't contain if it was written by this crate.
+    CorruptLog(String),
+    /// The log was written in a format version this crate doesn't know.
+    UnsupportedVersion(u32),
+    /// The store directory was written by another engine than the requested one.
+    WrongEngine {
+        /// The engine which should have been used.
+        requested: String,
+        /// The engine which used the directory before.
+        recorded: String,
+    },
+    /// The server failed to handle a request or didn't answer as expected.
+    Server(String),
+    /// A value was requested as string but it is not valid UTF-8.
+    NotUtf8(FromUtf8Error),
+    /// Input to be parsed, like the changes of a batch, is malformed.
+    InvalidInput(String),
+    /// A conditional write found the key other than expected, it has this version now.
+    Conflict(u64),
+    /// sled failed other than by reading or writing, see [`SledStore`](crate::SledStore).
+    Sled(sled::Error),
+}
+
+/// Result type of all fallible kvs operations.
+pub type Result<T> = std::result::Result<T, KvsError>;
+
+impl fmt::Display for KvsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            KvsError::Io(e) => write!(f, "I/O error: {}", e),
+            KvsError::Serde(e) => write!(f, "serialization error: {}", e),
+            KvsError::KeyNotFound => write!(f, "Key not found"),
+            KvsError::CorruptLog(reason) => write!(f, "corrupt log: {}", reason),
+            KvsError::UnsupportedVersion(version) => {
+                write!(f, "unsupported log format version {}", version)
+            }
+            KvsError::WrongEngine {
+                requested,
+                recorded,
+            } => write!(
+                f,
+                "can't use engine {}, the directory belongs to engine {}",
+                requested, recorded
+            ),
+            KvsError::Server(message) => write!(f, "server error: {}", message),
+            KvsError::NotUtf8(e) => write!(f, "value is not a string: {}", e),
+            KvsError::InvalidInput(reason) => write!(f, "invalid input: {}", reason),
+            KvsError::Conflict(version) => write!(
+                f,
+                "conflict: the key isn't as expected, its version is {}",
+                version
+            ),
+            KvsError::Sled(e) => write!(f, "sled error: {}", e),
+        }
+    }
+}
+
+impl KvsError {
+    /// Every kind of error gets its own exit code so that scripts can tell them apart.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            KvsError::KeyNotFound => 1,
+            KvsError::Io(_) => 2,
+            KvsError::Serde(_) => 3,
+            KvsError::CorruptLog(_) => 4,
+            KvsError::UnsupportedVersion(_) => 5,
+            KvsError::WrongEngine { .. } => 6,
+            KvsError::Server(_) => 7,
+            KvsError::NotUtf8(_) => 8,
+            KvsError::InvalidInput(_) => 9,
+            KvsError::Conflict(_) => 10,
+            KvsError::Sled(_) => 11,
+        }
+    }
+}
+
+impl std::error::Error for KvsError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            KvsError::Io(e) => Some(e),
+            KvsError::Serde(e) => Some(e),
+            KvsError::NotUtf8(e) => Some(e),
+            KvsError::Sled(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for KvsError {
+    fn from(e: io::Error) -> Self {
+        KvsError::Io(e)
+    }
+}
+
+impl From<bincode::Error> for KvsError {
+    fn from(e: bincode::Error) -> Self {
+        match *e {
+            bincode::ErrorKind::Io(e) => KvsError::Io(e),
+            _ => KvsError::Serde(e),
+        }
+    }
+}
+
+impl From<FromUtf8Error> for KvsError {
+    fn from(e: FromUtf8Error) -> Self {
+        KvsError::NotUtf8(e)
+    }
+}
+
+impl From<csv::Error> for KvsError {
+    fn from(e: csv::Error) -> Self {
+        let message = e.to_string();
+        match e.into_kind() {
+            csv::ErrorKind::Io(e) => KvsError::Io(e),
+            _ => KvsError::InvalidInput(message),
+        }
+    }
+}
+
+impl From<sled::Error> for KvsError {
+    fn from(e: sled::Error) -> Self {
+        match e {
+            sled::Error::Io(e) => KvsError::Io(e),
+            e => KvsError::Sled(e),
+        }
+    }
+}
+
+impl From<serde_json::Error> for KvsError {
+    fn from(e: serde_json::Error) -> Self {
+        if e.is_io() {
+            KvsError::Io(e.into())
+        } else {
+            KvsError::InvalidInput(e.to_string())
+        }
+    }
+}
@@ -0,0 +1,187 @@
+use crate::{KvStore, KvStoreOptions, KvsEngine, KvsError, Result, SyncPolicy};
+use serde::{Deserialize, Serialize};
+use std::io::{BufRead, Write};
+use std::str::FromStr;
+use tempfile::TempDir;
+
+/// A human-readable format for all entries of a store, see [`export`] and [`import`].
+/// Only keys and values are kept, versions and times to live are not.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    /// One JSON object `{"key": ..., "value": ...}` per line.
+    /// Bytes which are UTF-8 are written as a string, others as an array of numbers.
+    Jsonl,
+    /// Lines `key,value` after a header line, quoted where needed.
+    /// The bytes are written as they are, even if they aren't UTF-8.
+    Csv,
+}
+
+impl FromStr for Format {
+    type Err = KvsError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "jsonl" => Ok(Format::Jsonl),
+            "csv" => Ok(Format::Csv),
+            _ => Err(KvsError::InvalidInput(format!(
+                "unknown format {}, use jsonl or csv",
+                s
+            ))),
+        }
+    }
+}
+
+/// What [`import`] does with the entries which are in the store already.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ImportMode {
+    /// Keeps them, unless an imported entry has the same key.
+    Merge,
+    /// Removes them before importing, so only the imported entries are left.
+    Replace,
+}
+
+#[derive(Serialize, Deserialize)]
+struct JsonEntry {
+    key: JsonBytes,
+    value: JsonBytes,
+}
+
+/// Bytes in JSON, as a string if they are UTF-8 and as an array of numbers otherwise.
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+pub(crate) enum JsonBytes {
+    Text(String),
+    Bytes(Vec<u8>),
+}
+
+impl From<Vec<u8>> for JsonBytes {
+    fn from(bytes: Vec<u8>) -> Self {
+        match String::from_utf8(bytes) {
+            Ok(text) => JsonBytes::Text(text),
+            Err(e) => JsonBytes::Bytes(e.into_bytes()),
+        }
+    }
+}
+
+impl From<JsonBytes> for Vec<u8> {
+    fn from(bytes: JsonBytes) -> Self {
+        match bytes {
+            JsonBytes::Text(text) => text.into_bytes(),
+            JsonBytes::Bytes(bytes) => bytes,
+        }
+    }
+}
+
+/// Writes all entries of `engine` ordered by key to `writer`.
+/// The entries are read while they are written, so they don't have to fit in memory.
+/// Writes meanwhile don't show up in the export if the engine can scan consistently,
+/// see [`KvsEngine::scan_consistent`].
+/// Returns how many entries were written.
+pub fn export(engine: &impl KvsEngine, format: Format, mut writer: impl Write) -> Result<u64> {
+    let mut count = 0;
+    match format {
+        Format::Jsonl => {
+            for entry in engine.scan_consistent(..)? {
+                let (key, value) = entry?;
+                let entry = JsonEntry {
+                    key: key.into(),
+                    value: value.into(),
+                };
+                serde_json::to_writer(&mut writer, &entry)?;
+                writer.write_all(b"\n")?;
+                count += 1;
+            }
+            writer.flush()?;
+        }
+        Format::Csv => {
+            let mut writer = csv::Writer::from_writer(writer);
+            writer.write_record(["key", "value"])?;
+            for entry in engine.scan_consistent(..)? {
+                let (key, value) = entry?;
+                writer.write_record([key, value])?;
+                count += 1;
+            }
+            writer.flush()?;
+        }
+    }
+    Ok(count)
+}
+
+/// Sets the entries read from `reader` in `engine`.
+/// With [`ImportMode::Merge`] they are set one after another as they are read,
+/// so if the input turns out to be invalid, the entries before that are imported.
+/// With [`ImportMode::Replace`] the whole input is read and checked first,
+/// into a temporary [`KvStore`] so it doesn't have to fit in memory.
+/// Only then the imported entries take the place of those which are there,
+/// all at once with [`KvsEngine::replace_all`]. If that or reading the input fails,
+/// the store is left as it was.
+/// Returns how many entries were imported.
+pub fn import(
+    engine: &impl KvsEngine,
+    format: Format,
+    reader: impl BufRead,
+    mode: ImportMode,
+) -> Result<u64> {
+    if mode == ImportMode::Merge {
+        return read_entries(format, reader, |key, value| {
+            engine.set(key, value)?;
+            Ok(())
+        });
+    }
+
+    // Later entries with the same key win, like when they are set one after another.
+    let spool_dir = TempDir::new()?;
+    let options = KvStoreOptions {
+        sync: SyncPolicy::Never,
+        ..KvStoreOptions::default()
+    };
+    let spool = KvStore::open_with_options(spool_dir.path(), options)?;
+    let count = read_entries(format, reader, |key, value| {
+        spool.set(key, value)?;
+        Ok(())
+    })?;
+    engine.replace_all(spool.scan(..))?;
+    Ok(count)
+}
+
+/// Calls `f` with every entry read from `reader`, returns how many there were.
+fn read_entries(
+    format: Format,
+    reader: impl BufRead,
+    mut f: impl FnMut(Vec<u8>, Vec<u8>) -> Result<()>,
+) -> Result<u64> {
+    let mut count = 0;
+    match format {
+        Format::Jsonl => {
+            for (number, line) in reader.lines().enumerate() {
+                let line = line?;
+                if line.trim().is_empty() {
+                    continue;
+                }
+                let entry: JsonEntry = serde_json::from_str(&line).map_err(|e| {
+                    KvsError::InvalidInput(format!("line {} is no entry: {}", number + 1, e))
+                })?;
+                f(entry.key.into(), entry.value.into())?;
+                count += 1;
+            }
+        }
+        Format::Csv => {
+            let mut reader = csv::Reader::from_reader(reader);
+            for record in reader.byte_records() {
+                let record = record?;
+                match (record.get(0), record.get(1), record.len()) {
+                    (Some(key), Some(value), 2) => f(key.to_vec(), value.to_vec())?,
+                    _ => {
+                        let line = record.position().map_or(0, |position| position.line());
+                        return Err(KvsError::InvalidInput(format!(
+                            "line {} is not `key,value`",
+                            line
+                        )));
+                    }
+                };
+                count += 1;
+            }
+        }
+    }
+    Ok(count)
+}
@@ -0,0 +1,404 @@
+//! A REST interface over HTTP/1.1 with JSON bodies, for tools which can only talk HTTP.
+//!
+//! - `GET /keys/{key}` returns `{"key": ..., "value": ..., "version": ...}`.
+//! - `PUT /keys/{key}` sets the key to the request body and returns `{"key": ..., "version": ...}`.
+//! - `DELETE /keys/{key}` removes the key and returns nothing.
+//! - `GET /keys?prefix=...` returns `[{"key": ..., "value": ...}, ...]` ordered by key.
+//!
+//! Keys are percent-encoded in the path and the query. Bytes which aren't UTF-8 are written
+//! as arrays of numbers, like [`export`](crate::export) does.
+//! Failures return `{"error": ..., "message": ...}` with a status code which fits the error.
+
+use crate::export::JsonBytes;
+use crate::replication::Role;
+use crate::server::{run_on_pool, Connection};
+use crate::thread_pool::ThreadPool;
+use crate::{prefix_range, KvsEngine, KvsError, Result};
+use serde::Serialize;
+use std::fmt::Display;
+use std::io::{self, BufRead, Read, Write};
+
+/// How long the request line and every header line can be at most.
+const MAX_LINE_LEN: u64 = 64 * 1024;
+/// How many headers a request can have at most.
+const MAX_HEADERS: usize = 100;
+/// How long a value can be at most, the same as for RESP.
+const MAX_BODY_LEN: usize = 512 * 1024 * 1024;
+
+struct Request {
+    method: String,
+    target: String,
+    body: Vec<u8>,
+    /// Whether the client wants the connection to be closed after the response.
+    close: bool,
+}
+
+struct Response {
+    status: u16,
+    /// JSON, or nothing for `204 No Content`.
+    body: Option<String>,
+    /// The methods a resource allows, for `405 Method Not Allowed`.
+    allow: Option<&'static str>,
+}
+
+#[derive(Serialize)]
+struct Entry {
+    key: JsonBytes,
+    value: JsonBytes,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    version: Option<u64>,
+}
+
+#[derive(Serialize)]
+struct Written {
+    key: JsonBytes,
+    version: u64,
+}
+
+#[derive(Serialize)]
+struct Failure {
+    error: &'static str,
+    message: String,
+}
+
+impl Response {
+    fn json(status: u16, body: &impl Serialize) -> Result<Self> {
+        Ok(Self {
+            status,
+            body: Some(serde_json::to_string(body)?),
+            allow: None,
+        })
+    }
+
+    fn no_content() -> Self {
+        Self {
+            status: 204,
+            body: None,
+            allow: None,
+        }
+    }
+
+    fn error(status: u16, error: &'static str, message: impl Display) -> Self {
+        let failure = Failure {
+            error,
+            message: message.to_string(),
+        };
+        Self {
+            status,
+            body: Some(serde_json::to_string(&failure).unwrap()),
+            allow: None,
+        }
+    }
+
+    fn method_not_allowed(allow: &'static str) -> Self {
+        Self {
+            allow: Some(allow),
+            ..Self::error(405, "method_not_allowed", format!("use {}", allow))
+        }
+    }
+
+    fn write(&self, writer: &mut impl Write, close: bool) -> io::Result<()> {
+        write!(
+            writer,
+            "HTTP/1.1 {} {}\r\n",
+            self.status,
+            reason(self.status)
+        )?;
+        if let Some(allow) = self.allow {
+            write!(writer, "Allow: {}\r\n", allow)?;
+        }
+        if close {
+            write!(writer, "Connection: close\r\n")?;
+        }
+        match &self.body {
+            Some(body) => write!(
+                writer,
+                "Content-Type: application/json\r\nContent-Length: {}\r\n\r\n{}",
+                body.len(),
+                body
+            ),
+            None => write!(writer, "\r\n"),
+        }
+    }
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        204 => "No Content",
+        400 => "Bad Request",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        409 => "Conflict",
+        _ => "Internal Server Error",
+    }
+}
+
+/// The status code and the name of the error which a client gets for `e`.
+fn error_response(e: &KvsError) -> Response {
+    let (status, error) = match e {
+        KvsError::KeyNotFound => (404, "key_not_found"),
+        KvsError::InvalidInput(_) => (400, "invalid_input"),
+        KvsError::Conflict(_) => (409, "conflict"),
+        KvsError::Io(_) => (500, "io"),
+        KvsError::Serde(_) => (500, "serde"),
+        KvsError::CorruptLog(_) => (500, "corrupt_log"),
+        KvsError::UnsupportedVersion(_) => (500, "unsupported_version"),
+        KvsError::WrongEngine { .. } => (500, "wrong_engine"),
+        KvsError::Server(_) => (500, "server"),
+        KvsError::NotUtf8(_) => (500, "not_utf8"),
+        KvsError::Sled(_) => (500, "sled"),
+    };
+    Response::error(status, error, e)
+}
+
+/// Serves the requests of a client until it disconnects or asks to close the connection.
+pub(crate) fn serve<E: KvsEngine>(pool: &impl ThreadPool, connection: Connection<E>) -> Result<()> {
+    let Connection {
+        engine,
+        role,
+        mut reader,
+        mut writer,
+    } = connection;
+    loop {
+        let request = match read_request(&mut reader, &mut writer) {
+            Ok(Some(request)) => request,
+            Ok(None) => return Ok(()),
+            // The rest of the input can't be made sense of anymore.
+            Err(KvsError::InvalidInput(reason)) => {
+                Response::error(400, "bad_request", reason).write(&mut writer, true)?;
+                writer.flush()?;
+                return Ok(());
+            }
+            Err(e) => return Err(e),
+        };
+        let close = request.close;
+        let (engine, role) = (engine.clone(), role.clone());
+        let response = run_on_pool(pool, move || {
+            handle(&engine, &role, &request).unwrap_or_else(|e| error_response(&e))
+        })?;
+        response.write(&mut writer, close)?;
+        writer.flush()?;
+        if close {
+            return Ok(());
+        }
+    }
+}
+
+/// Reads the next request, returns `None` if the client disconnected before it.
+fn read_request(reader: &mut impl BufRead, writer: &mut impl Write) -> Result<Option<Request>> {
+    // Empty lines before a request are to be ignored.
+    let line = loop {
+        match read_line(reader)? {
+            Some(line) if line.is_empty() => continue,
+            Some(line) => break line,
+            None => return Ok(None),
+        }
+    };
+    let parts: Vec<_> = line.split(' ').collect();
+    let (method, target, version) = match parts[..] {
+        [method, target, version] if version.starts_with("HTTP/1.") => (method, target, version),
+        _ => {
+            return Err(KvsError::InvalidInput(format!(
+                "invalid request line '{}'",
+                line
+            )))
+        }
+    };
+
+    let mut close = version == "HTTP/1.0";
+    let mut content_length = 0;
+    let mut expects_continue = false;
+    let mut headers = 0;
+    loop {
+        let line = read_line(reader)?
+            .ok_or_else(|| KvsError::InvalidInput("headers end early".to_owned()))?;
+        if line.is_empty() {
+            break;
+        }
+        headers += 1;
+        if headers > MAX_HEADERS {
+            return Err(KvsError::InvalidInput("too many headers".to_owned()));
+        }
+        let (name, value) = line
+            .split_once(':')
+            .ok_or_else(|| KvsError::InvalidInput(format!("invalid header '{}'", line)))?;
+        let value = value.trim();
+        match name.to_ascii_lowercase().as_str() {
+            "content-length" => {
+                content_length = value
+                    .parse()
+                    .ok()
+                    .filter(|&len| len <= MAX_BODY_LEN)
+                    .ok_or_else(|| {
+                        KvsError::InvalidInput(format!("invalid Content-Length {}", value))
+                    })?
+            }
+            "transfer-encoding" => {
+                return Err(KvsError::InvalidInput(
+                    "a body has to be sent with Content-Length".to_owned(),
+                ))
+            }
+            "connection" => {
+                for option in value.split(',').map(str::trim) {
+                    if option.eq_ignore_ascii_case("close") {
+                        close = true;
+                    } else if option.eq_ignore_ascii_case("keep-alive") {
+                        close = false;
+                    }
+                }
+            }
+            "expect" => expects_continue = value.eq_ignore_ascii_case("100-continue"),
+            _ => {}
+        }
+    }
+
+    // Clients like curl wait a while for this before they send a large body.
+    if expects_continue {
+        write!(writer, "HTTP/1.1 100 Continue\r\n\r\n")?;
+        writer.flush()?;
+    }
+    // The buffer only grows as the bytes arrive, however long the body is said to be.
+    let mut body = Vec::new();
+    reader
+        .by_ref()
+        .take(content_length as u64)
+        .read_to_end(&mut body)?;
+    if body.len() < content_length {
+        return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+    }
+    Ok(Some(Request {
+        method: method.to_owned(),
+        target: target.to_owned(),
+        body,
+        close,
+    }))
+}
+
+/// Reads a line without its line break, returns `None` if the input ended before it.
+fn read_line(reader: &mut impl BufRead) -> Result<Option<String>> {
+    let mut line = Vec::new();
+    reader
+        .by_ref()
+        .take(MAX_LINE_LEN)
+        .read_until(b'\n', &mut line)?;
+    if line.is_empty() {
+        return Ok(None);
+    }
+    if line.pop() != Some(b'\n') {
+        return Err(KvsError::InvalidInput("line is too long".to_owned()));
+    }
+    if line.last() == Some(&b'\r') {
+        line.pop();
+    }
+    String::from_utf8(line)
+        .map(Some)
+        .map_err(|_| KvsError::InvalidInput("line is not UTF-8".to_owned()))
+}
+
+fn handle<E: KvsEngine>(engine: &E, role: &Role, request: &Request) -> Result<Response> {
+    let (path, query) = request
+        .target
+        .split_once('?')
+        .unwrap_or((&request.target, ""));
+    let method = request.method.as_str();
+    let is_write = matches!(method, "PUT" | "DELETE");
+    if let (true, Role::Follower(follower)) = (is_write, role) {
+        return Ok(Response::error(
+            403,
+            "read_only",
+            format!(
+                "this server is a read-only follower of {}",
+                follower.primary
+            ),
+        ));
+    }
+
+    if let Some(key) = path.strip_prefix("/keys/") {
+        let key = percent_decode(key)?;
+        match method {
+            "GET" => match engine.get_versioned(&key)? {
+                Some((value, version)) => Response::json(
+                    200,
+                    &Entry {
+                        key: key.into(),
+                        value: value.into(),
+                        version: Some(version),
+                    },
+                ),
+                None => Err(KvsError::KeyNotFound),
+            },
+            "PUT" => {
+                let version = engine.set(&key, &request.body)?;
+                Response::json(
+                    200,
+                    &Written {
+                        key: key.into(),
+                        version,
+                    },
+                )
+            }
+            "DELETE" => {
+                engine.remove(&key)?;
+                Ok(Response::no_content())
+            }
+            _ => Ok(Response::method_not_allowed("GET, PUT, DELETE")),
+        }
+    } else if path == "/keys" {
+        if method != "GET" {
+            return Ok(Response::method_not_allowed("GET"));
+        }
+        let mut prefix = Vec::new();
+        for pair in query.split('&').filter(|pair| !pair.is_empty()) {
+            let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+            match name {
+                "prefix" => prefix = percent_decode(&value.replace('+', " "))?,
+                _ => {
+                    return Err(KvsError::InvalidInput(format!(
+                        "unknown query parameter {}",
+                        name
+                    )))
+                }
+            }
+        }
+        let mut entries = Vec::new();
+        for entry in engine.scan_consistent(prefix_range(prefix))? {
+            let (key, value) = entry?;
+            entries.push(Entry {
+                key: key.into(),
+                value: value.into(),
+                version: None,
+            });
+        }
+        Response::json(200, &entries)
+    } else {
+        Ok(Response::error(
+            404,
+            "not_found",
+            format!("no resource at {}, use /keys or /keys/{{key}}", path),
+        ))
+    }
+}
+
+/// Decodes the `%XX` escapes of `s`.
+fn percent_decode(s: &str) -> Result<Vec<u8>> {
+    let bytes = s.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let byte = bytes
+                .get(i + 1..i + 3)
+                .filter(|hex| hex.iter().all(u8::is_ascii_hexdigit))
+                .and_then(|hex| u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok())
+                .ok_or_else(|| KvsError::InvalidInput(format!("invalid escape in {}", s)))?;
+            decoded.push(byte);
+            i += 3;
+        } else {
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+    }
+    Ok(decoded)
+}
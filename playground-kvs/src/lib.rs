@@ -1,49 +1,36 @@
 #![deny(missing_docs)]
 
-//! kvs contains a key-value store implementation.
-
-use std::collections::BTreeMap;
-
-/// A key-value which works but gives no guarantees about complexity, performance, edge-cases.
-/// Keys and values will be copied.
-/// Operations on non existent keys will be silently ignored.
-/// Keys are compared by Eq.
-/// That means that different keys, which are equal by Eq or change their equalness
-/// over time, might produce unintended behaviour.
-pub struct KvStore {
-    store: BTreeMap<String, String>,
-}
-
-impl KvStore {
-    /// Returns a new empty store.
-    /// Getting a value by a key will return None.
-    pub fn new() -> Self {
-        Self {
-            store: BTreeMap::new(),
-        }
-    }
-
-    /// Inserts a new key-value entry or overwrites an existing one with an equal key.
-    /// You can remove that entry or get its value after that call.
-    pub fn set(&mut self, key: String, value: String) {
-        self.store.insert(key, value);
-    }
-
-    /// If an equal key was inserted before and not removed yet,
-    /// then a copy of its value is returned.
-    pub fn get(&self, key: String) -> Option<String> {
-        self.store.get(&key).cloned()
-    }
-
-    /// Removes one entry with an equal key.
-    /// You can't get its value after that call.
-    pub fn remove(&mut self, key: String) {
-        self.store.remove(&key);
-    }
-}
-
-impl Default for KvStore {
-    fn default() -> Self {
-        KvStore::new()
-    }
-}
+//! kvs contains key-value store implementations behind the [`KvsEngine`] trait
+//! and a client and server to share one of them over the network.
+//! The server runs its connections on a [`ThreadPool`](thread_pool::ThreadPool)
+//! and can also talk the protocol of Redis or HTTP, see [`Protocol`].
+//! With the feature `async` there is also an [`AsyncKvsServer`] which runs them as tokio tasks.
+//! A server with a [`KvStore`] can be the primary of followers, which keep a read-only copy
+//! of its store by replicating its log.
+
+#[cfg(feature = "async")]
+mod async_server;
+mod client;
+mod engines;
+mod error;
+mod export;
+mod http;
+mod protocol;
+mod replication;
+mod resp;
+mod server;
+pub mod thread_pool;
+
+#[cfg(feature = "async")]
+pub use async_server::{AsyncEngine, AsyncKvsServer};
+pub use client::{KvsClient, RemoteWatch};
+pub use engines::{
+    prefix_range, recorded_engine, scan_range, select_engine, CheckReport, Event, Expected,
+    GenerationStats, KeyCounts, KeyRange, KvStore, KvStoreOptions, KvsEngine, LogOffset, MemStore,
+    NamespaceStats, Problem, Scan, ScanItem, SledStore, Snapshot, StoreStats, SyncPolicy, Watch,
+    WriteBatch,
+};
+pub use error::{KvsError, Result};
+pub use export::{export, import, Format, ImportMode};
+pub use replication::Status;
+pub use server::{KvsServer, Protocol};
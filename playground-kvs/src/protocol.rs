@@ -0,0 +1,221 @@
+//! What `kvs-client` and `kvs-server` say to each other.
+//!
+//! Every message is a frame: its length as big endian u32 followed by the bincode serialized message.
+//! A client sends one request frame and the server answers with one response frame,
+//! as often as the client likes on the same connection.
+//! A follower sends [`Request::Replicate`] instead and gets [`Replication`] frames from then on.
+//! After [`Request::Watch`] the server answers [`Response::Ok`] once the watch is set up
+//! and then sends a [`Response::Event`] for every change.
+
+use crate::{Event, Expected, KeyRange, KvsError, LogOffset, Result, Status, WriteBatch};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::io::{self, Read, Write};
+use std::time::Duration;
+#[cfg(feature = "async")]
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// How long a frame can be at most, room for the longest value RESP takes along with its key.
+const MAX_FRAME_LEN: usize = 1024 * 1024 * 1024;
+
+#[derive(Serialize, Deserialize, Debug)]
+pub enum Request {
+    Get {
+        #[serde(with = "serde_bytes")]
+        key: Vec<u8>,
+    },
+    /// The key is gone after `ttl` if given.
+    Set {
+        #[serde(with = "serde_bytes")]
+        key: Vec<u8>,
+        #[serde(with = "serde_bytes")]
+        value: Vec<u8>,
+        ttl: Option<Duration>,
+    },
+    Remove {
+        #[serde(with = "serde_bytes")]
+        key: Vec<u8>,
+    },
+    GetVersioned {
+        #[serde(with = "serde_bytes")]
+        key: Vec<u8>,
+    },
+    /// Sets the key to `new` or removes it if there is no `new`, if the key is as expected.
+    WriteIf {
+        #[serde(with = "serde_bytes")]
+        key: Vec<u8>,
+        expected: Expected,
+        #[serde(with = "serde_bytes")]
+        new: Option<Vec<u8>>,
+        ttl: Option<Duration>,
+    },
+    /// All changes of the batch or none of them.
+    Batch {
+        batch: WriteBatch,
+    },
+    /// At most `limit` entries with keys in `range`, or all of them if there is no limit.
+    Scan {
+        range: KeyRange,
+        limit: Option<u64>,
+    },
+    /// Streams the log from `from` on, or from the beginning if there is no `from`.
+    Replicate {
+        from: Option<LogOffset>,
+    },
+    Status,
+    /// Streams the changes of the keys which start with `prefix`.
+    Watch {
+        #[serde(with = "serde_bytes")]
+        prefix: Vec<u8>,
+    },
+}
+
+impl Request {
+    /// Whether the request changes the store, which a follower doesn't do.
+    pub fn is_write(&self) -> bool {
+        match self {
+            Request::Set { .. }
+            | Request::Remove { .. }
+            | Request::WriteIf { .. }
+            | Request::Batch { .. } => true,
+            Request::Get { .. }
+            | Request::GetVersioned { .. }
+            | Request::Scan { .. }
+            | Request::Replicate { .. }
+            | Request::Status
+            | Request::Watch { .. } => false,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub enum Response {
+    Ok,
+    Value(#[serde(with = "serde_bytes")] Option<Vec<u8>>),
+    Versioned(Option<(Vec<u8>, u64)>),
+    /// The new version of a key after a write.
+    Version(u64),
+    Entries(Vec<(Vec<u8>, Vec<u8>)>),
+    Status(Status),
+    Event(Event),
+    KeyNotFound,
+    /// The key of a conditional write wasn't as expected, it has this version.
+    Conflict(u64),
+    Err(String),
+}
+
+impl Response {
+    /// Turns the outcome of handling a request into what the client gets to see.
+    pub fn from_result(result: Result<Response>) -> Self {
+        match result {
+            Ok(response) => response,
+            Err(KvsError::KeyNotFound) => Response::KeyNotFound,
+            Err(KvsError::Conflict(version)) => Response::Conflict(version),
+            Err(e) => Response::Err(e.to_string()),
+        }
+    }
+}
+
+/// What a primary streams to a follower.
+#[derive(Serialize, Deserialize, Debug)]
+pub enum Replication {
+    /// The log starts over at `offset`, the follower drops all its entries.
+    Reset { offset: LogOffset },
+    /// Complete log records, after which the follower is at `offset`
+    /// with `pending` bytes of the log still to come.
+    Records {
+        #[serde(with = "serde_bytes")]
+        records: Vec<u8>,
+        offset: LogOffset,
+        pending: u64,
+    },
+    /// Nothing new, sent now and then so the follower knows that the primary is still there.
+    Heartbeat { offset: LogOffset, pending: u64 },
+    /// The server has no log to stream, the connection is closed after this.
+    Refused(String),
+}
+
+pub fn write_frame(mut writer: impl Write, message: &impl Serialize) -> Result<()> {
+    let bytes = bincode::serialize(message)?;
+    writer.write_all(&frame_len(bytes.len())?)?;
+    writer.write_all(&bytes)?;
+    writer.flush()?;
+    Ok(())
+}
+
+/// Returns `None` if the connection was closed before a new frame started.
+pub fn read_frame<T: DeserializeOwned>(mut reader: impl Read) -> Result<Option<T>> {
+    let mut len = [0; 4];
+    match reader.read_exact(&mut len) {
+        Ok(()) => {}
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e.into()),
+    }
+    let len = parse_frame_len(len)?;
+    // The buffer only grows as the bytes arrive, however long the frame claims to be.
+    let mut bytes = Vec::new();
+    reader.take(len as u64).read_to_end(&mut bytes)?;
+    check_complete(&bytes, len)?;
+    Ok(Some(bincode::deserialize(&bytes)?))
+}
+
+#[cfg(feature = "async")]
+pub async fn write_frame_async(
+    writer: &mut (impl AsyncWrite + Unpin),
+    message: &impl Serialize,
+) -> Result<()> {
+    let bytes = bincode::serialize(message)?;
+    writer.write_all(&frame_len(bytes.len())?).await?;
+    writer.write_all(&bytes).await?;
+    writer.flush().await?;
+    Ok(())
+}
+
+/// Returns `None` if the connection was closed before a new frame started.
+#[cfg(feature = "async")]
+pub async fn read_frame_async<T: DeserializeOwned>(
+    reader: &mut (impl AsyncRead + Unpin),
+) -> Result<Option<T>> {
+    let mut len = [0; 4];
+    match reader.read_exact(&mut len).await {
+        Ok(_) => {}
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e.into()),
+    }
+    let len = parse_frame_len(len)?;
+    let mut bytes = Vec::new();
+    reader.take(len as u64).read_to_end(&mut bytes).await?;
+    check_complete(&bytes, len)?;
+    Ok(Some(bincode::deserialize(&bytes)?))
+}
+
+/// The length in front of a frame of `len` bytes.
+fn frame_len(len: usize) -> Result<[u8; 4]> {
+    if len > MAX_FRAME_LEN {
+        return Err(KvsError::InvalidInput(format!(
+            "a message of {} bytes is too long",
+            len
+        )));
+    }
+    Ok((len as u32).to_be_bytes())
+}
+
+/// The length of the frame which starts with `len`, fails if it is too long to be read.
+fn parse_frame_len(len: [u8; 4]) -> Result<usize> {
+    let len = u32::from_be_bytes(len) as usize;
+    if len > MAX_FRAME_LEN {
+        return Err(KvsError::InvalidInput(format!(
+            "a frame of {} bytes is too long",
+            len
+        )));
+    }
+    Ok(len)
+}
+
+/// Fails if the connection was closed before all `len` bytes of the frame arrived.
+fn check_complete(bytes: &[u8], len: usize) -> Result<()> {
+    if bytes.len() < len {
+        return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+    }
+    Ok(())
+}
@@ -0,0 +1,192 @@
+use crate::protocol::{read_frame, write_frame, Replication, Request};
+use crate::{KvStore, KvsError, LogOffset, Result};
+use serde::{Deserialize, Serialize};
+use std::io::{BufReader, Write};
+use std::net::TcpStream;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How long a primary waits before it looks for new records in its log again.
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+/// How long a primary sends nothing before it sends a heartbeat.
+const HEARTBEAT_INTERVAL: Duration = Duration::from_millis(500);
+/// How long a follower waits before it connects again after losing its primary.
+const RETRY_INTERVAL: Duration = Duration::from_millis(500);
+/// About how many bytes of records are sent at once.
+const MAX_RECORDS_LEN: u64 = 1024 * 1024;
+
+/// How a server takes part in replication, as [`KvsClient::status`](crate::KvsClient::status)
+/// reports it.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub enum Status {
+    /// The engine of the server has no log which could be replicated.
+    Standalone,
+    /// Followers can replicate the log of the server.
+    Primary {
+        /// Where the next write goes in the log.
+        offset: LogOffset,
+        /// How many followers are connected.
+        followers: u64,
+    },
+    /// The server serves a read-only copy of a primary.
+    Follower {
+        /// The address of the primary.
+        primary: String,
+        /// Whether the follower is connected to the primary.
+        connected: bool,
+        /// How far the log of the primary is applied, if at all.
+        offset: Option<LogOffset>,
+        /// How many bytes of the log of the primary were not applied yet when it last said so.
+        lag: u64,
+        /// How long ago the primary was last heard of, if ever.
+        last_contact: Option<Duration>,
+    },
+}
+
+/// What a server does in replication, shared by all its connections.
+#[derive(Clone)]
+pub(crate) enum Role {
+    Standalone,
+    Primary {
+        store: KvStore,
+        followers: Arc<AtomicU64>,
+    },
+    Follower(Arc<Follower>),
+}
+
+impl Role {
+    pub(crate) fn status(&self) -> Status {
+        match self {
+            Role::Standalone => Status::Standalone,
+            Role::Primary { store, followers } => Status::Primary {
+                offset: store.end_offset(),
+                followers: followers.load(Ordering::SeqCst),
+            },
+            Role::Follower(follower) => {
+                let state = follower.state.lock().unwrap();
+                Status::Follower {
+                    primary: follower.primary.clone(),
+                    connected: state.connected,
+                    offset: state.offset,
+                    lag: state.lag,
+                    last_contact: state.last_contact.map(|instant| instant.elapsed()),
+                }
+            }
+        }
+    }
+}
+
+/// Applies the log of a primary to a store in the background.
+pub(crate) struct Follower {
+    pub(crate) primary: String,
+    state: Mutex<FollowerState>,
+}
+
+struct FollowerState {
+    connected: bool,
+    offset: Option<LogOffset>,
+    lag: u64,
+    last_contact: Option<Instant>,
+}
+
+impl Follower {
+    /// Starts following the primary at `primary` from where `store` left off.
+    /// Whenever the connection breaks, the follower connects again after a while.
+    pub(crate) fn start(store: KvStore, primary: String) -> Result<Arc<Self>> {
+        let follower = Arc::new(Self {
+            primary,
+            state: Mutex::new(FollowerState {
+                connected: false,
+                offset: store.replicated_offset()?,
+                lag: 0,
+                last_contact: None,
+            }),
+        });
+        let background = Arc::clone(&follower);
+        thread::spawn(move || loop {
+            if let Err(e) = background.replicate(&store) {
+                eprintln!("replication from {} failed: {}", background.primary, e);
+            }
+            background.state.lock().unwrap().connected = false;
+            thread::sleep(RETRY_INTERVAL);
+        });
+        Ok(follower)
+    }
+
+    /// Applies what the primary sends until the connection breaks.
+    fn replicate(&self, store: &KvStore) -> Result<()> {
+        let stream = TcpStream::connect(&self.primary)?;
+        let from = self.state.lock().unwrap().offset;
+        write_frame(&stream, &Request::Replicate { from })?;
+        let mut reader = BufReader::new(&stream);
+        self.state.lock().unwrap().connected = true;
+
+        while let Some(message) = read_frame(&mut reader)? {
+            let (offset, lag) = match message {
+                Replication::Reset { offset } => {
+                    store.clear()?;
+                    store.set_replicated_offset(offset)?;
+                    (offset, None)
+                }
+                Replication::Records {
+                    records,
+                    offset,
+                    pending,
+                } => {
+                    // The offset is saved after the records are applied, so a crash in between
+                    // only has them applied once more, which changes nothing.
+                    store.append_records(&records)?;
+                    store.set_replicated_offset(offset)?;
+                    (offset, Some(pending))
+                }
+                Replication::Heartbeat { offset, pending } => (offset, Some(pending)),
+                Replication::Refused(message) => return Err(KvsError::Server(message)),
+            };
+            let mut state = self.state.lock().unwrap();
+            state.offset = Some(offset);
+            state.lag = lag.unwrap_or(state.lag);
+            state.last_contact = Some(Instant::now());
+        }
+        Err(KvsError::Server(
+            "the primary closed the connection".to_owned(),
+        ))
+    }
+}
+
+/// Streams the log of `store` from `from` on to a follower until the connection breaks.
+pub(crate) fn stream_log(
+    store: &KvStore,
+    from: Option<LogOffset>,
+    mut writer: impl Write,
+) -> Result<()> {
+    let (mut tail, reset) = store.tail(from)?;
+    if reset {
+        let offset = tail.offset();
+        write_frame(&mut writer, &Replication::Reset { offset })?;
+    }
+    let mut last_sent = Instant::now();
+    loop {
+        let records = tail.read(MAX_RECORDS_LEN)?;
+        if !records.is_empty() {
+            let message = Replication::Records {
+                records,
+                offset: tail.offset(),
+                pending: tail.pending()?,
+            };
+            write_frame(&mut writer, &message)?;
+            last_sent = Instant::now();
+        } else {
+            if last_sent.elapsed() >= HEARTBEAT_INTERVAL {
+                let message = Replication::Heartbeat {
+                    offset: tail.offset(),
+                    pending: tail.pending()?,
+                };
+                write_frame(&mut writer, &message)?;
+                last_sent = Instant::now();
+            }
+            thread::sleep(POLL_INTERVAL);
+        }
+    }
+}
@@ -0,0 +1,501 @@
+//! The Redis serialization protocol RESP2, so that Redis clients and tools can use the store.
+//!
+//! Clients send commands as arrays of bulk strings, or as inline lines of words like `telnet` does.
+//! Only the commands which map onto a [`KvsEngine`] are known, see [`execute`].
+
+use crate::replication::{Role, Status};
+use crate::server::{run_on_pool, Connection};
+use crate::thread_pool::ThreadPool;
+use crate::{prefix_range, Expected, KvsEngine, KvsError, Result, WriteBatch};
+use std::collections::BTreeMap;
+use std::io::{self, BufRead, Read, Write};
+use std::ops::Bound;
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Redis refuses longer bulk strings by default, so do we.
+const MAX_BULK_LEN: usize = 512 * 1024 * 1024;
+/// How long an inline command or the line before a bulk string can be at most.
+const MAX_LINE_LEN: u64 = 64 * 1024;
+/// How many arguments a command can have at most.
+const MAX_ARGS: usize = 1024 * 1024;
+/// How many keys `SCAN` looks at if it isn't given a `COUNT`.
+const DEFAULT_SCAN_COUNT: usize = 10;
+/// How many cursors of `SCAN` are kept at most, the oldest ones are dropped first.
+const MAX_CURSORS: usize = 4096;
+
+/// The keys which `SCAN` went up to, by the cursors it returned.
+/// Clients like `redis-cli` read cursors as numbers, so they can't be the keys themselves.
+/// The cursors are shared by all connections, a scan can go on on another one like with Redis.
+static CURSORS: Mutex<Cursors> = Mutex::new(Cursors {
+    keys: BTreeMap::new(),
+    last: 0,
+});
+
+struct Cursors {
+    keys: BTreeMap<u64, Vec<u8>>,
+    /// The cursor returned last, 0 is the start and end of a scan.
+    last: u64,
+}
+
+impl Cursors {
+    /// Returns a new cursor for going on after `key`.
+    fn insert(&mut self, key: Vec<u8>) -> u64 {
+        self.last += 1;
+        self.keys.insert(self.last, key);
+        if self.keys.len() > MAX_CURSORS {
+            self.keys.pop_first();
+        }
+        self.last
+    }
+}
+
+/// What the server answers to a command.
+enum Reply {
+    Simple(&'static str),
+    Error(String),
+    Integer(i64),
+    Bulk(Option<Vec<u8>>),
+    Array(Vec<Reply>),
+}
+
+impl Reply {
+    fn write(&self, writer: &mut impl Write) -> io::Result<()> {
+        match self {
+            Reply::Simple(message) => write!(writer, "+{}\r\n", message),
+            // A line break would end the error early.
+            Reply::Error(message) => write!(writer, "-{}\r\n", message.replace(['\r', '\n'], " ")),
+            Reply::Integer(n) => write!(writer, ":{}\r\n", n),
+            Reply::Bulk(None) => write!(writer, "$-1\r\n"),
+            Reply::Bulk(Some(bytes)) => {
+                write!(writer, "${}\r\n", bytes.len())?;
+                writer.write_all(bytes)?;
+                writer.write_all(b"\r\n")
+            }
+            Reply::Array(replies) => {
+                write!(writer, "*{}\r\n", replies.len())?;
+                for reply in replies {
+                    reply.write(writer)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Serves the commands of a client until it disconnects or sends `QUIT`.
+pub(crate) fn serve<E: KvsEngine>(pool: &impl ThreadPool, connection: Connection<E>) -> Result<()> {
+    let Connection {
+        engine,
+        role,
+        mut reader,
+        mut writer,
+    } = connection;
+    loop {
+        let args = match read_command(&mut reader) {
+            Ok(Some(args)) => args,
+            Ok(None) => return Ok(()),
+            // The rest of the input can't be made sense of anymore.
+            Err(KvsError::InvalidInput(reason)) => {
+                Reply::Error(format!("ERR Protocol error: {}", reason)).write(&mut writer)?;
+                writer.flush()?;
+                return Ok(());
+            }
+            Err(e) => return Err(e),
+        };
+        let quit = args
+            .first()
+            .is_some_and(|name| name.eq_ignore_ascii_case(b"quit"));
+        let (engine, role) = (engine.clone(), role.clone());
+        let reply = match run_on_pool(pool, move || execute(&engine, &role, &args))? {
+            Ok(reply) => reply,
+            Err(e) => Reply::Error(format!("ERR {}", e)),
+        };
+        reply.write(&mut writer)?;
+        writer.flush()?;
+        if quit {
+            return Ok(());
+        }
+    }
+}
+
+/// Reads the arguments of the next command, returns `None` if the client disconnected before it.
+fn read_command(reader: &mut impl BufRead) -> Result<Option<Vec<Vec<u8>>>> {
+    loop {
+        let line = match read_line(reader)? {
+            Some(line) => line,
+            None => return Ok(None),
+        };
+        if let Some(count) = line.strip_prefix(b"*") {
+            let count = parse_len(count, MAX_ARGS)?;
+            let mut args = Vec::new();
+            for _ in 0..count {
+                args.push(read_bulk(reader)?);
+            }
+            if !args.is_empty() {
+                return Ok(Some(args));
+            }
+        } else {
+            let args: Vec<_> = line
+                .split(u8::is_ascii_whitespace)
+                .filter(|word| !word.is_empty())
+                .map(<[u8]>::to_vec)
+                .collect();
+            if !args.is_empty() {
+                return Ok(Some(args));
+            }
+        }
+    }
+}
+
+/// Reads a line without its line break, returns `None` if the input ended before it.
+fn read_line(reader: &mut impl BufRead) -> Result<Option<Vec<u8>>> {
+    let mut line = Vec::new();
+    reader
+        .by_ref()
+        .take(MAX_LINE_LEN)
+        .read_until(b'\n', &mut line)?;
+    if line.is_empty() {
+        return Ok(None);
+    }
+    if line.pop() != Some(b'\n') {
+        return Err(KvsError::InvalidInput(
+            "line is unterminated or too long".to_owned(),
+        ));
+    }
+    if line.last() == Some(&b'\r') {
+        line.pop();
+    }
+    Ok(Some(line))
+}
+
+fn read_bulk(reader: &mut impl BufRead) -> Result<Vec<u8>> {
+    let line = read_line(reader)?.unwrap_or_default();
+    let len = match line.strip_prefix(b"$") {
+        Some(len) => parse_len(len, MAX_BULK_LEN)?,
+        None => {
+            return Err(KvsError::InvalidInput(format!(
+                "expected '$', got '{}'",
+                String::from_utf8_lossy(&line)
+            )))
+        }
+    };
+    // The buffer only grows as the bytes arrive, however long the bulk string is said to be.
+    let mut bulk = Vec::new();
+    reader
+        .by_ref()
+        .take(len as u64 + 2)
+        .read_to_end(&mut bulk)?;
+    if bulk.len() < len + 2 {
+        return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+    }
+    if !bulk.ends_with(b"\r\n") {
+        return Err(KvsError::InvalidInput(
+            "bulk string is longer than said".to_owned(),
+        ));
+    }
+    bulk.truncate(len);
+    Ok(bulk)
+}
+
+fn parse_len(len: &[u8], max: usize) -> Result<usize> {
+    std::str::from_utf8(len)
+        .ok()
+        .and_then(|len| len.parse().ok())
+        .filter(|&len| len <= max)
+        .ok_or_else(|| {
+            KvsError::InvalidInput(format!("invalid length {}", String::from_utf8_lossy(len)))
+        })
+}
+
+/// Runs the command with the arguments `args` on `engine`.
+/// Errors which Redis has a reply of its own for are replies, the others come back as errors.
+fn execute<E: KvsEngine>(engine: &E, role: &Role, args: &[Vec<u8>]) -> Result<Reply> {
+    let name = String::from_utf8_lossy(&args[0]).to_ascii_uppercase();
+    let args = &args[1..];
+    let is_write = matches!(name.as_str(), "SET" | "DEL" | "MSET");
+    if let (true, Role::Follower(_)) = (is_write, role) {
+        return Ok(Reply::Error(
+            "READONLY You can't write against a read only replica.".to_owned(),
+        ));
+    }
+    let arity_ok = match name.as_str() {
+        "GET" => args.len() == 1,
+        "SET" => args.len() >= 2,
+        "DEL" | "EXISTS" | "MGET" => !args.is_empty(),
+        "MSET" => !args.is_empty() && args.len().is_multiple_of(2),
+        "SCAN" => !args.is_empty(),
+        "PING" => args.len() <= 1,
+        _ => true,
+    };
+    if !arity_ok {
+        return Ok(Reply::Error(format!(
+            "ERR wrong number of arguments for '{}' command",
+            name.to_ascii_lowercase()
+        )));
+    }
+
+    let reply = match name.as_str() {
+        "GET" => Reply::Bulk(engine.get(&args[0])?),
+        "SET" => return set(engine, &args[0], &args[1], &args[2..]),
+        "DEL" => {
+            let mut removed = 0;
+            for key in args {
+                match engine.remove(key) {
+                    Ok(()) => removed += 1,
+                    Err(KvsError::KeyNotFound) => {}
+                    Err(e) => return Err(e),
+                }
+            }
+            Reply::Integer(removed)
+        }
+        "EXISTS" => {
+            let mut found = 0;
+            for key in args {
+                if engine.get_versioned(key)?.is_some() {
+                    found += 1;
+                }
+            }
+            Reply::Integer(found)
+        }
+        "MGET" => Reply::Array(
+            args.iter()
+                .map(|key| Ok(Reply::Bulk(engine.get(key)?)))
+                .collect::<Result<_>>()?,
+        ),
+        "MSET" => {
+            let mut batch = WriteBatch::new();
+            for pair in args.chunks(2) {
+                batch.set(&pair[0], &pair[1]);
+            }
+            engine.apply(&batch)?;
+            Reply::Simple("OK")
+        }
+        "SCAN" => return scan(engine, args),
+        "PING" => match args.first() {
+            Some(message) => Reply::Bulk(Some(message.clone())),
+            None => Reply::Simple("PONG"),
+        },
+        "INFO" => Reply::Bulk(Some(info(engine, role)?.into_bytes())),
+        // Sent by `redis-cli` when it starts, it copes with knowing nothing about the commands.
+        "COMMAND" => Reply::Array(Vec::new()),
+        "QUIT" => Reply::Simple("OK"),
+        _ => Reply::Error(format!(
+            "ERR unknown command '{}'",
+            name.to_ascii_lowercase()
+        )),
+    };
+    Ok(reply)
+}
+
+/// `SET key value [EX seconds | PX milliseconds] [NX | XX]`
+fn set(engine: &impl KvsEngine, key: &[u8], value: &[u8], options: &[Vec<u8>]) -> Result<Reply> {
+    let mut ttl = None;
+    let mut condition = None;
+    let mut options = options.iter();
+    while let Some(option) = options.next() {
+        let option = String::from_utf8_lossy(option).to_ascii_uppercase();
+        match option.as_str() {
+            "EX" | "PX" if ttl.is_none() => {
+                let amount = match options.next().and_then(|amount| parse_number(amount)) {
+                    Some(amount) if amount > 0 => amount,
+                    _ => {
+                        return Ok(Reply::Error(
+                            "ERR invalid expire time in 'set' command".to_owned(),
+                        ))
+                    }
+                };
+                ttl = Some(if option == "EX" {
+                    Duration::from_secs(amount)
+                } else {
+                    Duration::from_millis(amount)
+                });
+            }
+            "NX" | "XX" if condition.is_none() => condition = Some(option),
+            _ => return Ok(Reply::Error("ERR syntax error".to_owned())),
+        }
+    }
+
+    let written = match condition.as_deref() {
+        Some("NX") => match engine.write_if(key, Expected::Version(0), Some(value), ttl) {
+            Ok(_) => true,
+            Err(KvsError::Conflict(_)) => false,
+            Err(e) => return Err(e),
+        },
+        // Only keys which are there, with whatever version they have by the time it is written.
+        Some(_) => loop {
+            let version = match engine.get_versioned(key)? {
+                Some((_, version)) => version,
+                None => break false,
+            };
+            match engine.write_if(key, Expected::Version(version), Some(value), ttl) {
+                Ok(_) => break true,
+                Err(KvsError::Conflict(_)) => continue,
+                Err(e) => return Err(e),
+            }
+        },
+        None => {
+            match ttl {
+                Some(ttl) => engine.set_with_ttl(key, value, ttl)?,
+                None => engine.set(key, value)?,
+            };
+            true
+        }
+    };
+    Ok(if written {
+        Reply::Simple("OK")
+    } else {
+        Reply::Bulk(None)
+    })
+}
+
+/// `SCAN cursor [MATCH pattern] [COUNT count]`
+///
+/// The cursor stands for the last key which was looked at, the scan goes on after it.
+/// So every key which is there all along is returned once, even if others are written meanwhile.
+fn scan(engine: &impl KvsEngine, args: &[Vec<u8>]) -> Result<Reply> {
+    let after = match parse_number(&args[0]) {
+        Some(0) => None,
+        Some(cursor) => match CURSORS.lock().unwrap().keys.get(&cursor) {
+            Some(key) => Some(key.clone()),
+            None => return Ok(Reply::Error("ERR invalid cursor".to_owned())),
+        },
+        None => return Ok(Reply::Error("ERR invalid cursor".to_owned())),
+    };
+    let mut pattern = None;
+    let mut count = DEFAULT_SCAN_COUNT;
+    let mut options = args[1..].iter();
+    while let Some(option) = options.next() {
+        match (
+            String::from_utf8_lossy(option)
+                .to_ascii_uppercase()
+                .as_str(),
+            options.next(),
+        ) {
+            ("MATCH", Some(matching)) => pattern = Some(matching.as_slice()),
+            ("COUNT", Some(n)) => match parse_number(n) {
+                Some(n) if n > 0 => count = n as usize,
+                _ => return Ok(Reply::Error("ERR syntax error".to_owned())),
+            },
+            _ => return Ok(Reply::Error("ERR syntax error".to_owned())),
+        }
+    }
+
+    // Only keys with the part of the pattern before its first wildcard can match.
+    let prefix = pattern.map_or(&[][..], |pattern| {
+        let literal = pattern
+            .iter()
+            .position(|c| b"*?[\\".contains(c))
+            .unwrap_or(pattern.len());
+        &pattern[..literal]
+    });
+    let mut range = prefix_range(prefix);
+    if let Some(after) = after.filter(|after| after.as_slice() >= prefix) {
+        range.0 = Bound::Excluded(after);
+    }
+    let mut looked_at = Vec::new();
+    for key in engine.scan_keys(range).take(count + 1) {
+        looked_at.push(key?);
+    }
+    let next = if looked_at.len() > count {
+        looked_at.pop();
+        CURSORS
+            .lock()
+            .unwrap()
+            .insert(looked_at.last().unwrap().clone())
+    } else {
+        0
+    };
+    let keys = looked_at
+        .into_iter()
+        .filter(|key| pattern.is_none_or(|pattern| glob_matches(pattern, key)))
+        .map(|key| Reply::Bulk(Some(key)))
+        .collect();
+    Ok(Reply::Array(vec![
+        Reply::Bulk(Some(next.to_string().into_bytes())),
+        Reply::Array(keys),
+    ]))
+}
+
+/// The sections `Server`, `Replication` and `Keyspace` of `INFO`, as far as they apply.
+fn info(engine: &impl KvsEngine, role: &Role) -> Result<String> {
+    let mut info = format!(
+        "# Server\r\nredis_version:2.8.0\r\nkvs_version:{}\r\n\r\n# Replication\r\n",
+        env!("CARGO_PKG_VERSION")
+    );
+    match role.status() {
+        Status::Standalone => info.push_str("role:master\r\nconnected_slaves:0\r\n"),
+        Status::Primary { followers, .. } => info.push_str(&format!(
+            "role:master\r\nconnected_slaves:{}\r\n",
+            followers
+        )),
+        Status::Follower {
+            primary,
+            connected,
+            lag,
+            ..
+        } => {
+            let (host, port) = primary.rsplit_once(':').unwrap_or((&primary, ""));
+            info.push_str(&format!(
+                "role:slave\r\nmaster_host:{}\r\nmaster_port:{}\r\nmaster_link_status:{}\r\nkvs_lag_bytes:{}\r\n",
+                host,
+                port,
+                if connected { "up" } else { "down" },
+                lag
+            ));
+        }
+    }
+    let counts = engine.key_counts()?;
+    info.push_str(&format!(
+        "\r\n# Keyspace\r\ndb0:keys={},expires={}\r\n",
+        counts.keys, counts.expiring
+    ));
+    Ok(info)
+}
+
+fn parse_number(bytes: &[u8]) -> Option<u64> {
+    std::str::from_utf8(bytes).ok()?.parse().ok()
+}
+
+/// Whether `key` matches the glob-style `pattern` of Redis:
+/// `*` is any bytes, `?` any one byte, `[abc]`, `[^abc]` and `[a-z]` one of those bytes,
+/// and `\` takes the next byte as it is.
+fn glob_matches(pattern: &[u8], key: &[u8]) -> bool {
+    match pattern.split_first() {
+        None => key.is_empty(),
+        Some((b'*', rest)) => (0..=key.len()).any(|skip| glob_matches(rest, &key[skip..])),
+        Some((b'?', rest)) => !key.is_empty() && glob_matches(rest, &key[1..]),
+        Some((b'[', rest)) => {
+            let (negated, rest) = match rest.split_first() {
+                Some((b'^', rest)) => (true, rest),
+                _ => (false, rest),
+            };
+            let end = match rest.iter().position(|&c| c == b']') {
+                Some(end) => end,
+                // Without an end it is no class.
+                None => return key.first() == Some(&b'[') && glob_matches(rest, &key[1..]),
+            };
+            let class = &rest[..end];
+            let c = match key.first() {
+                Some(&c) => c,
+                None => return false,
+            };
+            let mut found = false;
+            let mut i = 0;
+            while i < class.len() {
+                if i + 2 < class.len() && class[i + 1] == b'-' {
+                    found |= class[i] <= c && c <= class[i + 2];
+                    i += 3;
+                } else {
+                    found |= class[i] == c;
+                    i += 1;
+                }
+            }
+            found != negated && glob_matches(&rest[end + 1..], &key[1..])
+        }
+        Some((b'\\', rest)) if !rest.is_empty() => {
+            key.first() == Some(&rest[0]) && glob_matches(&rest[1..], &key[1..])
+        }
+        Some((&c, rest)) => key.first() == Some(&c) && glob_matches(rest, &key[1..]),
+    }
+}
@@ -0,0 +1,281 @@
+use crate::protocol::{read_frame, write_frame, Replication, Request, Response};
+use crate::replication::{stream_log, Follower, Role};
+use crate::thread_pool::ThreadPool;
+use crate::{http, resp, KvStore, KvsEngine, KvsError, LogOffset, Result, Watch};
+use std::io::{self, BufReader, BufWriter};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{mpsc, Arc};
+use std::thread;
+use std::time::Duration;
+
+/// How long a watch waits for a change before it looks whether its client is still there.
+pub(crate) const WATCH_CHECK_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Serves a [`KvsEngine`] to [`KvsClient`](crate::KvsClient)s over TCP.
+/// Every connection has a thread of its own which reads the requests of its client
+/// and writes the responses, with a clone of the engine. Only the work on the engine
+/// is a job of the thread pool, so the pool bounds how many requests are served at once,
+/// while clients which are idle or slow to send only hold up their own connection threads.
+pub struct KvsServer<E: KvsEngine, P: ThreadPool> {
+    engine: E,
+    pool: Arc<P>,
+    role: Role,
+    protocol: Protocol,
+}
+
+/// How clients talk to a [`KvsServer`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Protocol {
+    /// The protocol of [`KvsClient`](crate::KvsClient), which can do everything.
+    Kvs,
+    /// RESP2, the protocol of Redis, with the commands `GET`, `SET` with `EX`, `PX`, `NX` and `XX`,
+    /// `DEL`, `EXISTS`, `SCAN`, `MGET`, `MSET`, `PING` and `INFO`.
+    Resp,
+    /// HTTP/1.1 with JSON bodies, `GET`, `PUT` and `DELETE` on `/keys/{key}`
+    /// and `GET` on `/keys?prefix=...` to list the entries with a prefix.
+    Http,
+}
+
+impl FromStr for Protocol {
+    type Err = KvsError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "kvs" => Ok(Protocol::Kvs),
+            "resp" => Ok(Protocol::Resp),
+            "http" => Ok(Protocol::Http),
+            _ => Err(KvsError::InvalidInput(format!(
+                "unknown protocol {}, use kvs, resp or http",
+                s
+            ))),
+        }
+    }
+}
+
+impl<E: KvsEngine, P: ThreadPool> KvsServer<E, P> {
+    /// Returns a server which will serve `engine` on the threads of `pool`.
+    pub fn new(engine: E, pool: P) -> Self {
+        Self {
+            engine,
+            pool: Arc::new(pool),
+            role: Role::Standalone,
+            protocol: Protocol::Kvs,
+        }
+    }
+
+    /// Lets the clients talk `protocol` instead of the protocol of [`KvsClient`](crate::KvsClient).
+    /// Followers only replicate with the protocol of [`KvsClient`](crate::KvsClient),
+    /// so they can't follow a server which talks another one.
+    pub fn with_protocol(mut self, protocol: Protocol) -> Self {
+        self.protocol = protocol;
+        self
+    }
+}
+
+impl<E: KvsEngine, P: ThreadPool + Send + Sync + 'static> KvsServer<E, P> {
+    /// Listens on `addr` and serves every client which connects.
+    /// A failing connection is reported on stderr and doesn't stop the server.
+    pub fn run(&self, addr: impl ToSocketAddrs) -> Result<()> {
+        let listener = TcpListener::bind(addr)?;
+        for stream in listener.incoming() {
+            let engine = self.engine.clone();
+            let role = self.role.clone();
+            let pool = Arc::clone(&self.pool);
+            let serve: Serve<E, P> = match self.protocol {
+                Protocol::Kvs => serve,
+                Protocol::Resp => resp::serve,
+                Protocol::Http => http::serve,
+            };
+            thread::spawn(move || {
+                let result = stream
+                    .map_err(KvsError::from)
+                    .and_then(|stream| Connection::new(engine, role, stream))
+                    .and_then(|connection| serve(&*pool, connection));
+                if let Err(e) = result {
+                    eprintln!("connection failed: {}", e);
+                }
+            });
+        }
+        Ok(())
+    }
+}
+
+impl<P: ThreadPool> KvsServer<KvStore, P> {
+    /// Returns a server like [`KvsServer::new`] whose log followers can replicate.
+    /// Every follower is served on a thread of its own as long as it is connected.
+    pub fn primary(store: KvStore, pool: P) -> Self {
+        Self {
+            pool: Arc::new(pool),
+            role: Role::Primary {
+                store: store.clone(),
+                followers: Arc::new(AtomicU64::new(0)),
+            },
+            engine: store,
+            protocol: Protocol::Kvs,
+        }
+    }
+
+    /// Returns a server which serves `store` read-only while it applies the log
+    /// of the primary listening on `primary` to it in the background.
+    /// It goes on from where `store` followed that primary before,
+    /// and connects again whenever the connection breaks.
+    pub fn follower(store: KvStore, pool: P, primary: impl Into<String>) -> Result<Self> {
+        let follower = Follower::start(store.clone(), primary.into())?;
+        Ok(Self {
+            engine: store,
+            pool: Arc::new(pool),
+            role: Role::Follower(follower),
+            protocol: Protocol::Kvs,
+        })
+    }
+}
+
+/// A connection of a client along with what serving its requests needs.
+pub(crate) struct Connection<E> {
+    pub(crate) engine: E,
+    pub(crate) role: Role,
+    pub(crate) reader: BufReader<TcpStream>,
+    pub(crate) writer: BufWriter<TcpStream>,
+}
+
+/// Serves the requests of a connection until the client disconnects, with the pool for the engine.
+type Serve<E, P> = fn(&P, Connection<E>) -> Result<()>;
+
+impl<E: KvsEngine> Connection<E> {
+    fn new(engine: E, role: Role, stream: TcpStream) -> Result<Self> {
+        Ok(Self {
+            engine,
+            role,
+            reader: BufReader::new(stream.try_clone()?),
+            writer: BufWriter::new(stream),
+        })
+    }
+}
+
+/// Runs `job` on the pool and waits for what it returns.
+pub(crate) fn run_on_pool<T: Send + 'static>(
+    pool: &impl ThreadPool,
+    job: impl FnOnce() -> T + Send + 'static,
+) -> Result<T> {
+    let (sender, receiver) = mpsc::channel();
+    pool.spawn(move || {
+        let _ = sender.send(job());
+    });
+    receiver
+        .recv()
+        .map_err(|_| KvsError::Server("serving a request panicked".to_owned()))
+}
+
+/// Serves the requests of a client of the protocol of [`KvsClient`](crate::KvsClient).
+fn serve<E: KvsEngine>(pool: &impl ThreadPool, connection: Connection<E>) -> Result<()> {
+    let Connection {
+        engine,
+        role,
+        mut reader,
+        mut writer,
+    } = connection;
+    let handle_on_pool = |request| {
+        let engine = engine.clone();
+        run_on_pool(pool, move || handle(&engine, request))
+    };
+    loop {
+        let request = match read_frame(&mut reader)? {
+            Some(request) => request,
+            None => return Ok(()),
+        };
+        let response = match request {
+            // The connection streams the log from now on.
+            Request::Replicate { from } => return replicate(&role, from, writer),
+            Request::Watch { prefix } => return watch(&engine, &prefix, writer),
+            Request::Status => Response::Status(role.status()),
+            request if request.is_write() => match &role {
+                Role::Follower(follower) => Response::Err(format!(
+                    "this server is a read-only follower of {}",
+                    follower.primary
+                )),
+                _ => handle_on_pool(request)?,
+            },
+            request => handle_on_pool(request)?,
+        };
+        write_frame(&mut writer, &response)?;
+    }
+}
+
+/// Streams the log to a follower on the thread of its connection, if the server is a primary.
+fn replicate(role: &Role, from: Option<LogOffset>, mut writer: BufWriter<TcpStream>) -> Result<()> {
+    match role {
+        Role::Primary { store, followers } => {
+            followers.fetch_add(1, Ordering::SeqCst);
+            let result = stream_log(store, from, writer);
+            followers.fetch_sub(1, Ordering::SeqCst);
+            result
+        }
+        _ => {
+            let refused = Replication::Refused("this server is no primary".to_owned());
+            write_frame(&mut writer, &refused)
+        }
+    }
+}
+
+/// Sends the changes of the keys with `prefix` to the client until it disconnects,
+/// on the thread of its connection like [`replicate`].
+fn watch(engine: &impl KvsEngine, prefix: &[u8], mut writer: BufWriter<TcpStream>) -> Result<()> {
+    let watch = engine.watch(prefix);
+    write_frame(&mut writer, &Response::Ok)?;
+    send_events(watch, writer)
+}
+
+fn send_events(watch: Watch, mut writer: BufWriter<TcpStream>) -> Result<()> {
+    // The client sends nothing anymore, so reading only finds out whether it is gone.
+    writer
+        .get_ref()
+        .set_read_timeout(Some(Duration::from_millis(1)))?;
+    loop {
+        match watch.next_timeout(WATCH_CHECK_INTERVAL) {
+            Some(event) => write_frame(&mut writer, &Response::Event(event))?,
+            None => match writer.get_ref().peek(&mut [0]) {
+                Ok(0) => return Ok(()),
+                Ok(_) => {}
+                Err(e)
+                    if matches!(
+                        e.kind(),
+                        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                    ) => {}
+                Err(e) => return Err(e.into()),
+            },
+        }
+    }
+}
+
+fn handle(engine: &impl KvsEngine, request: Request) -> Response {
+    let result = match request {
+        Request::Get { key } => engine.get(key).map(Response::Value),
+        Request::Set { key, value, ttl } => match ttl {
+            Some(ttl) => engine.set_with_ttl(key, value, ttl),
+            None => engine.set(key, value),
+        }
+        .map(Response::Version),
+        Request::Remove { key } => engine.remove(key).map(|()| Response::Ok),
+        Request::GetVersioned { key } => engine.get_versioned(key).map(Response::Versioned),
+        Request::WriteIf {
+            key,
+            expected,
+            new,
+            ttl,
+        } => engine
+            .write_if(key, expected, new.as_deref(), ttl)
+            .map(Response::Version),
+        Request::Batch { batch } => engine.apply(&batch).map(|()| Response::Ok),
+        Request::Scan { range, limit } => engine
+            .scan(range)
+            .take(limit.map_or(usize::MAX, |limit| limit as usize))
+            .collect::<Result<_>>()
+            .map(Response::Entries),
+        Request::Replicate { .. } | Request::Status | Request::Watch { .. } => {
+            unreachable!("requests for the connection are served before")
+        }
+    };
+    Response::from_result(result)
+}
@@ -0,0 +1,23 @@
+//! Thread pools to run the connections of the server on.
+
+use crate::Result;
+
+mod naive;
+mod shared_queue;
+
+pub use self::naive::NaiveThreadPool;
+pub use self::shared_queue::SharedQueueThreadPool;
+
+/// Runs jobs on other threads.
+pub trait ThreadPool {
+    /// Creates a pool which runs up to `threads` jobs at the same time.
+    fn new(threads: u32) -> Result<Self>
+    where
+        Self: Sized;
+
+    /// Runs `job` on one of the threads of the pool.
+    /// A panicking job must not take the pool down with it.
+    fn spawn<F>(&self, job: F)
+    where
+        F: FnOnce() + Send + 'static;
+}
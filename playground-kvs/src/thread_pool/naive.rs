@@ -0,0 +1,20 @@
+use super::ThreadPool;
+use crate::Result;
+use std::thread;
+
+/// Not really a pool, every job gets a new thread.
+pub struct NaiveThreadPool;
+
+impl ThreadPool for NaiveThreadPool {
+    /// There is no limit to the number of threads, so `threads` is ignored.
+    fn new(_threads: u32) -> Result<Self> {
+        Ok(NaiveThreadPool)
+    }
+
+    fn spawn<F>(&self, job: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        thread::spawn(job);
+    }
+}
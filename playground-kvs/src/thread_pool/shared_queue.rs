@@ -0,0 +1,66 @@
+use super::ThreadPool;
+use crate::Result;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+/// A fixed number of threads which take their jobs from one queue.
+/// When a job panics its thread is replaced by a new one.
+/// Dropping the pool lets the threads finish the queued jobs and then exit.
+pub struct SharedQueueThreadPool {
+    sender: Sender<Job>,
+}
+
+impl ThreadPool for SharedQueueThreadPool {
+    fn new(threads: u32) -> Result<Self> {
+        let (sender, receiver) = mpsc::channel::<Job>();
+        let receiver = Arc::new(Mutex::new(receiver));
+        for _ in 0..threads {
+            let worker = Worker(Arc::clone(&receiver));
+            thread::Builder::new().spawn(move || worker.run())?;
+        }
+        Ok(Self { sender })
+    }
+
+    fn spawn<F>(&self, job: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.sender
+            .send(Box::new(job))
+            .expect("the pool has no threads left");
+    }
+}
+
+/// Takes jobs from the queue until the pool is dropped.
+struct Worker(Arc<Mutex<Receiver<Job>>>);
+
+impl Worker {
+    fn run(&self) {
+        loop {
+            // The lock is released before the job runs, so other workers can take jobs meanwhile.
+            let job = match self.0.lock() {
+                Ok(receiver) => receiver.recv(),
+                Err(_) => return,
+            };
+            match job {
+                Ok(job) => job(),
+                Err(_) => return,
+            }
+        }
+    }
+}
+
+impl Drop for Worker {
+    /// Only gets dropped by a panicking job or when the pool is gone.
+    fn drop(&mut self) {
+        if thread::panicking() {
+            let worker = Worker(Arc::clone(&self.0));
+            if let Err(e) = thread::Builder::new().spawn(move || worker.run()) {
+                eprintln!("couldn't replace a panicked thread of the pool: {}", e);
+            }
+        }
+    }
+}
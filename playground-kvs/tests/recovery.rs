@@ -0,0 +1,305 @@
+use kvs::{KvStore, KvStoreOptions, KvsEngine, KvsError, Result, SyncPolicy, WriteBatch};
+use std::fs;
+use std::path::Path;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+type Entries = Vec<(Vec<u8>, Vec<u8>)>;
+
+/// A log written by a few sets, removals and a batch,
+/// along with the length of the log and the entries in the store after each write.
+struct History {
+    log: Vec<u8>,
+    states: Vec<(u64, Entries)>,
+}
+
+fn entries(store: &KvStore) -> Result<Entries> {
+    store.scan(..).collect()
+}
+
+fn write_history(dir: &Path) -> Result<History> {
+    let log_path = dir.join("1.log");
+    let store = KvStore::open(dir)?;
+    let mut states = vec![(fs::metadata(&log_path)?.len(), entries(&store)?)];
+    let mut record = |store: &KvStore| -> Result<()> {
+        states.push((fs::metadata(&log_path)?.len(), entries(store)?));
+        Ok(())
+    };
+
+    store.set("key1", "value1")?;
+    record(&store)?;
+    store.set("key2", "value2")?;
+    record(&store)?;
+    store.set("key1", "a longer value which replaces value1")?;
+    record(&store)?;
+    store.remove("key2")?;
+    record(&store)?;
+    let mut batch = WriteBatch::new();
+    batch
+        .set("key3", "value3")
+        .remove("key1")
+        .set("key4", [0, 255]);
+    store.apply(&batch)?;
+    record(&store)?;
+    store.set("key5", "value5")?;
+    record(&store)?;
+    drop(store);
+
+    Ok(History {
+        log: fs::read(&log_path)?,
+        states,
+    })
+}
+
+/// Opens a store with `log` as its only log.
+fn open_with_log(dir: &Path, log: &[u8]) -> Result<KvStore> {
+    fs::write(dir.join("1.log"), log)?;
+    KvStore::open(dir)
+}
+
+// Cutting the log off anywhere should lose the torn write and nothing before it
+#[test]
+fn truncated_log() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let history = write_history(temp_dir.path())?;
+
+    let dir = TempDir::new()?;
+    for len in history.states[0].0..=history.log.len() as u64 {
+        let store = open_with_log(dir.path(), &history.log[..len as usize])?;
+        let expected = history
+            .states
+            .iter()
+            .rev()
+            .find(|(state_len, _)| *state_len <= len)
+            .map(|(_, entries)| entries)
+            .unwrap();
+        assert_eq!(
+            &entries(&store)?,
+            expected,
+            "log cut off after {} bytes",
+            len
+        );
+
+        // The torn write must be gone from the log, so later writes are read back.
+        store.set("later", "write")?;
+        drop(store);
+        let store = KvStore::open(dir.path())?;
+        let mut expected = expected.clone();
+        expected.push((b"later".to_vec(), b"write".to_vec()));
+        expected.sort();
+        assert_eq!(
+            entries(&store)?,
+            expected,
+            "log cut off after {} bytes",
+            len
+        );
+    }
+
+    Ok(())
+}
+
+// Flipping a bit should fail the open. Only damage in the last write looks like a torn write,
+// which loses that write but never one before it.
+#[test]
+fn bit_flipped_log() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let history = write_history(temp_dir.path())?;
+
+    let dir = TempDir::new()?;
+    for pos in 0..history.log.len() {
+        // The log and the entries before the last write, if the byte belongs to it.
+        let before = &history.states[history.states.len() - 2];
+        let in_last_write = before.0 <= pos as u64;
+        for bit in 0..8 {
+            let mut log = history.log.clone();
+            log[pos] ^= 1 << bit;
+            match open_with_log(dir.path(), &log) {
+                Ok(store) => {
+                    assert!(
+                        in_last_write,
+                        "bit {} of byte {} flipped, the store opened",
+                        bit, pos
+                    );
+                    let (len, expected) = before;
+                    assert_eq!(
+                        &entries(&store)?,
+                        expected,
+                        "bit {} of byte {} flipped",
+                        bit,
+                        pos
+                    );
+                    assert!(
+                        fs::metadata(dir.path().join("1.log"))?.len() >= *len,
+                        "bit {} of byte {} flipped, the log was cut off before it",
+                        bit,
+                        pos
+                    );
+                }
+                Err(KvsError::CorruptLog(_)) | Err(KvsError::UnsupportedVersion(_)) => {}
+                Err(e) => panic!("bit {} of byte {} flipped, got {}", bit, pos, e),
+            }
+        }
+    }
+
+    Ok(())
+}
+
+// A flipped bit in the length of a record must fail the open instead of cutting off the rest
+#[test]
+fn damaged_record_length() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let history = write_history(temp_dir.path())?;
+
+    // The third byte of the length of the first set, so that it reaches beyond the end.
+    let mut log = history.log.clone();
+    log[history.states[0].0 as usize + 2] ^= 1;
+    match open_with_log(temp_dir.path(), &log) {
+        Err(KvsError::CorruptLog(_)) => {}
+        Err(e) => panic!("expected a corrupt log, got {}", e),
+        Ok(_) => panic!("expected a corrupt log, the store opened"),
+    }
+    assert_eq!(fs::read(temp_dir.path().join("1.log"))?, log);
+    assert!(!KvStore::check(temp_dir.path())?.is_ok());
+
+    Ok(())
+}
+
+// Only the last generation can end in a torn write, the others are never cut off
+#[test]
+fn torn_earlier_generation() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let history = write_history(temp_dir.path())?;
+    let torn = &history.log[..history.log.len() - 1];
+    fs::write(temp_dir.path().join("1.log"), torn)?;
+    fs::write(temp_dir.path().join("2.log"), &history.log[..8])?;
+
+    match KvStore::open(temp_dir.path()) {
+        Err(KvsError::CorruptLog(_)) => {}
+        Err(e) => panic!("expected a corrupt log, got {}", e),
+        Ok(_) => panic!("expected a corrupt log, the store opened"),
+    }
+    assert_eq!(fs::read(temp_dir.path().join("1.log"))?, torn);
+
+    Ok(())
+}
+
+// A damaged record before the end of the log is no torn write and must not be cut off silently
+#[test]
+fn damaged_record_before_the_end() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let history = write_history(temp_dir.path())?;
+
+    // The last byte of the value of the first set.
+    let mut log = history.log.clone();
+    log[history.states[1].0 as usize - 1] ^= 1;
+    match open_with_log(temp_dir.path(), &log) {
+        Err(KvsError::CorruptLog(_)) => {}
+        Err(e) => panic!("expected a corrupt log, got {}", e),
+        Ok(_) => panic!("expected a corrupt log, the store opened"),
+    }
+
+    Ok(())
+}
+
+// Writes should be read back after reopening the store with every sync policy
+#[test]
+fn sync_policies() -> Result<()> {
+    for sync in [
+        SyncPolicy::Always,
+        SyncPolicy::Interval(Duration::from_millis(1)),
+        SyncPolicy::Never,
+    ] {
+        let temp_dir = TempDir::new()?;
+        let options = KvStoreOptions {
+            sync,
+            ..KvStoreOptions::default()
+        };
+        let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+        store.set("key1", "value1")?;
+        let mut batch = WriteBatch::new();
+        batch.set("key2", "value2").set("key3", "value3");
+        store.apply(&batch)?;
+        thread::sleep(Duration::from_millis(5));
+        store.remove("key3")?;
+        drop(store);
+
+        let store = KvStore::open_with_options(temp_dir.path(), options)?;
+        assert_eq!(store.get_string("key1")?, Some("value1".to_owned()));
+        assert_eq!(store.get_string("key2")?, Some("value2".to_owned()));
+        assert_eq!(store.get("key3")?, None);
+    }
+
+    Ok(())
+}
+
+// `KvStore::check` should find every damaged record and `KvStore::repair` should keep all the others
+#[test]
+fn check_and_repair() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let history = write_history(temp_dir.path())?;
+    let report = KvStore::check(temp_dir.path())?;
+    assert!(report.is_ok(), "{:?}", report.problems);
+    assert_eq!(report.keys, 3);
+
+    // Damage the first record and the last one of the batch, the batch is dropped as a whole.
+    let mut log = history.log.clone();
+    log[history.states[1].0 as usize - 1] ^= 1;
+    log[history.states[5].0 as usize - 1] ^= 1;
+    fs::write(temp_dir.path().join("1.log"), &log)?;
+    assert!(matches!(
+        KvStore::open(temp_dir.path()),
+        Err(KvsError::CorruptLog(_))
+    ));
+
+    let report = KvStore::check(temp_dir.path())?;
+    let positions: Vec<_> = report.problems.iter().map(|problem| problem.pos).collect();
+    assert_eq!(positions.len(), 3, "{:?}", report.problems);
+    assert_eq!(positions[0], history.states[0].0);
+    assert!(positions[1] > history.states[4].0 && positions[1] < history.states[5].0);
+    assert_eq!(positions[2], history.states[4].0);
+    assert!(report.problems[2].description.contains("dropped"));
+
+    KvStore::repair(temp_dir.path())?;
+    assert!(KvStore::check(temp_dir.path())?.is_ok());
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(
+        entries(&store)?,
+        vec![
+            (
+                b"key1".to_vec(),
+                b"a longer value which replaces value1".to_vec()
+            ),
+            (b"key5".to_vec(), b"value5".to_vec()),
+        ]
+    );
+
+    Ok(())
+}
+
+// A damaged header should be reported, but repairing should keep the intact records after it
+#[test]
+fn repair_damaged_header() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let history = write_history(temp_dir.path())?;
+    let (_, expected) = history.states.last().unwrap();
+
+    // The magic bytes, the log version and the last version.
+    for pos in [0, 4, 8] {
+        let dir = TempDir::new()?;
+        let mut log = history.log.clone();
+        log[pos] ^= 1;
+        assert!(open_with_log(dir.path(), &log).is_err());
+
+        let report = KvStore::check(dir.path())?;
+        assert_eq!(report.problems.len(), 1, "{:?}", report.problems);
+        assert_eq!(report.problems[0].pos, 0);
+        assert_eq!(report.keys, expected.len());
+
+        KvStore::repair(dir.path())?;
+        assert!(KvStore::check(dir.path())?.is_ok());
+        let store = KvStore::open(dir.path())?;
+        assert_eq!(&entries(&store)?, expected, "byte {} damaged", pos);
+    }
+    Ok(())
+}
@@ -0,0 +1,956 @@
+use assert_cmd::prelude::*;
+use kvs::{Event, KvsClient, KvsError, Status, WriteBatch};
+use predicates::ord::eq;
+use predicates::str::{contains, is_empty, PredicateStrExt};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::process::{Child, Command, Stdio};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+
+/// A `kvs-server` process which gets killed when this is dropped.
+struct Server {
+    child: Child,
+    addr: String,
+}
+
+impl Server {
+    fn start(dir: &TempDir, args: &[&str]) -> Self {
+        let addr = free_addr();
+        let child = Command::cargo_bin("kvs-server")
+            .unwrap()
+            .args(["--addr", &addr])
+            .args(args)
+            .current_dir(dir)
+            .spawn()
+            .unwrap();
+
+        let started = Instant::now();
+        while TcpStream::connect(&addr).is_err() {
+            assert!(
+                started.elapsed() < Duration::from_secs(10),
+                "server didn't start"
+            );
+            thread::sleep(Duration::from_millis(20));
+        }
+        Self { child, addr }
+    }
+
+    fn client(&self) -> Command {
+        let mut command = Command::cargo_bin("kvs-client").unwrap();
+        command.args(["--addr", &self.addr]);
+        command
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+/// The protocol tests run against every way the server can serve its connections.
+fn modes() -> Vec<&'static [&'static str]> {
+    #[allow(unused_mut)]
+    let mut modes: Vec<&'static [&'static str]> = vec![&[]];
+    #[cfg(feature = "async")]
+    modes.push(&["--async"]);
+    modes
+}
+
+fn free_addr() -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    listener.local_addr().unwrap().to_string()
+}
+
+/// Waits until `done` holds, which a follower should get to soon.
+fn eventually(mut done: impl FnMut() -> kvs::Result<bool>) -> kvs::Result<()> {
+    let started = Instant::now();
+    while !done()? {
+        assert!(
+            started.elapsed() < Duration::from_secs(10),
+            "follower didn't catch up"
+        );
+        thread::sleep(Duration::from_millis(20));
+    }
+    Ok(())
+}
+
+/// A connection to a server which talks RESP, like a Redis client.
+struct RespClient {
+    reader: BufReader<TcpStream>,
+}
+
+impl RespClient {
+    fn connect(server: &Server) -> Self {
+        let stream = TcpStream::connect(&server.addr).unwrap();
+        Self {
+            reader: BufReader::new(stream),
+        }
+    }
+
+    /// Sends the command and returns the reply as it was sent.
+    fn send(&mut self, args: &[&str]) -> String {
+        let mut command = format!("*{}\r\n", args.len());
+        for arg in args {
+            command += &format!("${}\r\n{}\r\n", arg.len(), arg);
+        }
+        self.send_raw(&command)
+    }
+
+    fn send_raw(&mut self, command: &str) -> String {
+        self.reader.get_mut().write_all(command.as_bytes()).unwrap();
+        let mut reply = String::new();
+        self.read_reply(&mut reply);
+        reply
+    }
+
+    fn read_reply(&mut self, reply: &mut String) {
+        let start = reply.len();
+        self.reader.read_line(reply).unwrap();
+        let line = reply[start..].trim_end().to_owned();
+        let len: i64 = line[1..].parse().unwrap_or(-1);
+        match &line[..1] {
+            "$" if len >= 0 => {
+                let mut bulk = vec![0; len as usize + 2];
+                self.reader.read_exact(&mut bulk).unwrap();
+                reply.push_str(std::str::from_utf8(&bulk).unwrap());
+            }
+            "*" => {
+                for _ in 0..len.max(0) {
+                    self.read_reply(reply);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Sends `method` on `path` with `body` to a server which talks HTTP,
+/// returns the status code and the body of the response.
+fn http(server: &Server, method: &str, path: &str, body: &str) -> (u16, String) {
+    let mut stream = TcpStream::connect(&server.addr).unwrap();
+    write!(
+        stream,
+        "{} {} HTTP/1.1\r\nHost: kvs\r\nConnection: close\r\nContent-Length: {}\r\n\r\n{}",
+        method,
+        path,
+        body.len(),
+        body
+    )
+    .unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    let (head, body) = response.split_once("\r\n\r\n").unwrap();
+    let status = head.split(' ').nth(1).unwrap().parse().unwrap();
+    (status, body.to_owned())
+}
+
+#[test]
+fn client_cli_invalid_args() {
+    Command::cargo_bin("kvs-client").unwrap().assert().failure();
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get"])
+        .assert()
+        .failure();
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "missing_field"])
+        .assert()
+        .failure();
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["rm", "extra", "field"])
+        .assert()
+        .failure();
+}
+
+#[test]
+fn server_cli_version() {
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["-V"])
+        .assert()
+        .stdout(contains(env!("CARGO_PKG_VERSION")));
+}
+
+// Without a server the client should fail.
+#[test]
+fn client_no_server() {
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["--addr", &free_addr(), "get", "key1"])
+        .assert()
+        .code(2);
+}
+
+// Separate client processes should see each others writes.
+#[test]
+fn client_cli_access_server() {
+    for mode in modes() {
+        check_client_cli_access_server(mode);
+    }
+}
+
+fn check_client_cli_access_server(mode: &[&str]) {
+    let temp_dir = TempDir::new().unwrap();
+    let server = Server::start(&temp_dir, mode);
+
+    server
+        .client()
+        .args(["set", "key1", "value1"])
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    server
+        .client()
+        .args(["get", "key1"])
+        .assert()
+        .success()
+        .stdout(eq("value1").trim());
+
+    server
+        .client()
+        .args(["set", "key2", "value2"])
+        .assert()
+        .success();
+
+    server
+        .client()
+        .args(["scan"])
+        .assert()
+        .success()
+        .stdout(eq("key1 value1\nkey2 value2\n"));
+
+    server
+        .client()
+        .args(["scan", "--prefix", "key", "--start", "key2", "--limit", "5"])
+        .assert()
+        .success()
+        .stdout(eq("key2 value2\n"));
+
+    server
+        .client()
+        .args(["rm", "key1"])
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    server
+        .client()
+        .args(["get", "key1"])
+        .assert()
+        .success()
+        .stdout(eq("Key not found").trim());
+
+    server
+        .client()
+        .args(["rm", "key1"])
+        .assert()
+        .code(1)
+        .stdout(eq("Key not found").trim());
+}
+
+// Many clients at once should be served by both thread pools, even with fewer threads than clients.
+#[test]
+fn concurrent_clients() {
+    let mut modes = modes();
+    modes.push(&["--pool", "naive"]);
+    for mode in modes {
+        let temp_dir = TempDir::new().unwrap();
+        let server = Server::start(&temp_dir, &[mode, &["--threads", "2"]].concat());
+
+        let handles: Vec<_> = (0..8)
+            .map(|thread_id| {
+                let addr = server.addr.clone();
+                thread::spawn(move || -> kvs::Result<()> {
+                    let mut client = KvsClient::connect(addr)?;
+                    for iter in 0..20 {
+                        let key = format!("key{}", thread_id);
+                        client.set(key.clone(), format!("value{}", iter))?;
+                        assert_eq!(client.get_string(key)?, Some(format!("value{}", iter)));
+                    }
+                    Ok(())
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap().unwrap();
+        }
+    }
+}
+
+// Clients which are connected but send nothing, or stop in the middle of a request,
+// should not keep the pool from serving others.
+#[test]
+fn idle_clients() {
+    for protocol in ["kvs", "resp", "http"] {
+        let temp_dir = TempDir::new().unwrap();
+        let server = Server::start(&temp_dir, &["--threads", "1", "--protocol", protocol]);
+        let _idle: Vec<_> = (0..2)
+            .map(|_| TcpStream::connect(&server.addr).unwrap())
+            .collect();
+        let mut stalled = TcpStream::connect(&server.addr).unwrap();
+        let start: &[u8] = match protocol {
+            "resp" => b"*2\r\n$3\r\nGET",
+            "http" => b"GET /keys/key1 HTTP/1.1\r\nHost:",
+            _ => &[100, 0],
+        };
+        stalled.write_all(start).unwrap();
+
+        match protocol {
+            "resp" => assert_eq!(RespClient::connect(&server).send(&["PING"]), "+PONG\r\n"),
+            "http" => assert_eq!(http(&server, "GET", "/keys/key1", "").0, 404),
+            _ => {
+                let mut client = KvsClient::connect(&server.addr).unwrap();
+                client.set("key1", "value1").unwrap();
+                assert_eq!(
+                    client.get_string("key1").unwrap(),
+                    Some("value1".to_owned())
+                );
+            }
+        }
+    }
+}
+
+// The data should still be there after the server restarted.
+#[test]
+fn server_persists() {
+    for engine in ["kvs", "sled"] {
+        let temp_dir = TempDir::new().unwrap();
+
+        let server = Server::start(&temp_dir, &["--engine", engine]);
+        server
+            .client()
+            .args(["set", "key1", "value1"])
+            .assert()
+            .success();
+        drop(server);
+
+        let server = Server::start(&temp_dir, &[]);
+        server
+            .client()
+            .args(["get", "key1"])
+            .assert()
+            .success()
+            .stdout(eq("value1").trim());
+    }
+}
+
+// The server should refuse a directory of another engine.
+#[test]
+fn server_wrong_engine() {
+    let temp_dir = TempDir::new().unwrap();
+
+    // The memory engine leaves nothing behind, so the directory stays free for the others.
+    drop(Server::start(&temp_dir, &["--engine", "memory"]));
+    assert!(!temp_dir.path().join("engine").exists());
+
+    drop(Server::start(&temp_dir, &["--engine", "kvs"]));
+
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--engine", "memory", "--addr", &free_addr()])
+        .current_dir(&temp_dir)
+        .assert()
+        .code(6);
+}
+
+#[test]
+fn client_api() -> kvs::Result<()> {
+    for mode in modes() {
+        check_client_api(mode)?;
+    }
+    Ok(())
+}
+
+fn check_client_api(mode: &[&str]) -> kvs::Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let server = Server::start(&temp_dir, &[mode, &["--engine", "memory"]].concat());
+    let mut client = KvsClient::connect(&server.addr)?;
+
+    client.set("key1", "value1")?;
+    client.set("key2", "value2")?;
+    assert_eq!(client.get_string("key1")?, Some("value1".to_owned()));
+    client.remove("key1")?;
+    assert_eq!(client.get_string("key1")?, None);
+    assert_eq!(
+        client.scan(.., None)?,
+        vec![(b"key2".to_vec(), b"value2".to_vec())]
+    );
+    client.set("key3", "value3")?;
+    client.set("other", "value")?;
+    assert_eq!(
+        client.scan_prefix("key", Some(1))?,
+        vec![(b"key2".to_vec(), b"value2".to_vec())]
+    );
+    assert_eq!(
+        client.scan(b"key3".to_vec().., None)?,
+        vec![
+            (b"key3".to_vec(), b"value3".to_vec()),
+            (b"other".to_vec(), b"value".to_vec())
+        ]
+    );
+    let mut batch = WriteBatch::new();
+    batch.set("key4", "value4").remove("key3");
+    client.apply(&batch)?;
+    assert_eq!(client.get_string("key4")?, Some("value4".to_owned()));
+    assert_eq!(client.get("key3")?, None);
+    let version = client.set_if_absent("key5", "value5")?;
+    assert_eq!(
+        client.set_if_version("key5", "value6", version)?,
+        version + 1
+    );
+    assert_eq!(
+        client.get_versioned("key5")?,
+        Some((b"value6".to_vec(), version + 1))
+    );
+    match client.compare_and_swap("key5", Some(b"value5"), None) {
+        Err(KvsError::Conflict(current)) if current == version + 1 => {}
+        other => panic!("expected Conflict, got {:?}", other),
+    }
+    assert_eq!(client.compare_and_swap("key5", Some(b"value6"), None)?, 0);
+    client.set_with_ttl("key6", "value6", Duration::from_millis(50))?;
+    assert_eq!(client.get_string("key6")?, Some("value6".to_owned()));
+    thread::sleep(Duration::from_millis(100));
+    assert_eq!(client.get("key6")?, None);
+    client.set([0xff, 0x00], [0xc3, 0x28])?;
+    assert_eq!(client.get([0xff, 0x00])?, Some(vec![0xc3, 0x28]));
+    match client.remove("key1") {
+        Err(KvsError::KeyNotFound) => Ok(()),
+        other => panic!("expected KeyNotFound, got {:?}", other),
+    }
+}
+
+// A frame which claims to be longer than any message should be refused without waiting for it,
+// like an HTTP request sent to the wrong port.
+#[test]
+fn oversized_frame() -> kvs::Result<()> {
+    for mode in modes() {
+        let temp_dir = TempDir::new().unwrap();
+        let server = Server::start(&temp_dir, &[mode, &["--engine", "memory"]].concat());
+        let mut stream = TcpStream::connect(&server.addr)?;
+        stream.write_all(b"GET / HTTP/1.1\r\n")?;
+        stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+        let mut rest = Vec::new();
+        stream.read_to_end(&mut rest)?;
+
+        let mut client = KvsClient::connect(&server.addr)?;
+        client.set("key1", "value1")?;
+        assert_eq!(client.get_string("key1")?, Some("value1".to_owned()));
+    }
+    Ok(())
+}
+
+// A follower should apply the writes of its primary, also across compactions of the primary,
+// and refuse writes of its own.
+#[test]
+fn replication() -> kvs::Result<()> {
+    let primary_dir = TempDir::new().unwrap();
+    let follower_dir = TempDir::new().unwrap();
+    let primary = Server::start(&primary_dir, &[]);
+    let mut client = KvsClient::connect(&primary.addr)?;
+    client.set("key1", "value1")?;
+    client.set("key2", "value2")?;
+
+    let follower = Server::start(&follower_dir, &["--follow", &primary.addr]);
+    let mut follower_client = KvsClient::connect(&follower.addr)?;
+    eventually(|| Ok(follower_client.get("key2")?.is_some()))?;
+    assert_eq!(
+        follower_client.get_versioned("key1")?,
+        Some((b"value1".to_vec(), 1))
+    );
+
+    let mut batch = WriteBatch::new();
+    batch.set("key3", "value3").remove("key1");
+    client.apply(&batch)?;
+    client.set_with_ttl("key4", "value4", Duration::from_secs(3600))?;
+    // About 2 MB which get compacted away.
+    for i in 0..200 {
+        client.set("big", vec![i as u8; 10_000])?;
+    }
+    client.set("last", "value")?;
+    eventually(|| Ok(follower_client.get("last")?.is_some()))?;
+    assert_eq!(follower_client.scan(.., None)?, client.scan(.., None)?,);
+    assert_eq!(
+        follower_client.get_versioned("big")?,
+        client.get_versioned("big")?
+    );
+
+    match follower_client.set("key5", "value5") {
+        Err(KvsError::Server(message)) => assert!(message.contains("read-only"), "{}", message),
+        other => panic!("expected a refused write, got {:?}", other),
+    }
+    assert_eq!(follower_client.get("key5")?, None);
+
+    let offset = match client.status()? {
+        Status::Primary { offset, followers } => {
+            assert_eq!(followers, 1);
+            offset
+        }
+        other => panic!("expected a primary, got {:?}", other),
+    };
+    eventually(|| match follower_client.status()? {
+        Status::Follower {
+            primary: followed,
+            connected,
+            offset: applied,
+            lag,
+            last_contact,
+        } => {
+            assert_eq!(followed, primary.addr);
+            assert!(connected && last_contact.is_some());
+            Ok(applied == Some(offset) && lag == 0)
+        }
+        other => panic!("expected a follower, got {:?}", other),
+    })
+}
+
+// A restarted follower should go on from where it was, and one whose primary is gone
+// should keep serving what it has.
+#[test]
+fn follower_restarts() -> kvs::Result<()> {
+    let primary_dir = TempDir::new().unwrap();
+    let follower_dir = TempDir::new().unwrap();
+    let primary = Server::start(&primary_dir, &[]);
+    let mut client = KvsClient::connect(&primary.addr)?;
+    client.set("key1", "value1")?;
+
+    let follower = Server::start(&follower_dir, &["--follow", &primary.addr]);
+    let mut follower_client = KvsClient::connect(&follower.addr)?;
+    eventually(|| Ok(follower_client.get("key1")?.is_some()))?;
+    drop(follower);
+
+    client.set("key2", "value2")?;
+    client.remove("key1")?;
+    let follower = Server::start(&follower_dir, &["--follow", &primary.addr]);
+    let mut follower_client = KvsClient::connect(&follower.addr)?;
+    eventually(|| Ok(follower_client.get("key1")?.is_none()))?;
+    assert_eq!(
+        follower_client.scan(.., None)?,
+        vec![(b"key2".to_vec(), b"value2".to_vec())]
+    );
+
+    drop(primary);
+    eventually(|| match follower_client.status()? {
+        Status::Follower { connected, .. } => Ok(!connected),
+        other => panic!("expected a follower, got {:?}", other),
+    })?;
+    assert_eq!(
+        follower_client.get_string("key2")?,
+        Some("value2".to_owned())
+    );
+    Ok(())
+}
+
+// `kvs-client status` should show the role of the server and how far a follower is.
+#[test]
+fn cli_status() {
+    let primary_dir = TempDir::new().unwrap();
+    let follower_dir = TempDir::new().unwrap();
+    let memory_dir = TempDir::new().unwrap();
+    let primary = Server::start(&primary_dir, &[]);
+    let follower = Server::start(&follower_dir, &["--follow", &primary.addr]);
+    let memory = Server::start(&memory_dir, &["--engine", "memory"]);
+
+    primary
+        .client()
+        .args(["status"])
+        .assert()
+        .success()
+        .stdout(contains("role: primary\noffset: 1:20\n"));
+    follower
+        .client()
+        .args(["status"])
+        .assert()
+        .success()
+        .stdout(contains(format!(
+            "role: follower\nprimary: {}\n",
+            primary.addr
+        )))
+        .stdout(contains("lag: "));
+    memory
+        .client()
+        .args(["status"])
+        .assert()
+        .success()
+        .stdout(eq("role: standalone\n"));
+
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--engine", "memory", "--addr", &free_addr()])
+        .args(["--follow", &primary.addr])
+        .current_dir(&memory_dir)
+        .assert()
+        .code(9);
+}
+
+// A client should get the changes of the keys it watches, also from a follower.
+#[test]
+fn watch() -> kvs::Result<()> {
+    for mode in modes() {
+        let primary_dir = TempDir::new().unwrap();
+        let follower_dir = TempDir::new().unwrap();
+        let primary = Server::start(&primary_dir, mode);
+        // Only the threaded server has followers.
+        let follower = match mode {
+            [] => Some(Server::start(&follower_dir, &["--follow", &primary.addr])),
+            _ => None,
+        };
+        let mut watch = KvsClient::connect(&primary.addr)?.watch("key")?;
+        let mut follower_watch = match &follower {
+            Some(follower) => Some(KvsClient::connect(&follower.addr)?.watch("key")?),
+            None => None,
+        };
+
+        let mut client = KvsClient::connect(&primary.addr)?;
+        client.set("other", "value")?;
+        client.set("key1", "value1")?;
+        client.remove("key1")?;
+        let expected = vec![
+            Event::Set {
+                key: b"key1".to_vec(),
+                value: b"value1".to_vec(),
+                version: 2,
+            },
+            Event::Remove {
+                key: b"key1".to_vec(),
+            },
+        ];
+        assert_eq!(
+            watch.by_ref().take(2).collect::<kvs::Result<Vec<_>>>()?,
+            expected
+        );
+        if let Some(follower_watch) = &mut follower_watch {
+            assert_eq!(
+                follower_watch
+                    .by_ref()
+                    .take(2)
+                    .collect::<kvs::Result<Vec<_>>>()?,
+                expected
+            );
+        }
+    }
+    Ok(())
+}
+
+// Watches on the async server should not take the blocking threads of the runtime,
+// which every other request needs.
+#[cfg(feature = "async")]
+#[test]
+fn async_watches() -> kvs::Result<()> {
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .max_blocking_threads(2)
+        .enable_io()
+        .build()?;
+    let addr = free_addr();
+    let server = kvs::AsyncKvsServer::new(kvs::MemStore::new());
+    let server_addr = addr.clone();
+    runtime.spawn(async move { server.run(server_addr).await });
+    let started = Instant::now();
+    while TcpStream::connect(&addr).is_err() {
+        assert!(started.elapsed() < Duration::from_secs(10));
+        thread::sleep(Duration::from_millis(20));
+    }
+
+    let watches = (0..4)
+        .map(|_| KvsClient::connect(&addr)?.watch("key"))
+        .collect::<kvs::Result<Vec<_>>>()?;
+    let (sender, done) = mpsc::channel();
+    let client_addr = addr.clone();
+    thread::spawn(move || {
+        let result = KvsClient::connect(&client_addr).and_then(|mut client| {
+            client.set("key1", "value1")?;
+            client.get_string("key1")
+        });
+        let _ = sender.send(result);
+    });
+    let value = done
+        .recv_timeout(Duration::from_secs(10))
+        .expect("requests wait for the watches");
+    assert_eq!(value?, Some("value1".to_owned()));
+    for mut watch in watches {
+        let expected = Event::Set {
+            key: b"key1".to_vec(),
+            value: b"value1".to_vec(),
+            version: 1,
+        };
+        assert_eq!(watch.next().unwrap()?, expected);
+    }
+    Ok(())
+}
+
+// `kvs-client watch` should print the changes as they come.
+#[test]
+fn cli_watch() {
+    for mode in modes() {
+        let temp_dir = TempDir::new().unwrap();
+        let server = Server::start(&temp_dir, mode);
+        let mut child = server
+            .client()
+            .args(["watch", "key"])
+            .stdout(Stdio::piped())
+            .spawn()
+            .unwrap();
+        let stdout = BufReader::new(child.stdout.take().unwrap());
+        let (sender, lines) = mpsc::channel();
+        thread::spawn(move || {
+            for line in stdout.lines() {
+                sender.send(line.unwrap()).unwrap();
+            }
+        });
+
+        // Until the watch is set up the changes aren't seen.
+        let started = Instant::now();
+        loop {
+            server
+                .client()
+                .args(["set", "key0", "ready"])
+                .assert()
+                .success();
+            if lines.recv_timeout(Duration::from_millis(100)).is_ok() {
+                break;
+            }
+            assert!(
+                started.elapsed() < Duration::from_secs(10),
+                "watch didn't start"
+            );
+        }
+        server
+            .client()
+            .args(["set", "other", "value"])
+            .assert()
+            .success();
+        server
+            .client()
+            .args(["set", "key1", "value1"])
+            .assert()
+            .success();
+        server.client().args(["rm", "key1"]).assert().success();
+
+        let seen: Vec<_> = lines
+            .iter()
+            .filter(|line| line != "set key0 ready")
+            .take(2)
+            .collect();
+        assert_eq!(seen, vec!["set key1 value1", "rm key1"]);
+        let _ = child.kill();
+        let _ = child.wait();
+    }
+}
+
+// Redis clients should be able to use the server with `--protocol resp`.
+#[test]
+fn resp() {
+    let dir = TempDir::new().unwrap();
+    let server = Server::start(&dir, &["--protocol", "resp"]);
+    let mut client = RespClient::connect(&server);
+
+    assert_eq!(client.send(&["PING"]), "+PONG\r\n");
+    assert_eq!(client.send(&["ping", "hi"]), "$2\r\nhi\r\n");
+    assert_eq!(client.send(&["SET", "key1", "value1"]), "+OK\r\n");
+    assert_eq!(client.send(&["GET", "key1"]), "$6\r\nvalue1\r\n");
+    assert_eq!(client.send(&["GET", "key2"]), "$-1\r\n");
+
+    assert_eq!(client.send(&["SET", "key1", "value2", "NX"]), "$-1\r\n");
+    assert_eq!(client.send(&["SET", "key2", "value2", "XX"]), "$-1\r\n");
+    assert_eq!(client.send(&["SET", "key2", "value2", "NX"]), "+OK\r\n");
+    assert_eq!(client.send(&["SET", "key1", "value3", "XX"]), "+OK\r\n");
+    assert_eq!(client.send(&["GET", "key1"]), "$6\r\nvalue3\r\n");
+    assert_eq!(
+        client.send(&["SET", "key1", "value1", "EX", "0"]),
+        "-ERR invalid expire time in 'set' command\r\n"
+    );
+    assert_eq!(
+        client.send(&["SET", "key1", "value1", "NX", "XX"]),
+        "-ERR syntax error\r\n"
+    );
+    assert_eq!(
+        client.send(&["SET", "temp", "value", "PX", "100"]),
+        "+OK\r\n"
+    );
+    thread::sleep(Duration::from_millis(300));
+    assert_eq!(client.send(&["GET", "temp"]), "$-1\r\n");
+
+    assert_eq!(
+        client.send(&["MSET", "key3", "value3", "other", "value"]),
+        "+OK\r\n"
+    );
+    assert_eq!(
+        client.send(&["MGET", "key3", "nope", "other"]),
+        "*3\r\n$6\r\nvalue3\r\n$-1\r\n$5\r\nvalue\r\n"
+    );
+    assert_eq!(client.send(&["EXISTS", "key1", "key1", "nope"]), ":2\r\n");
+    assert_eq!(client.send(&["DEL", "other", "nope"]), ":1\r\n");
+    assert_eq!(client.send(&["EXISTS", "other"]), ":0\r\n");
+
+    assert_eq!(
+        client.send(&["SCAN", "0", "MATCH", "key*", "COUNT", "2"]),
+        "*2\r\n$1\r\n1\r\n*2\r\n$4\r\nkey1\r\n$4\r\nkey2\r\n"
+    );
+    // The scan goes on after the last key it looked at, whatever is written before it.
+    assert_eq!(client.send(&["SET", "key0", "value0"]), "+OK\r\n");
+    assert_eq!(
+        client.send(&["SCAN", "1", "MATCH", "key*", "COUNT", "2"]),
+        "*2\r\n$1\r\n0\r\n*1\r\n$4\r\nkey3\r\n"
+    );
+    assert_eq!(client.send(&["DEL", "key0"]), ":1\r\n");
+    assert_eq!(client.send(&["SCAN", "7"]), "-ERR invalid cursor\r\n");
+    assert_eq!(
+        client.send(&["SET", "temp", "value", "EX", "100"]),
+        "+OK\r\n"
+    );
+    let info = client.send(&["INFO"]);
+    assert!(info.contains("role:master"));
+    assert!(info.contains("db0:keys=4,expires=1"));
+
+    assert_eq!(
+        client.send(&["HSET", "key1", "field", "value"]),
+        "-ERR unknown command 'hset'\r\n"
+    );
+    assert_eq!(
+        client.send(&["GET"]),
+        "-ERR wrong number of arguments for 'get' command\r\n"
+    );
+    assert_eq!(client.send_raw("get key1\r\n"), "$6\r\nvalue3\r\n");
+    assert_eq!(client.send(&["QUIT"]), "+OK\r\n");
+
+    // Lines are only read up to 64 KiB, this one doesn't end by then.
+    let mut client = RespClient::connect(&server);
+    assert_eq!(
+        client.send_raw(&"k".repeat(64 * 1024)),
+        "-ERR Protocol error: line is unterminated or too long\r\n"
+    );
+
+    // The same data is there for the clients of the usual protocol.
+    drop(server);
+    let server = Server::start(&dir, &[]);
+    server
+        .client()
+        .args(["get", "key3"])
+        .assert()
+        .success()
+        .stdout(eq("value3").trim());
+}
+
+// A follower should refuse writes of Redis clients like it does for the others.
+#[test]
+fn resp_follower() -> kvs::Result<()> {
+    let primary_dir = TempDir::new()?;
+    let primary = Server::start(&primary_dir, &[]);
+    let follower_dir = TempDir::new()?;
+    let follower = Server::start(
+        &follower_dir,
+        &["--follow", &primary.addr, "--protocol", "resp"],
+    );
+
+    KvsClient::connect(&primary.addr)?.set("key1", "value1")?;
+    let mut client = RespClient::connect(&follower);
+    eventually(|| Ok(client.send(&["GET", "key1"]) == "$6\r\nvalue1\r\n"))?;
+    assert_eq!(
+        client.send(&["SET", "key1", "value2"]),
+        "-READONLY You can't write against a read only replica.\r\n"
+    );
+    assert!(client.send(&["INFO", "replication"]).contains("role:slave"));
+    Ok(())
+}
+
+// Tools which only talk HTTP should be able to use the server with `--protocol http`.
+#[test]
+fn http_rest() {
+    let dir = TempDir::new().unwrap();
+    let server = Server::start(&dir, &["--protocol", "http"]);
+
+    assert_eq!(
+        http(&server, "PUT", "/keys/key1", "value1"),
+        (200, r#"{"key":"key1","version":1}"#.to_owned())
+    );
+    assert_eq!(
+        http(&server, "GET", "/keys/key1", ""),
+        (
+            200,
+            r#"{"key":"key1","value":"value1","version":1}"#.to_owned()
+        )
+    );
+    assert_eq!(
+        http(&server, "PUT", "/keys/key%2F2", "value 2"),
+        (200, r#"{"key":"key/2","version":2}"#.to_owned())
+    );
+    http(&server, "PUT", "/keys/other", "value");
+    assert_eq!(
+        http(&server, "GET", "/keys?prefix=key", ""),
+        (
+            200,
+            r#"[{"key":"key/2","value":"value 2"},{"key":"key1","value":"value1"}]"#.to_owned()
+        )
+    );
+    assert_eq!(
+        http(&server, "GET", "/keys?prefix=nope", ""),
+        (200, "[]".to_owned())
+    );
+
+    assert_eq!(
+        http(&server, "DELETE", "/keys/key1", ""),
+        (204, String::new())
+    );
+    assert_eq!(
+        http(&server, "GET", "/keys/key1", ""),
+        (
+            404,
+            r#"{"error":"key_not_found","message":"Key not found"}"#.to_owned()
+        )
+    );
+    assert_eq!(http(&server, "DELETE", "/keys/key1", "").0, 404);
+    assert_eq!(http(&server, "GET", "/keys/bad%2", "").0, 400);
+    assert_eq!(http(&server, "GET", "/keys?limit=1", "").0, 400);
+    assert_eq!(http(&server, "POST", "/keys/key1", "").0, 405);
+    assert_eq!(http(&server, "GET", "/", "").0, 404);
+
+    // Several requests can be sent on one connection.
+    let mut stream = TcpStream::connect(&server.addr).unwrap();
+    write!(
+        stream,
+        "GET /keys/other HTTP/1.1\r\n\r\nGET /keys/other HTTP/1.1\r\nConnection: close\r\n\r\n"
+    )
+    .unwrap();
+    let mut responses = String::new();
+    stream.read_to_string(&mut responses).unwrap();
+    assert_eq!(responses.matches("HTTP/1.1 200 OK").count(), 2);
+
+    // The same data is there for the clients of the usual protocol.
+    drop(server);
+    let server = Server::start(&dir, &[]);
+    server
+        .client()
+        .args(["get", "key/2"])
+        .assert()
+        .success()
+        .stdout(eq("value 2").trim());
+}
+
+// A follower should refuse writes over HTTP with 403.
+#[test]
+fn http_follower() -> kvs::Result<()> {
+    let primary_dir = TempDir::new()?;
+    let primary = Server::start(&primary_dir, &[]);
+    let follower_dir = TempDir::new()?;
+    let follower = Server::start(
+        &follower_dir,
+        &["--follow", &primary.addr, "--protocol", "http"],
+    );
+
+    KvsClient::connect(&primary.addr)?.set("key1", "value1")?;
+    eventually(|| Ok(http(&follower, "GET", "/keys/key1", "").0 == 200))?;
+    let (status, body) = http(&follower, "PUT", "/keys/key1", "value2");
+    assert_eq!(status, 403);
+    assert!(body.contains("read_only"));
+    Ok(())
+}
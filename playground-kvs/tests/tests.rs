@@ -1,7 +1,17 @@
 use assert_cmd::prelude::*;
-use kvs::KvStore;
-use predicates::str::contains;
+use kvs::{
+    Event, Format, ImportMode, KeyCounts, KvStore, KvStoreOptions, KvsEngine, KvsError, MemStore,
+    Result, Scan, SledStore, Watch, WriteBatch,
+};
+use predicates::boolean::PredicateBooleanExt;
+use predicates::ord::eq;
+use predicates::str::{contains, is_empty, PredicateStrExt};
+use std::fs::{self, File};
+use std::path::Path;
 use std::process::Command;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
 
 // `kvs` with no args should exit with a non-zero code.
 #[test]
@@ -14,55 +24,890 @@ fn cli_no_args() {
 fn cli_version() {
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["-V"])
+        .args(["-V"])
         .assert()
         .stdout(contains(env!("CARGO_PKG_VERSION")));
 }
 
-// `kvs get <KEY>` should print "unimplemented" to stderr and exit with non-zero code
+/// The engines which keep their entries on disk, which the tests of `kvs` run with.
+const ENGINES: [&str; 2] = ["kvs", "sled"];
+
+/// `kvs` with the storage engine `engine`.
+fn kvs(engine: &str) -> Command {
+    let mut command = Command::cargo_bin("kvs").unwrap();
+    command.args(["--engine", engine]);
+    command
+}
+
+/// Sets the keys in a store of `engine` in `dir`, as an earlier invocation of `kvs` would.
+fn preload(engine: &str, dir: &Path, entries: &[(&str, &str)]) -> Result<()> {
+    match engine {
+        "kvs" => set_all(KvStore::open(dir)?, entries),
+        _ => set_all(SledStore::open(dir)?, entries),
+    }
+}
+
+fn set_all(store: impl KvsEngine, entries: &[(&str, &str)]) -> Result<()> {
+    for (key, value) in entries {
+        store.set(key, value)?;
+    }
+    Ok(())
+}
+
+// `kvs get <KEY>` should print "Key not found" for a non-existent key and exit with zero.
+#[test]
+fn cli_get_non_existent_key() {
+    for engine in ENGINES {
+        let temp_dir = TempDir::new().unwrap();
+        kvs(engine)
+            .args(["get", "key1"])
+            .current_dir(&temp_dir)
+            .assert()
+            .success()
+            .stdout(eq("Key not found").trim());
+    }
+}
+
+// `kvs rm <KEY>` should print "Key not found" for an empty database and exit with non-zero code.
+#[test]
+fn cli_rm_non_existent_key() {
+    for engine in ENGINES {
+        let temp_dir = TempDir::new().unwrap();
+        kvs(engine)
+            .args(["rm", "key1"])
+            .current_dir(&temp_dir)
+            .assert()
+            .failure()
+            .stdout(eq("Key not found").trim());
+    }
+}
+
+// `kvs set <KEY> <VALUE>` should print nothing and exit with zero.
+#[test]
+fn cli_set() {
+    for engine in ENGINES {
+        let temp_dir = TempDir::new().unwrap();
+        kvs(engine)
+            .args(["set", "key1", "value1"])
+            .current_dir(&temp_dir)
+            .assert()
+            .success()
+            .stdout(is_empty());
+    }
+}
+
+// `kvs get <KEY>` should print the value set by an earlier invocation.
+#[test]
+fn cli_get_stored() {
+    for engine in ENGINES {
+        let temp_dir = TempDir::new().unwrap();
+
+        preload(
+            engine,
+            temp_dir.path(),
+            &[("key1", "value1"), ("key2", "value2")],
+        )
+        .unwrap();
+
+        kvs(engine)
+            .args(["get", "key1"])
+            .current_dir(&temp_dir)
+            .assert()
+            .success()
+            .stdout(eq("value1").trim());
+
+        kvs(engine)
+            .args(["get", "key2"])
+            .current_dir(&temp_dir)
+            .assert()
+            .success()
+            .stdout(eq("value2").trim());
+    }
+}
+
+// `kvs rm <KEY>` should remove the key so that later invocations don't find it.
+#[test]
+fn cli_rm_stored() {
+    for engine in ENGINES {
+        let temp_dir = TempDir::new().unwrap();
+
+        kvs(engine)
+            .args(["set", "key1", "value1"])
+            .current_dir(&temp_dir)
+            .assert()
+            .success();
+
+        kvs(engine)
+            .args(["rm", "key1"])
+            .current_dir(&temp_dir)
+            .assert()
+            .success()
+            .stdout(is_empty());
+
+        kvs(engine)
+            .args(["get", "key1"])
+            .current_dir(&temp_dir)
+            .assert()
+            .success()
+            .stdout(eq("Key not found").trim());
+    }
+}
+
+// `--dir <DIR>` should be used instead of the current directory.
+#[test]
+fn cli_dir() {
+    for engine in ENGINES {
+        let temp_dir = TempDir::new().unwrap();
+        let other_dir = TempDir::new().unwrap();
+
+        kvs(engine)
+            .args(["set", "key1", "value1", "--dir"])
+            .arg(temp_dir.path())
+            .current_dir(&other_dir)
+            .assert()
+            .success();
+
+        kvs(engine)
+            .args(["get", "key1"])
+            .current_dir(&other_dir)
+            .assert()
+            .success()
+            .stdout(eq("Key not found").trim());
+
+        kvs(engine)
+            .args(["get", "key1"])
+            .current_dir(&temp_dir)
+            .assert()
+            .success()
+            .stdout(eq("value1").trim());
+    }
+}
+
+// A log that doesn't start with the magic bytes should exit with the code for a corrupt log.
 #[test]
-fn cli_get() {
+fn cli_corrupt_log() {
+    let temp_dir = TempDir::new().unwrap();
+    fs::write(temp_dir.path().join("1.log"), b"garbage!").unwrap();
+
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["get", "key1"])
+        .args(["get", "key1"])
+        .current_dir(&temp_dir)
         .assert()
-        .failure()
-        .stderr(contains("unimplemented"));
+        .code(4)
+        .stderr(contains("corrupt log"));
 }
 
-// `kvs set <KEY> <VALUE>` should print "unimplemented" to stderr and exit with non-zero code
+// A log of an unknown format version should exit with its own code.
 #[test]
-fn cli_set() {
+fn cli_unsupported_version() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut header = b"KVSL\x63\0\0\0".to_vec();
+    header.resize(20, 0);
+    fs::write(temp_dir.path().join("1.log"), header).unwrap();
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .code(5)
+        .stderr(contains("version 99"));
+}
+
+// `kvs compact` should keep the live entries and exit with zero.
+#[test]
+fn cli_compact() {
+    let temp_dir = TempDir::new().unwrap();
+
+    let store = KvStore::open(temp_dir.path()).unwrap();
+    store.set("key1", "value1").unwrap();
+    store.set("key1", "value2").unwrap();
+    drop(store);
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["compact"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("value2").trim());
+}
+
+// `kvs check` should report a damaged record and `kvs repair` should make the store open again.
+#[test]
+fn cli_check_repair() {
+    let temp_dir = TempDir::new().unwrap();
+    let log_path = temp_dir.path().join("1.log");
+
+    let store = KvStore::open(temp_dir.path()).unwrap();
+    store.set("key1", "value1").unwrap();
+    store.set("key2", "value2").unwrap();
+    drop(store);
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["check"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("1 generations, 2 records, 2 keys, 0 problems").trim());
+
+    // Damage the value of key1, which is not the last record.
+    let mut log = fs::read(&log_path).unwrap();
+    let pos = log.windows(6).position(|bytes| bytes == b"value1").unwrap();
+    log[pos] ^= 1;
+    fs::write(&log_path, log).unwrap();
+
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["set", "key1", "value1"])
+        .args(["get", "key2"])
+        .current_dir(&temp_dir)
         .assert()
-        .failure()
-        .stderr(contains("unimplemented"));
+        .code(4);
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["check"])
+        .current_dir(&temp_dir)
+        .assert()
+        .code(4)
+        .stdout(contains("log 1 at byte 20: checksum mismatch"))
+        .stderr(contains("1 problems found"));
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["repair"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("1 keys salvaged"));
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get", "key2"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("value2").trim());
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["check"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
 }
 
-// `kvs rm <KEY>` should print "unimplemented" to stderr and exit with non-zero code
+// `kvs check` should change nothing, not even claim a new directory for an engine.
 #[test]
-fn cli_rm() {
+fn cli_check_changes_nothing() {
+    let temp_dir = TempDir::new().unwrap();
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["check"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("0 generations, 0 records, 0 keys, 0 problems").trim());
+    assert!(!temp_dir.path().join("engine").exists());
+
+    let missing = temp_dir.path().join("missing");
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["rm", "key1"])
+        .args(["--dir", missing.to_str().unwrap(), "check"])
         .assert()
-        .failure()
-        .stderr(contains("unimplemented"));
+        .failure();
+    assert!(!missing.exists());
+}
+
+// `kvs export` should print what `kvs import` reads, in both formats.
+#[test]
+fn cli_export_import() {
+    let temp_dir = TempDir::new().unwrap();
+    let other_dir = TempDir::new().unwrap();
+    let export_path = temp_dir.path().join("export");
+
+    let store = KvStore::open(temp_dir.path()).unwrap();
+    store.set("key1", "value 1, with a comma").unwrap();
+    store.set("key2", "value2").unwrap();
+    drop(store);
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["export"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq(concat!(
+            "{\"key\":\"key1\",\"value\":\"value 1, with a comma\"}\n",
+            "{\"key\":\"key2\",\"value\":\"value2\"}\n"
+        )));
+
+    let output = Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["export", "--format", "csv"])
+        .current_dir(&temp_dir)
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    assert_eq!(
+        output.stdout,
+        b"key,value\nkey1,\"value 1, with a comma\"\nkey2,value2\n"
+    );
+    fs::write(&export_path, output.stdout).unwrap();
+
+    let store = KvStore::open(other_dir.path()).unwrap();
+    store.set("key3", "value3").unwrap();
+    drop(store);
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["import", "--format", "csv"])
+        .arg(&export_path)
+        .current_dir(&other_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["scan"])
+        .current_dir(&other_dir)
+        .assert()
+        .success()
+        .stdout(eq("key1 value 1, with a comma\nkey2 value2\nkey3 value3\n"));
+
+    fs::write(&export_path, "{\"key\":\"key4\",\"value\":[0,255]}\n").unwrap();
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["import", "--replace"])
+        .stdin(File::open(&export_path).unwrap())
+        .current_dir(&other_dir)
+        .assert()
+        .success();
+    let output = Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get", "key4"])
+        .current_dir(&other_dir)
+        .output()
+        .unwrap();
+    assert_eq!(output.stdout, b"\x00\xff\n");
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get", "key1"])
+        .current_dir(&other_dir)
+        .assert()
+        .success()
+        .stdout(eq("Key not found").trim());
+
+    fs::write(&export_path, "{\"key\":\"key5\"}\n").unwrap();
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["import"])
+        .arg(&export_path)
+        .current_dir(&other_dir)
+        .assert()
+        .code(9)
+        .stderr(contains("line 1"));
+}
+
+// `kvs snapshot` should create, list and restore snapshots by name.
+#[test]
+fn cli_snapshot() {
+    let temp_dir = TempDir::new().unwrap();
+
+    let kvs = |args: &[&str]| {
+        Command::cargo_bin("kvs")
+            .unwrap()
+            .args(args)
+            .current_dir(&temp_dir)
+            .assert()
+    };
+
+    kvs(&["set", "key1", "value1"]).success();
+    kvs(&["snapshot", "create", "first"])
+        .success()
+        .stdout(is_empty());
+    kvs(&["set", "key1", "value2"]).success();
+    kvs(&["snapshot", "create", "second"]).success();
+    kvs(&["snapshot", "create", "second"])
+        .code(9)
+        .stderr(contains("already"));
+    kvs(&["snapshot", "list"])
+        .success()
+        .stdout(eq("first\nsecond\n"));
+
+    kvs(&["snapshot", "restore", "first"]).success();
+    kvs(&["get", "key1"]).success().stdout(eq("value1").trim());
+    kvs(&["snapshot", "restore", "third"])
+        .code(9)
+        .stderr(contains("no snapshot named third"));
+}
+
+// `kvs --engine` should refuse a directory written by another engine.
+#[test]
+fn cli_wrong_engine() {
+    let temp_dir = TempDir::new().unwrap();
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["--engine", "kvs", "set", "key1", "value1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["--engine", "sled", "get", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .code(6);
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["--engine", "memory", "get", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .code(6);
+
+    // Without --engine the engine of the directory is used.
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("value1").trim());
+}
+
+// The subcommands which work on the log itself should refuse the sled and memory engines.
+#[test]
+fn cli_sled_no_log() {
+    for engine in ["sled", "memory"] {
+        let temp_dir = TempDir::new().unwrap();
+        for subcommand in ["compact", "check", "repair"] {
+            kvs(engine)
+                .arg(subcommand)
+                .current_dir(&temp_dir)
+                .assert()
+                .code(9)
+                .stderr(contains("only the kvs engine"));
+        }
+    }
+}
+
+// `kvs --engine memory` should work, but keep nothing once a command is done
+// and leave the directory to the other engines.
+#[test]
+fn cli_memory_engine() {
+    let temp_dir = TempDir::new().unwrap();
+    kvs("memory")
+        .args(["set", "key1", "value1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    kvs("memory")
+        .args(["get", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("Key not found").trim());
+    assert!(!temp_dir.path().join("engine").exists());
+}
+
+// `kvs scan` should print all entries ordered by key.
+#[test]
+fn cli_scan() {
+    for engine in ENGINES {
+        let temp_dir = TempDir::new().unwrap();
+
+        preload(
+            engine,
+            temp_dir.path(),
+            &[("key2", "value2"), ("key1", "value1")],
+        )
+        .unwrap();
+
+        kvs(engine)
+            .args(["scan"])
+            .current_dir(&temp_dir)
+            .assert()
+            .success()
+            .stdout(eq("key1 value1\nkey2 value2\n"));
+    }
+}
+
+// `kvs scan` should only print the entries in the range and not more than the limit.
+#[test]
+fn cli_scan_range() {
+    for engine in ENGINES {
+        let temp_dir = TempDir::new().unwrap();
+
+        let entries: Vec<_> = ["a1", "b1", "b2", "b3", "b4", "c1"]
+            .iter()
+            .map(|&key| (key, "v"))
+            .collect();
+        preload(engine, temp_dir.path(), &entries).unwrap();
+
+        kvs(engine)
+            .args(["scan", "--prefix", "b"])
+            .current_dir(&temp_dir)
+            .assert()
+            .success()
+            .stdout(eq("b1 v\nb2 v\nb3 v\nb4 v\n"));
+
+        kvs(engine)
+            .args(["scan", "--prefix", "b", "--start", "b2", "--end", "b4"])
+            .current_dir(&temp_dir)
+            .assert()
+            .success()
+            .stdout(eq("b2 v\nb3 v\n"));
+
+        kvs(engine)
+            .args(["scan", "--start", "b3", "--limit", "2"])
+            .current_dir(&temp_dir)
+            .assert()
+            .success()
+            .stdout(eq("b3 v\nb4 v\n"));
+
+        kvs(engine)
+            .args(["scan", "--start", "c", "--end", "b"])
+            .current_dir(&temp_dir)
+            .assert()
+            .success()
+            .stdout(is_empty());
+    }
+}
+
+// `kvs set <KEY> --file <FILE>` should store the exact bytes of the file, or of stdin with `-`.
+#[test]
+fn cli_set_file() {
+    let temp_dir = TempDir::new().unwrap();
+    let blob: Vec<u8> = (0..=255).collect();
+    let blob_path = temp_dir.path().join("blob.bin");
+    fs::write(&blob_path, &blob).unwrap();
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["set", "key1", "--file"])
+        .arg(&blob_path)
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["set", "key2", "--file", "-"])
+        .stdin(File::open(&blob_path).unwrap())
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["set", "key3", "value3", "--file", "-"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+
+    let store = KvStore::open(temp_dir.path()).unwrap();
+    assert_eq!(store.get("key1").unwrap(), Some(blob.clone()));
+    assert_eq!(store.get("key2").unwrap(), Some(blob.clone()));
+    drop(store);
+
+    // get prints the bytes as they are, followed by a newline.
+    let output = Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get", "key1"])
+        .current_dir(&temp_dir)
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    assert_eq!(output.stdout, [blob, b"\n".to_vec()].concat());
+}
+
+// `kvs batch` should apply all changes read from stdin, or none of them if one is invalid.
+#[test]
+fn cli_batch() {
+    for engine in ENGINES {
+        let temp_dir = TempDir::new().unwrap();
+        let input_path = temp_dir.path().join("batch.txt");
+
+        fs::write(
+            &input_path,
+            "set key1 value 1\nset key2 value2\n\n# comment\nrm key2\n",
+        )
+        .unwrap();
+        kvs(engine)
+            .args(["batch"])
+            .stdin(File::open(&input_path).unwrap())
+            .current_dir(&temp_dir)
+            .assert()
+            .success()
+            .stdout(is_empty());
+
+        fs::write(&input_path, "set key3 value3\nremove key1\n").unwrap();
+        kvs(engine)
+            .args(["batch"])
+            .stdin(File::open(&input_path).unwrap())
+            .current_dir(&temp_dir)
+            .assert()
+            .code(9)
+            .stderr(contains("line 2"));
+
+        fs::write(&input_path, "set key3 value3\nrm key2\n").unwrap();
+        kvs(engine)
+            .args(["batch"])
+            .stdin(File::open(&input_path).unwrap())
+            .current_dir(&temp_dir)
+            .assert()
+            .code(1)
+            .stdout(eq("Key not found").trim());
+
+        kvs(engine)
+            .args(["scan"])
+            .current_dir(&temp_dir)
+            .assert()
+            .success()
+            .stdout(eq("key1 value 1\n"));
+    }
+}
+
+// Conditional writes should fail with their own exit code if the key isn't as expected.
+#[test]
+fn cli_conditional_writes() {
+    for engine in ENGINES {
+        let temp_dir = TempDir::new().unwrap();
+
+        kvs(engine)
+            .args(["set", "key1", "value1", "--if-absent", "--versioned"])
+            .current_dir(&temp_dir)
+            .assert()
+            .success()
+            .stdout(eq("1").trim());
+
+        kvs(engine)
+            .args(["set", "key1", "value2", "--if-absent"])
+            .current_dir(&temp_dir)
+            .assert()
+            .code(10)
+            .stderr(contains("version is 1"));
+
+        kvs(engine)
+            .args(["set", "key1", "value2", "--if-version", "1", "--versioned"])
+            .current_dir(&temp_dir)
+            .assert()
+            .success()
+            .stdout(eq("2").trim());
+
+        kvs(engine)
+            .args(["get", "key1", "--versioned"])
+            .current_dir(&temp_dir)
+            .assert()
+            .success()
+            .stdout(eq("2 value2").trim());
+
+        kvs(engine)
+            .args(["rm", "key1", "--if-value", "value1"])
+            .current_dir(&temp_dir)
+            .assert()
+            .code(10);
+
+        kvs(engine)
+            .args(["rm", "key1", "--if-value", "value2"])
+            .current_dir(&temp_dir)
+            .assert()
+            .success()
+            .stdout(is_empty());
+
+        kvs(engine)
+            .args(["set", "key1", "value1", "--if-absent", "--if-version", "0"])
+            .current_dir(&temp_dir)
+            .assert()
+            .failure();
+    }
+}
+
+// `kvs set --ttl` should let the key expire, also for later invocations.
+#[test]
+fn cli_set_ttl() {
+    for engine in ENGINES {
+        let temp_dir = TempDir::new().unwrap();
+
+        kvs(engine)
+            .args(["set", "key1", "value1", "--ttl", "1h"])
+            .current_dir(&temp_dir)
+            .assert()
+            .success()
+            .stdout(is_empty());
+
+        kvs(engine)
+            .args(["set", "key2", "value2", "--ttl", "10ms"])
+            .current_dir(&temp_dir)
+            .assert()
+            .success();
+        thread::sleep(Duration::from_millis(50));
+
+        kvs(engine)
+            .args(["get", "key2"])
+            .current_dir(&temp_dir)
+            .assert()
+            .success()
+            .stdout(eq("Key not found").trim());
+
+        kvs(engine)
+            .args(["scan"])
+            .current_dir(&temp_dir)
+            .assert()
+            .success()
+            .stdout(eq("key1 value1\n"));
+
+        kvs(engine)
+            .args(["set", "key1", "value1", "--ttl", "5x"])
+            .current_dir(&temp_dir)
+            .assert()
+            .failure()
+            .stderr(contains("unknown unit"));
+    }
+}
+
+// `kvs --ns` should keep the keys of every namespace apart and `kvs ns` should look after them.
+#[test]
+fn cli_namespaces() {
+    let temp_dir = TempDir::new().unwrap();
+
+    kvs("kvs")
+        .args(["--ns", "users", "set", "key1", "value1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    kvs("kvs")
+        .args(["set", "key1", "value2"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+
+    kvs("kvs")
+        .args(["get", "key1", "--ns", "users"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("value1").trim());
+
+    kvs("kvs")
+        .args(["--ns", "teams", "get", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("Key not found").trim());
+
+    kvs("kvs")
+        .args(["scan"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("key1 value2\n"));
+
+    kvs("kvs")
+        .args(["ns", "list"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("users\n"));
+
+    kvs("kvs")
+        .args(["ns", "stats", "users"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("1 keys"));
+
+    kvs("kvs")
+        .args(["ns", "drop", "users"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("1 keys removed").trim());
+
+    kvs("kvs")
+        .args(["--ns", "users", "get", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("Key not found").trim());
+
+    kvs("kvs")
+        .args(["get", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("value2").trim());
+
+    let sled_dir = TempDir::new().unwrap();
+    kvs("sled")
+        .args(["--ns", "users", "get", "key1"])
+        .current_dir(&sled_dir)
+        .assert()
+        .code(9)
+        .stderr(contains("namespaces"));
+}
+
+// `kvs stats` should print how big the store is, as text or JSON.
+#[test]
+fn cli_stats() {
+    let temp_dir = TempDir::new().unwrap();
+    let entries = [("key1", "value1"), ("key1", "value2"), ("key2", "value2")];
+    preload("kvs", temp_dir.path(), &entries).unwrap();
+
+    kvs("kvs")
+        .args(["stats"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("keys: 2").and(contains("last compaction: never")));
+
+    kvs("kvs")
+        .args(["compact"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+
+    kvs("kvs")
+        .args(["stats", "--format", "json"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains(r#""keys": 2"#).and(contains(r#""stale_bytes": 0"#)));
+
+    let sled_dir = TempDir::new().unwrap();
+    kvs("sled")
+        .args(["stats"])
+        .current_dir(&sled_dir)
+        .assert()
+        .code(9);
 }
 
 #[test]
 fn cli_invalid_get() {
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["get"])
+        .args(["get"])
         .assert()
         .failure();
 
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["get", "extra", "field"])
+        .args(["get", "extra", "field"])
         .assert()
         .failure();
 }
@@ -71,19 +916,19 @@ fn cli_invalid_get() {
 fn cli_invalid_set() {
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["set"])
+        .args(["set"])
         .assert()
         .failure();
 
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["set", "missing_field"])
+        .args(["set", "missing_field"])
         .assert()
         .failure();
 
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["set", "extra", "extra", "field"])
+        .args(["set", "extra", "extra", "field"])
         .assert()
         .failure();
 }
@@ -92,13 +937,13 @@ fn cli_invalid_set() {
 fn cli_invalid_rm() {
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["rm"])
+        .args(["rm"])
         .assert()
         .failure();
 
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["rm", "extra", "field"])
+        .args(["rm", "extra", "field"])
         .assert()
         .failure();
 }
@@ -107,49 +952,1090 @@ fn cli_invalid_rm() {
 fn cli_invalid_subcommand() {
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["unknown", "subcommand"])
+        .args(["unknown", "subcommand"])
         .assert()
         .failure();
 }
 
+/// Opens a store of an engine which keeps its entries on disk.
+fn open_kvs(dir: &Path) -> Result<KvStore> {
+    KvStore::open(dir)
+}
+
+fn open_sled(dir: &Path) -> Result<SledStore> {
+    SledStore::open(dir)
+}
+
 // Should get previously stored value
 #[test]
-fn get_stored_value() {
-    let mut store = KvStore::new();
+fn get_stored_value() -> Result<()> {
+    check_get_stored_value(open_kvs)?;
+    check_get_stored_value(open_sled)
+}
+
+fn check_get_stored_value<E: KvsEngine>(open: fn(&Path) -> Result<E>) -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let store = open(temp_dir.path())?;
+
+    store.set("key1", "value1")?;
+    store.set("key2", "value2")?;
 
-    store.set("key1".to_owned(), "value1".to_owned());
-    store.set("key2".to_owned(), "value2".to_owned());
+    assert_eq!(store.get_string("key1")?, Some("value1".to_owned()));
+    assert_eq!(store.get_string("key2")?, Some("value2".to_owned()));
 
-    assert_eq!(store.get("key1".to_owned()), Some("value1".to_owned()));
-    assert_eq!(store.get("key2".to_owned()), Some("value2".to_owned()));
+    // Open from disk again and check persistent data
+    drop(store);
+    let store = open(temp_dir.path())?;
+    assert_eq!(store.get_string("key1")?, Some("value1".to_owned()));
+    assert_eq!(store.get_string("key2")?, Some("value2".to_owned()));
+
+    Ok(())
 }
 
 // Should overwrite existent value
 #[test]
-fn overwrite_value() {
-    let mut store = KvStore::new();
+fn overwrite_value() -> Result<()> {
+    check_overwrite_value(open_kvs)?;
+    check_overwrite_value(open_sled)
+}
+
+fn check_overwrite_value<E: KvsEngine>(open: fn(&Path) -> Result<E>) -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let store = open(temp_dir.path())?;
 
-    store.set("key1".to_owned(), "value1".to_owned());
-    assert_eq!(store.get("key1".to_owned()), Some("value1".to_owned()));
+    store.set("key1", "value1")?;
+    assert_eq!(store.get_string("key1")?, Some("value1".to_owned()));
 
-    store.set("key1".to_owned(), "value2".to_owned());
-    assert_eq!(store.get("key1".to_owned()), Some("value2".to_owned()));
+    store.set("key1", "value2")?;
+    assert_eq!(store.get_string("key1")?, Some("value2".to_owned()));
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let store = open(temp_dir.path())?;
+    assert_eq!(store.get_string("key1")?, Some("value2".to_owned()));
+
+    Ok(())
 }
 
 // Should get `None` when getting a non-existent key
 #[test]
-fn get_non_existent_value() {
-    let mut store = KvStore::new();
+fn get_non_existent_value() -> Result<()> {
+    check_get_non_existent_value(open_kvs)?;
+    check_get_non_existent_value(open_sled)
+}
+
+fn check_get_non_existent_value<E: KvsEngine>(open: fn(&Path) -> Result<E>) -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let store = open(temp_dir.path())?;
+
+    store.set("key1", "value1")?;
+    assert_eq!(store.get_string("key2")?, None);
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let store = open(temp_dir.path())?;
+    assert_eq!(store.get_string("key2")?, None);
+
+    Ok(())
+}
+
+#[test]
+fn remove_key() -> Result<()> {
+    check_remove_key(open_kvs)?;
+    check_remove_key(open_sled)
+}
+
+fn check_remove_key<E: KvsEngine>(open: fn(&Path) -> Result<E>) -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let store = open(temp_dir.path())?;
+
+    store.set("key1", "value1")?;
+    store.remove("key1")?;
+    assert_eq!(store.get_string("key1")?, None);
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let store = open(temp_dir.path())?;
+    assert_eq!(store.get_string("key1")?, None);
+
+    Ok(())
+}
+
+// Removing a non-existent key should fail
+#[test]
+fn remove_non_existent_key() -> Result<()> {
+    check_remove_non_existent_key(open_kvs)?;
+    check_remove_non_existent_key(open_sled)
+}
+
+fn check_remove_non_existent_key<E: KvsEngine>(open: fn(&Path) -> Result<E>) -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let store = open(temp_dir.path())?;
+
+    match store.remove("key1") {
+        Err(KvsError::KeyNotFound) => {}
+        other => panic!("expected KeyNotFound, got {:?}", other),
+    }
+
+    Ok(())
+}
+
+// Keys and values of any bytes should be stored as they are
+#[test]
+fn binary_keys_and_values() -> Result<()> {
+    check_binary_keys_and_values(open_kvs)?;
+    check_binary_keys_and_values(open_sled)
+}
+
+fn check_binary_keys_and_values<E: KvsEngine>(open: fn(&Path) -> Result<E>) -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let store = open(temp_dir.path())?;
+
+    let value: Vec<u8> = (0..=255).rev().collect();
+    store.set([0xff, 0x00], &value)?;
+    store.set([0xff, 0xff], b"\xc3\x28")?;
+    store.set("key1", "value1")?;
+
+    drop(store);
+    let store = open(temp_dir.path())?;
+    assert_eq!(store.get([0xff, 0x00])?, Some(value));
+    assert_eq!(store.get_string("key1")?, Some("value1".to_owned()));
+    match store.get_string([0xff, 0xff]) {
+        Err(KvsError::NotUtf8(_)) => {}
+        other => panic!("expected NotUtf8, got {:?}", other),
+    }
+    assert_eq!(
+        store
+            .scan_prefix([0xff])
+            .map(|entry| entry.map(|(key, _)| key))
+            .collect::<Result<Vec<_>>>()?,
+        vec![vec![0xff, 0x00], vec![0xff, 0xff]]
+    );
+
+    Ok(())
+}
+
+fn dir_size(dir: &Path) -> u64 {
+    fs::read_dir(dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().metadata().unwrap().len())
+        .sum()
+}
+
+// Insert data until total size of the directory decreases.
+// Test data correctness after compaction.
+#[test]
+fn compaction() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let options = KvStoreOptions {
+        compaction_threshold: 64 * 1024,
+        ..KvStoreOptions::default()
+    };
+    let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+
+    let mut current_size = dir_size(temp_dir.path());
+    for iter in 0..1000 {
+        for key_id in 0..1000 {
+            let key = format!("key{}", key_id);
+            let value = format!("{}", iter);
+            store.set(key, value)?;
+        }
+
+        let new_size = dir_size(temp_dir.path());
+        if new_size > current_size {
+            current_size = new_size;
+            continue;
+        }
+        // Compaction triggered
+
+        drop(store);
+        // reopen and check content
+        let store = KvStore::open_with_options(temp_dir.path(), options)?;
+        for key_id in 0..1000 {
+            let key = format!("key{}", key_id);
+            assert_eq!(store.get_string(key)?, Some(format!("{}", iter)));
+        }
+        return Ok(());
+    }
+
+    panic!("No compaction detected");
+}
+
+// Explicit compaction should drop overwritten and removed entries but keep the rest.
+#[test]
+fn compact_explicitly() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let store = KvStore::open(temp_dir.path())?;
+
+    for iter in 0..100 {
+        store.set("key1", format!("value{}", iter))?;
+        store.set(format!("gone{}", iter), "value")?;
+        store.remove(format!("gone{}", iter))?;
+    }
+    let size_before = dir_size(temp_dir.path());
+    store.compact()?;
+    assert!(dir_size(temp_dir.path()) < size_before);
+    assert_eq!(store.get_string("key1")?, Some("value99".to_owned()));
+    assert_eq!(store.get_string("gone1")?, None);
+
+    store.set("key2", "value2")?;
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get_string("key1")?, Some("value99".to_owned()));
+    assert_eq!(store.get_string("key2")?, Some("value2".to_owned()));
+    assert_eq!(store.get_string("gone1")?, None);
+
+    Ok(())
+}
+
+// A compaction should go through even if the time of it can't be saved for the stats
+#[test]
+fn compaction_time_unsaved() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let options = KvStoreOptions {
+        compaction_threshold: 1024,
+        ..KvStoreOptions::default()
+    };
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    // The time can't be renamed over a directory which isn't empty.
+    fs::create_dir_all(temp_dir.path().join("compacted-at").join("blocked"))?;
+
+    store.compact()?;
+    for iter in 0..100 {
+        store.set("key1", format!("value{}", iter))?;
+    }
+    assert_eq!(store.get_string("key1")?, Some("value99".to_owned()));
+    Ok(())
+}
+
+// All engines should behave the same apart from persistence
+#[test]
+fn engines_agree() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let sled_dir = TempDir::new()?;
+    check_engine(KvStore::open(temp_dir.path())?)?;
+    check_engine(SledStore::open(sled_dir.path())?)?;
+    check_engine(MemStore::new())
+}
+
+fn check_engine(engine: impl KvsEngine) -> Result<()> {
+    engine.set("b", "2")?;
+    engine.set("a", "1")?;
+    engine.set("c", "3")?;
+    engine.set("a", "4")?;
+    engine.remove("c")?;
+    assert_eq!(engine.get_string("a")?, Some("4".to_owned()));
+    assert_eq!(engine.get_string("c")?, None);
+    assert_eq!(
+        engine.scan(..).collect::<Result<Vec<_>>>()?,
+        vec![
+            (b"a".to_vec(), b"4".to_vec()),
+            (b"b".to_vec(), b"2".to_vec())
+        ]
+    );
+    match engine.remove("c") {
+        Err(KvsError::KeyNotFound) => Ok(()),
+        other => panic!("expected KeyNotFound, got {:?}", other),
+    }
+}
+
+// Batches should be applied completely or not at all by all engines
+#[test]
+fn batches() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let sled_dir = TempDir::new()?;
+    check_batches(KvStore::open(temp_dir.path())?)?;
+    check_batches(SledStore::open(sled_dir.path())?)?;
+    check_batches(MemStore::new())?;
+
+    check_batches_persist(KvStore::open(temp_dir.path())?)?;
+    check_batches_persist(SledStore::open(sled_dir.path())?)
+}
+
+fn check_batches_persist(store: impl KvsEngine) -> Result<()> {
+    assert_eq!(store.get_string("key1")?, Some("value3".to_owned()));
+    assert_eq!(store.get("key2")?, None);
+    assert_eq!(store.get_string("key3")?, Some("value3".to_owned()));
+    Ok(())
+}
+
+fn check_batches(engine: impl KvsEngine) -> Result<()> {
+    engine.set("key1", "value1")?;
+
+    let mut batch = WriteBatch::new();
+    batch
+        .set("key2", "value2")
+        .set("key1", "value3")
+        .remove("key2")
+        .set("key3", "value3");
+    engine.apply(&batch)?;
+    assert_eq!(engine.get_string("key1")?, Some("value3".to_owned()));
+    assert_eq!(engine.get("key2")?, None);
+    assert_eq!(engine.get_string("key3")?, Some("value3".to_owned()));
+
+    let mut batch = WriteBatch::new();
+    batch.set("key4", "value4").remove("key1").remove("key1");
+    match engine.apply(&batch) {
+        Err(KvsError::KeyNotFound) => {}
+        other => panic!("expected KeyNotFound, got {:?}", other),
+    }
+    assert_eq!(engine.get("key4")?, None);
+    assert_eq!(engine.get_string("key1")?, Some("value3".to_owned()));
+
+    engine.apply(&WriteBatch::new())
+}
+
+// A batch which didn't make it into the log completely should be dropped on open
+#[test]
+fn incomplete_batch() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let log_path = temp_dir.path().join("1.log");
+    let store = KvStore::open(temp_dir.path())?;
+
+    store.set("key0", "value0")?;
+    let size_before = fs::metadata(&log_path)?.len();
+    store.set("key0", "value1")?;
+    let command_len = fs::metadata(&log_path)?.len() - size_before;
+
+    let mut batch = WriteBatch::new();
+    batch.set("key1", "value1").set("key2", "value2");
+    store.apply(&batch)?;
+    drop(store);
+
+    // Cut off the last command as if the process died while writing it.
+    let log = fs::read(&log_path)?;
+    fs::write(&log_path, &log[..log.len() - command_len as usize])?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get_string("key0")?, Some("value1".to_owned()));
+    assert_eq!(store.get("key1")?, None);
+    assert_eq!(store.get("key2")?, None);
+
+    // Later writes shouldn't be taken as the rest of the dropped batch.
+    store.set("key3", "value3")?;
+    store.set("key4", "value4")?;
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1")?, None);
+    assert_eq!(store.get_string("key3")?, Some("value3".to_owned()));
+    assert_eq!(store.get_string("key4")?, Some("value4".to_owned()));
+
+    Ok(())
+}
+
+// Exported entries should be imported as they were, merged or replacing, with all engines
+#[test]
+fn export_import() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let other_dir = TempDir::new()?;
+    check_export_import(
+        KvStore::open(temp_dir.path())?,
+        KvStore::open(other_dir.path())?,
+    )?;
+    let sled_dir = TempDir::new()?;
+    let other_sled_dir = TempDir::new()?;
+    check_export_import(
+        SledStore::open(sled_dir.path())?,
+        SledStore::open(other_sled_dir.path())?,
+    )?;
+    check_export_import(MemStore::new(), MemStore::new())
+}
+
+fn check_export_import(engine: impl KvsEngine, other: impl KvsEngine) -> Result<()> {
+    let entries = vec![
+        (b"binary".to_vec(), vec![0, 255, b'\n']),
+        (b"key1".to_vec(), b"value1".to_vec()),
+        (b"quoted".to_vec(), b"\"a\", b\nand c".to_vec()),
+        (vec![255], b"".to_vec()),
+    ];
+    for (key, value) in &entries {
+        engine.set(key, value)?;
+    }
+
+    for &format in &[Format::Jsonl, Format::Csv] {
+        let mut exported = Vec::new();
+        assert_eq!(kvs::export(&engine, format, &mut exported)?, 4);
+
+        other.set("key1", "old")?;
+        other.set("other", "old")?;
+        assert_eq!(
+            kvs::import(&other, format, exported.as_slice(), ImportMode::Merge)?,
+            4
+        );
+        let mut expected = entries.clone();
+        expected.insert(2, (b"other".to_vec(), b"old".to_vec()));
+        assert_eq!(other.scan(..).collect::<Result<Vec<_>>>()?, expected);
+
+        kvs::import(&other, format, exported.as_slice(), ImportMode::Replace)?;
+        assert_eq!(other.scan(..).collect::<Result<Vec<_>>>()?, entries);
+    }
+
+    // An invalid input is found before anything is removed.
+    match kvs::import(
+        &other,
+        Format::Jsonl,
+        &b"{\"key\":\"new\",\"value\":\"new\"}\n{}\n"[..],
+        ImportMode::Replace,
+    ) {
+        Err(KvsError::InvalidInput(_)) => {}
+        result => panic!("expected InvalidInput, got {:?}", result),
+    }
+    assert_eq!(other.scan(..).collect::<Result<Vec<_>>>()?, entries);
+
+    match kvs::import(&other, Format::Jsonl, &b"{}\n"[..], ImportMode::Merge) {
+        Err(KvsError::InvalidInput(_)) => {}
+        result => panic!("expected InvalidInput, got {:?}", result),
+    }
+    match kvs::import(
+        &other,
+        Format::Csv,
+        &b"key,value\na,b,c\n"[..],
+        ImportMode::Merge,
+    ) {
+        Err(KvsError::InvalidInput(_)) => {}
+        result => panic!("expected InvalidInput, got {:?}", result),
+    }
+
+    Ok(())
+}
+
+// Replacing the entries of a namespace should keep the other namespaces
+// and a failure in the middle of it should leave the store as it was.
+#[test]
+fn import_replace_failure() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let store = KvStore::open(temp_dir.path())?;
+    let other = store.namespace("other");
+    store.set("key1", "value1")?;
+    store.set("key2", "value2")?;
+    other.set("key1", "other")?;
+    let input = b"{\"key\":\"key2\",\"value\":\"new\"}\n{\"key\":\"key3\",\"value\":\"new\"}\n";
+    let scan = |store: &KvStore| store.scan(..).collect::<Result<Vec<_>>>();
+    let before = vec![
+        (b"key1".to_vec(), b"value1".to_vec()),
+        (b"key2".to_vec(), b"value2".to_vec()),
+    ];
+
+    // The log of the next generation can't be written where a directory is in the way.
+    let in_the_way = temp_dir.path().join("2.log");
+    fs::create_dir(&in_the_way)?;
+    match kvs::import(&store, Format::Jsonl, &input[..], ImportMode::Replace) {
+        Err(KvsError::Io(_)) => {}
+        result => panic!("expected Io, got {:?}", result),
+    }
+    assert_eq!(scan(&store)?, before);
+    fs::remove_dir(&in_the_way)?;
+    drop((store, other));
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(scan(&store)?, before);
+
+    assert_eq!(
+        kvs::import(&store, Format::Jsonl, &input[..], ImportMode::Replace)?,
+        2
+    );
+    let after = vec![
+        (b"key2".to_vec(), b"new".to_vec()),
+        (b"key3".to_vec(), b"new".to_vec()),
+    ];
+    assert_eq!(scan(&store)?, after);
+    assert_eq!(
+        store.namespace("other").get_string("key1")?,
+        Some("other".to_owned())
+    );
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(scan(&store)?, after);
+    assert_eq!(
+        store.namespace("other").get_string("key1")?,
+        Some("other".to_owned())
+    );
+    Ok(())
+}
+
+// A snapshot should see the store as it was while writes and compactions go on
+#[test]
+fn snapshots() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1", "value1")?;
+    store.set("key2", "value2")?;
+    store.set_with_ttl("expiring", "value", Duration::from_millis(200))?;
+
+    let snapshot = store.snapshot()?;
+    store.set("key1", "changed")?;
+    store.remove("key2")?;
+    store.set("key3", "value3")?;
+    store.compact()?;
+    thread::sleep(Duration::from_millis(300));
+
+    assert_eq!(
+        snapshot.get_versioned("key1")?,
+        Some((b"value1".to_vec(), 1))
+    );
+    assert_eq!(snapshot.get("key3")?, None);
+    assert_eq!(snapshot.get("expiring")?, Some(b"value".to_vec()));
+    let entries: Vec<_> = snapshot.scan_prefix("key").collect::<Result<_>>()?;
+    assert_eq!(
+        entries,
+        vec![
+            (b"key1".to_vec(), b"value1".to_vec()),
+            (b"key2".to_vec(), b"value2".to_vec()),
+        ]
+    );
+
+    assert_eq!(store.get_string("key1")?, Some("changed".to_owned()));
+    assert_eq!(store.get("expiring")?, None);
+    assert_eq!(store.snapshot()?.get("key2")?, None);
+
+    Ok(())
+}
+
+// A consistent scan of a KvStore should not see the writes which happen while it goes on.
+#[test]
+fn consistent_scan() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 0..100 {
+        store.set(format!("key{:03}", i), "value")?;
+    }
+
+    let mut scan = store.scan_consistent(..)?;
+    assert_eq!(
+        scan.next().transpose()?,
+        Some((b"key000".to_vec(), b"value".to_vec()))
+    );
+    store.set("key099", "changed")?;
+    store.remove("key098")?;
+    store.set("key100", "value")?;
+    store.compact()?;
+    let entries: Vec<_> = scan.collect::<Result<_>>()?;
+    assert_eq!(entries.len(), 99);
+    assert_eq!(entries[97], (b"key098".to_vec(), b"value".to_vec()));
+    assert_eq!(entries[98], (b"key099".to_vec(), b"value".to_vec()));
+    Ok(())
+}
+
+// Snapshots saved by name should be listed and restored, also after reopening the store
+#[test]
+fn named_snapshots() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.snapshot_names()?, Vec::<String>::new());
+
+    store.set("key1", "value1")?;
+    store.set("key2", "value2")?;
+    store.create_snapshot("before")?;
+    store.set("key1", "changed")?;
+    store.remove("key2")?;
+    store.set("key3", "value3")?;
+    store.create_snapshot("after-1.0")?;
+    assert_eq!(store.snapshot_names()?, vec!["after-1.0", "before"]);
+
+    for name in &["", ".hidden", "a/b", "../up"] {
+        match store.create_snapshot(name) {
+            Err(KvsError::InvalidInput(_)) => {}
+            result => panic!("expected InvalidInput for {:?}, got {:?}", name, result),
+        }
+    }
+    match store.create_snapshot("before") {
+        Err(KvsError::InvalidInput(_)) => {}
+        result => panic!("expected InvalidInput, got {:?}", result),
+    }
+    match store.restore_snapshot("missing") {
+        Err(KvsError::InvalidInput(_)) => {}
+        result => panic!("expected InvalidInput, got {:?}", result),
+    }
+
+    // key1 had version 1 when the snapshot was saved and version 3 since, the store got up to 4.
+    store.restore_snapshot("before")?;
+    assert_eq!(store.get_versioned("key1")?, Some((b"value1".to_vec(), 5)));
+    assert!(store.set_if_version("key1", "stale", 1).is_err());
+    assert_eq!(store.get_string("key2")?, Some("value2".to_owned()));
+    assert_eq!(store.get("key3")?, None);
+    store.set("key4", "value4")?;
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    let entries: Vec<_> = store.scan(..).collect::<Result<_>>()?;
+    assert_eq!(
+        entries,
+        vec![
+            (b"key1".to_vec(), b"value1".to_vec()),
+            (b"key2".to_vec(), b"value2".to_vec()),
+            (b"key4".to_vec(), b"value4".to_vec()),
+        ]
+    );
+    store.restore_snapshot("after-1.0")?;
+    assert_eq!(store.get_string("key1")?, Some("changed".to_owned()));
+    assert_eq!(store.get("key4")?, None);
+
+    Ok(())
+}
+
+// A watch should get the changes of the keys with its prefix in the order they were written.
+#[test]
+fn watches() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let sled_dir = TempDir::new()?;
+    check_watches(KvStore::open(temp_dir.path())?)?;
+    check_watches(SledStore::open(sled_dir.path())?)?;
+    check_watches(MemStore::new())
+}
+
+fn check_watches(engine: impl KvsEngine) -> Result<()> {
+    engine.set("key1", "before")?;
+    let watch = engine.watch("key");
+    let all = engine.watch("");
+    drop(engine.watch("key"));
+
+    engine.set("key1", "value1")?;
+    engine.set("other", "value")?;
+    let mut batch = WriteBatch::new();
+    batch.set("key2", "value2").remove("key1");
+    engine.apply(&batch)?;
+    engine.set_if_version("key2", "value3", 4)?;
+    assert!(engine.set_if_version("key2", "value4", 4).is_err());
+    engine.compare_and_swap("key2", Some(b"value3"), None)?;
+    engine.set_with_ttl("key3", "value3", Duration::from_millis(1))?;
+    thread::sleep(Duration::from_millis(10));
+    assert_eq!(engine.get("key3")?, None);
+
+    // Expiring doesn't count as a change, so key3 is never removed.
+    assert_eq!(
+        events(&watch),
+        vec![
+            set("key1", "value1", 2),
+            set("key2", "value2", 4),
+            remove("key1"),
+            set("key2", "value3", 5),
+            remove("key2"),
+            set("key3", "value3", 6),
+        ]
+    );
+    assert_eq!(events(&all)[1], set("other", "value", 3));
+
+    // The watch ends with the store.
+    drop(engine);
+    assert_eq!(watch.collect::<Vec<_>>(), vec![]);
+    Ok(())
+}
+
+// Restoring a snapshot should show up as the keys which changed by it.
+#[test]
+fn watch_restore() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1", "value1")?;
+    store.create_snapshot("before")?;
+    store.set("key2", "value2")?;
+    let watch = store.watch("key");
+
+    store.compact()?;
+    store.restore_snapshot("before")?;
+    assert_eq!(
+        events(&watch),
+        vec![remove("key2"), set("key1", "value1", 3)]
+    );
+    Ok(())
+}
+
+/// The events of the watch which are there by now.
+fn events(watch: &Watch) -> Vec<Event> {
+    let mut events = Vec::new();
+    while let Some(event) = watch.next_timeout(Duration::from_millis(100)) {
+        events.push(event);
+    }
+    events
+}
+
+fn set(key: &str, value: &str, version: u64) -> Event {
+    Event::Set {
+        key: key.into(),
+        value: value.into(),
+        version,
+    }
+}
 
-    store.set("key1".to_owned(), "value1".to_owned());
-    assert_eq!(store.get("key2".to_owned()), None);
+fn remove(key: &str) -> Event {
+    Event::Remove { key: key.into() }
 }
 
+// Conditional writes should only write if the key is as expected, with all engines
 #[test]
-fn remove_key() {
-    let mut store = KvStore::new();
+fn conditional_writes() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let sled_dir = TempDir::new()?;
+    check_conditional_writes(KvStore::open(temp_dir.path())?)?;
+    check_conditional_writes(SledStore::open(sled_dir.path())?)?;
+    check_conditional_writes(MemStore::new())
+}
+
+fn check_conditional_writes(engine: impl KvsEngine) -> Result<()> {
+    let expect_conflict = |result: Result<u64>, version| match result {
+        Err(KvsError::Conflict(current)) => assert_eq!(current, version),
+        other => panic!("expected Conflict, got {:?}", other),
+    };
+
+    assert_eq!(engine.set_if_absent("key1", "value1")?, 1);
+    expect_conflict(engine.set_if_absent("key1", "value2"), 1);
+    assert_eq!(engine.set("key1", "value2")?, 2);
+    assert_eq!(engine.get_versioned("key1")?, Some((b"value2".to_vec(), 2)));
+
+    expect_conflict(engine.set_if_version("key1", "value3", 1), 2);
+    assert_eq!(engine.set_if_version("key1", "value3", 2)?, 3);
+
+    expect_conflict(
+        engine.compare_and_swap("key1", Some(b"value2"), Some(b"value4")),
+        3,
+    );
+    assert_eq!(
+        engine.compare_and_swap("key1", Some(b"value3"), Some(b"value4"))?,
+        4
+    );
+    assert_eq!(engine.compare_and_swap("key1", Some(b"value4"), None)?, 0);
+    assert_eq!(engine.get("key1")?, None);
+    expect_conflict(engine.compare_and_swap("key1", Some(b"value4"), None), 0);
+
+    // A removed key goes on after the versions it had, so none of them comes back.
+    assert_eq!(engine.compare_and_swap("key1", None, Some(b"value5"))?, 5);
+
+    let mut batch = WriteBatch::new();
+    batch
+        .set("key2", "value1")
+        .set("key2", "value2")
+        .set("key1", "value6");
+    engine.apply(&batch)?;
+    assert_eq!(engine.get_versioned("key2")?, Some((b"value2".to_vec(), 7)));
+    assert_eq!(engine.get_versioned("key1")?, Some((b"value6".to_vec(), 8)));
+    Ok(())
+}
+
+// Versions should survive reopening and compaction,
+// also those of removed keys whose records were compacted away
+#[test]
+fn versions_persist() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let store = KvStore::open(temp_dir.path())?;
+    for iter in 0..10 {
+        store.set("key1", format!("value{}", iter))?;
+    }
+    store.set("key2", "value")?;
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get_versioned("key1")?, Some((b"value9".to_vec(), 10)));
+    assert_eq!(store.get_versioned("key2")?, Some((b"value".to_vec(), 11)));
+    store.remove("key2")?;
+    store.compact()?;
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.set("key2", "value")?, 12);
+    store.compact()?;
+    assert_eq!(store.set("key1", "value10")?, 13);
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(
+        store.get_versioned("key1")?,
+        Some((b"value10".to_vec(), 13))
+    );
+    Ok(())
+}
+
+// Expired keys should be gone for every operation, with all engines
+#[test]
+fn expiration() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let sled_dir = TempDir::new()?;
+    check_expiration(KvStore::open(temp_dir.path())?)?;
+    check_expiration(SledStore::open(sled_dir.path())?)?;
+    check_expiration(MemStore::new())
+}
+
+fn check_expiration(engine: impl KvsEngine) -> Result<()> {
+    engine.set_with_ttl("key1", "value1", Duration::from_millis(100))?;
+    engine.set_with_ttl("key2", "value2", Duration::from_secs(3600))?;
+    engine.set("key3", "value3")?;
+    engine.set_with_ttl("key4", "value4", Duration::from_millis(100))?;
+    assert_eq!(engine.get_string("key1")?, Some("value1".to_owned()));
+    assert_eq!(engine.scan(..).count(), 4);
+    let counts = KeyCounts {
+        keys: 4,
+        expiring: 3,
+    };
+    assert_eq!(engine.key_counts()?, counts);
+
+    thread::sleep(Duration::from_millis(200));
+    assert_eq!(engine.get("key1")?, None);
+    assert_eq!(engine.get_versioned("key1")?, None);
+    assert_eq!(
+        engine
+            .scan(..)
+            .map(|entry| entry.map(|(key, _)| key))
+            .collect::<Result<Vec<_>>>()?,
+        vec![b"key2".to_vec(), b"key3".to_vec()]
+    );
+    let counts = KeyCounts {
+        keys: 2,
+        expiring: 1,
+    };
+    assert_eq!(engine.key_counts()?, counts);
+    match engine.remove("key1") {
+        Err(KvsError::KeyNotFound) => {}
+        other => panic!("expected KeyNotFound, got {:?}", other),
+    }
+    // Expired keys go on after the versions they had.
+    assert_eq!(engine.set_if_absent("key1", "value5")?, 5);
+    assert_eq!(engine.set("key4", "value6")?, 6);
+    assert_eq!(engine.get_string("key4")?, Some("value6".to_owned()));
+    Ok(())
+}
+
+// Expiry times should survive reopening and expired entries should be dropped by compaction
+#[test]
+fn expiration_persists() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let store = KvStore::open(temp_dir.path())?;
+    store.set_with_ttl("key1", "expiring", Duration::from_millis(100))?;
+    store.set_with_ttl("key2", "value2", Duration::from_secs(3600))?;
+    drop(store);
+
+    thread::sleep(Duration::from_millis(200));
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1")?, None);
+    assert_eq!(store.get_string("key2")?, Some("value2".to_owned()));
+
+    store.compact()?;
+    for entry in fs::read_dir(temp_dir.path())? {
+        let log = fs::read(entry?.path())?;
+        assert!(!log.windows(8).any(|bytes| bytes == b"expiring"));
+    }
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1")?, None);
+    assert_eq!(store.get_string("key2")?, Some("value2".to_owned()));
+    Ok(())
+}
+
+// Expired records should count as stale, so keys which only ever expire still get compacted,
+// also when they expired while the store was closed
+#[test]
+fn expiration_compacts() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let options = KvStoreOptions {
+        compaction_threshold: 8 * 1024,
+        ..KvStoreOptions::default()
+    };
+    let value = "v".repeat(100);
+    let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    for key_id in 0..100 {
+        store.set_with_ttl(format!("key{}", key_id), &value, Duration::from_millis(100))?;
+    }
+    let full_size = dir_size(temp_dir.path());
+    thread::sleep(Duration::from_millis(200));
+    store.set("other", "value")?;
+    assert!(dir_size(temp_dir.path()) < full_size / 10);
+
+    for key_id in 0..100 {
+        store.set_with_ttl(format!("key{}", key_id), &value, Duration::from_millis(100))?;
+    }
+    drop(store);
+    thread::sleep(Duration::from_millis(200));
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    store.set("other", "value")?;
+    assert!(dir_size(temp_dir.path()) < full_size / 10);
+    assert_eq!(store.get_string("other")?, Some("value".to_owned()));
+    assert_eq!(store.scan(..).count(), 1);
+    Ok(())
+}
+
+// Scans should return exactly the keys in their range in order, also across batches
+#[test]
+fn scan_ranges() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let sled_dir = TempDir::new()?;
+    check_scan_ranges(KvStore::open(temp_dir.path())?)?;
+    check_scan_ranges(SledStore::open(sled_dir.path())?)?;
+    check_scan_ranges(MemStore::new())
+}
+
+fn check_scan_ranges(engine: impl KvsEngine) -> Result<()> {
+    for i in 0..300 {
+        engine.set(format!("key{:03}", i), format!("value{}", i))?;
+    }
+    engine.set("kez", "after")?;
+
+    let keys = |scan: Scan| -> Result<Vec<String>> {
+        scan.map(|entry| Ok(String::from_utf8(entry?.0)?)).collect()
+    };
+    let expected = |range: std::ops::Range<usize>| -> Vec<String> {
+        range.map(|i| format!("key{:03}", i)).collect()
+    };
+
+    assert_eq!(keys(engine.scan_prefix("key"))?, expected(0..300));
+    assert_eq!(keys(engine.scan_prefix("key1"))?, expected(100..200));
+    assert_eq!(
+        keys(engine.scan(b"key050".to_vec()..b"key130".to_vec()))?,
+        expected(50..130)
+    );
+    assert_eq!(
+        keys(engine.scan(b"key290".to_vec()..))?,
+        [expected(290..300), vec!["kez".to_owned()]].concat()
+    );
+    assert_eq!(keys(engine.scan_prefix("nope"))?, Vec::<String>::new());
+    assert_eq!(
+        keys(engine.scan(b"b".to_vec()..b"a".to_vec()))?,
+        Vec::<String>::new()
+    );
+    assert_eq!(
+        keys(engine.scan(kvs::scan_range(
+            Some(b"key2"),
+            Some(b"key150"),
+            Some(b"key210")
+        )))?,
+        expected(200..210)
+    );
+    let only_keys: Vec<_> = engine
+        .scan_keys(b"key050".to_vec()..b"key130".to_vec())
+        .map(|key| Ok(String::from_utf8(key?)?))
+        .collect::<Result<_>>()?;
+    assert_eq!(only_keys, expected(50..130));
+
+    let (key, value) = engine.scan(b"key100".to_vec()..).next().unwrap()?;
+    assert_eq!((key, value), (b"key100".to_vec(), b"value100".to_vec()));
+    Ok(())
+}
+
+// Clones of a store used from many threads should see each others writes, also across compactions
+#[test]
+fn concurrent_set_get() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let options = KvStoreOptions {
+        compaction_threshold: 4 * 1024,
+        ..KvStoreOptions::default()
+    };
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+
+    let handles: Vec<_> = (0..8)
+        .map(|thread_id| {
+            let store = store.clone();
+            thread::spawn(move || -> Result<()> {
+                for iter in 0..100 {
+                    let key = format!("key{}", thread_id);
+                    store.set(key.clone(), format!("value{}", iter))?;
+                    assert_eq!(store.get_string(key)?, Some(format!("value{}", iter)));
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap()?;
+    }
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    for thread_id in 0..8 {
+        assert_eq!(
+            store.get_string(format!("key{}", thread_id))?,
+            Some("value99".to_owned())
+        );
+    }
+
+    Ok(())
+}
+
+// Namespaces should only see their own keys, persist and be dropped as a whole
+#[test]
+fn namespaces() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let store = KvStore::open(temp_dir.path())?;
+    let users = store.namespace("users");
+    let teams = store.namespace("teams");
+    check_engine(users.clone())?;
+
+    store.set("key1", "default")?;
+    let key1_version = teams.set("key1", "teams")?;
+    let key2_version = teams.set("key2", "teams")?;
+    let watch = teams.watch("key");
+    let mut batch = WriteBatch::new();
+    batch.set("key3", "teams").remove("key2");
+    teams.apply(&batch)?;
+    assert_eq!(
+        events(&watch),
+        vec![set("key3", "teams", key2_version + 1), remove("key2")]
+    );
+
+    assert_eq!(store.get_string("key1")?, Some("default".to_owned()));
+    assert_eq!(users.get("key1")?, None);
+    assert_eq!(
+        store.namespace("").get_string("key1")?,
+        Some("default".to_owned())
+    );
+    assert_eq!(
+        teams.scan(..).collect::<Result<Vec<_>>>()?,
+        vec![
+            (b"key1".to_vec(), b"teams".to_vec()),
+            (b"key3".to_vec(), b"teams".to_vec()),
+        ]
+    );
+    assert_eq!(
+        store.scan(..).collect::<Result<Vec<_>>>()?,
+        vec![(b"key1".to_vec(), b"default".to_vec())]
+    );
+    assert_eq!(teams.snapshot()?.scan_prefix("key").count(), 2);
+    assert_eq!(store.namespaces(), vec!["teams", "users"]);
+    let stats = store.namespace_stats("teams");
+    assert_eq!(stats.keys, 2);
+    assert!(stats.bytes > 0);
+    assert_eq!(teams.key_counts()?.keys, 2);
+
+    store.compact()?;
+    drop((store, users, teams));
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(
+        store.namespace("teams").get_versioned("key1")?,
+        Some((b"teams".to_vec(), key1_version))
+    );
+
+    assert_eq!(store.drop_namespace("teams")?, 2);
+    assert_eq!(store.drop_namespace("missing")?, 0);
+    assert_eq!(store.namespaces(), vec!["users"]);
+    assert_eq!(store.namespace_stats("teams").keys, 0);
+    assert_eq!(store.get_string("key1")?, Some("default".to_owned()));
+    Ok(())
+}
+
+// Stats should tell live from stale bytes and when the store was last compacted
+#[test]
+fn stats() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let store = KvStore::open(temp_dir.path())?;
+    let stats = store.stats()?;
+    assert_eq!(stats.keys, 0);
+    assert_eq!((stats.live_bytes(), stats.stale_bytes()), (0, 0));
+    assert_eq!(stats.last_compaction, None);
+
+    store.set("key1", "value1")?;
+    store.set("key1", "value2")?;
+    store.set("key2", "value2")?;
+    store.namespace("users").set("key1", "value1")?;
+    store.remove("key2")?;
+    let stats = store.stats()?;
+    assert_eq!(stats.keys, 2);
+    assert_eq!(stats.generations.len(), 1);
+    assert!(stats.live_bytes() > 0);
+    assert!(stats.stale_bytes() > stats.live_bytes());
+    assert!(stats.index_bytes > 0);
 
-    store.set("key1".to_owned(), "value1".to_owned());
-    store.remove("key1".to_owned());
-    assert_eq!(store.get("key1".to_owned()), None);
+    store.compact()?;
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    let compacted = store.stats()?;
+    assert_eq!(compacted.keys, 2);
+    assert_eq!(compacted.live_bytes(), stats.live_bytes());
+    assert_eq!(compacted.stale_bytes(), 0);
+    assert!(compacted.last_compaction.is_some());
+    Ok(())
 }
@@ -0,0 +1,48 @@
+use kvs::thread_pool::{NaiveThreadPool, SharedQueueThreadPool, ThreadPool};
+use kvs::Result;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc};
+use std::time::Duration;
+
+const TASK_NUM: usize = 20;
+
+/// Runs `TASK_NUM` jobs which count up and waits until all of them are done.
+fn spawn_counter(pool: impl ThreadPool) {
+    let counter = Arc::new(AtomicUsize::new(0));
+    let (done, finished) = mpsc::channel();
+    for _ in 0..TASK_NUM {
+        let counter = Arc::clone(&counter);
+        let done = done.clone();
+        pool.spawn(move || {
+            counter.fetch_add(1, Ordering::SeqCst);
+            done.send(()).unwrap();
+        });
+    }
+    for _ in 0..TASK_NUM {
+        finished.recv_timeout(Duration::from_secs(10)).unwrap();
+    }
+    assert_eq!(counter.load(Ordering::SeqCst), TASK_NUM);
+}
+
+#[test]
+fn naive_thread_pool_spawn_counter() -> Result<()> {
+    spawn_counter(NaiveThreadPool::new(4)?);
+    Ok(())
+}
+
+#[test]
+fn shared_queue_thread_pool_spawn_counter() -> Result<()> {
+    spawn_counter(SharedQueueThreadPool::new(4)?);
+    Ok(())
+}
+
+// Every thread of the pool panics once, the pool should still run all later jobs.
+#[test]
+fn shared_queue_thread_pool_panic_task() -> Result<()> {
+    let pool = SharedQueueThreadPool::new(4)?;
+    for _ in 0..TASK_NUM {
+        pool.spawn(|| panic!("this panic is part of the test"));
+    }
+    spawn_counter(pool);
+    Ok(())
+}